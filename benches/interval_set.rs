@@ -0,0 +1,64 @@
+//! Gossip payload size and merge time of `IntervalSet` against the flat
+//! `BTreeSet` it replaced. Run with `cargo bench --bench interval_set`.
+use std::{
+    collections::BTreeSet,
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use fly_dis::{interval_set::IntervalSet, sim::Rng};
+
+const VALUES: usize = 10_000;
+const MERGES: u32 = 200;
+
+/// Values a node has seen: dense with a share of gaps, the way broadcast
+/// values fill up while gossip is still in flight.
+fn seen(rng: &mut Rng, gap_percent: u64) -> Vec<usize> {
+    (0..VALUES)
+        .filter(|_| rng.below(100) >= gap_percent)
+        .collect()
+}
+
+fn time(mut f: impl FnMut()) -> Duration {
+    let start = Instant::now();
+    for _ in 0..MERGES {
+        f();
+    }
+    start.elapsed() / MERGES
+}
+
+fn main() {
+    println!(
+        "{:>6} {:>12} {:>12} {:>12} {:>12}",
+        "gaps", "flat bytes", "range bytes", "flat merge", "range merge"
+    );
+    let mut rng = Rng::new(7);
+    for gap_percent in [0, 1, 10, 50] {
+        let (local, remote) = (seen(&mut rng, gap_percent), seen(&mut rng, gap_percent));
+        let flat = (
+            local.iter().copied().collect::<BTreeSet<_>>(),
+            remote.iter().copied().collect::<BTreeSet<_>>(),
+        );
+        let ranges = (
+            local.iter().copied().collect::<IntervalSet>(),
+            remote.iter().copied().collect::<IntervalSet>(),
+        );
+        let flat_bytes = serde_json::to_vec(&flat.1).unwrap().len();
+        let range_bytes = serde_json::to_vec(&ranges.1).unwrap().len();
+        // a merge is what a node does with every gossip it receives.
+        let flat_merge = time(|| {
+            let mut merged = flat.0.clone();
+            merged.extend(black_box(&flat.1));
+            black_box(merged);
+        });
+        let range_merge = time(|| {
+            let mut merged = ranges.0.clone();
+            merged.union(black_box(&ranges.1));
+            black_box(merged);
+        });
+        println!(
+            "{:>5}% {:>12} {:>12} {:>12?} {:>12?}",
+            gap_percent, flat_bytes, range_bytes, flat_merge, range_merge
+        );
+    }
+}
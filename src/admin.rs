@@ -0,0 +1,319 @@
+#![allow(dead_code)]
+//! Introspection requests every node answers next to its workload: `stats`,
+//! `dump_state` and `set_config`. The runtime answers them on the reader
+//! thread, so a node whose handlers are stuck still reports what it is doing.
+//! Answers are not events, a trace replays without them.
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::bail;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    config::Config,
+    message::{self, ErrorCode, ErrorReply, Message, Payload},
+    output::Outbox,
+    runtime::Node,
+};
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Admin {
+    Stats,
+    StatsOk {
+        in_reply_to: usize,
+        /// Messages read and written so far by body type.
+        messages_in: BTreeMap<String, u64>,
+        messages_out: BTreeMap<String, u64>,
+        /// Events waiting in the queue of the node.
+        queue_depth: usize,
+        /// Messages the overflow policy dropped.
+        dropped_events: u64,
+        /// Ticks left out since an equal one was still waiting.
+        coalesced_events: u64,
+        /// Figures of the workload, see `Node::stats`.
+        node: Value,
+    },
+    DumpState,
+    DumpStateOk {
+        in_reply_to: usize,
+        state: Value,
+    },
+    /// Keys and values as in a config file, e.g. `{"gossip_interval_ms": 200}`,
+    /// limited to the `LIVE_KEYS` of the node.
+    SetConfig {
+        config: Map<String, Value>,
+    },
+    SetConfigOk {
+        in_reply_to: usize,
+        config: Config,
+    },
+}
+
+impl Admin {
+    /// Body types of the answers, which never come from node events.
+    pub const REPLIES: [&'static str; 3] = ["stats_ok", "dump_state_ok", "set_config_ok"];
+}
+
+/// Answers admin requests read by the runtime.
+pub type AdminHandler<'a> = &'a dyn Fn(&Outbox, Message<Admin>) -> message::Result<()>;
+
+/// Counters kept by the runtime, shared by every clone of an `Outbox`.
+#[derive(Debug, Default)]
+pub struct Stats {
+    messages_in: Mutex<BTreeMap<String, u64>>,
+    messages_out: Mutex<BTreeMap<String, u64>>,
+    queue_depth: AtomicUsize,
+    dropped_events: AtomicU64,
+    coalesced_events: AtomicU64,
+}
+
+impl Stats {
+    pub fn record_in(&self, kind: &str) {
+        Stats::count(&self.messages_in, kind);
+    }
+
+    pub fn record_out(&self, kind: &str) {
+        Stats::count(&self.messages_out, kind);
+    }
+
+    pub fn set_queue_depth(&self, depth: usize) {
+        self.queue_depth.store(depth, Ordering::Relaxed);
+    }
+
+    pub fn record_dropped(&self) {
+        self.dropped_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_coalesced(&self) {
+        self.coalesced_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.queue_depth.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped_events.load(Ordering::Relaxed)
+    }
+
+    pub fn coalesced_events(&self) -> u64 {
+        self.coalesced_events.load(Ordering::Relaxed)
+    }
+
+    pub fn messages_in(&self) -> BTreeMap<String, u64> {
+        self.messages_in
+            .lock()
+            .expect("stats lock poisoned")
+            .clone()
+    }
+
+    pub fn messages_out(&self) -> BTreeMap<String, u64> {
+        self.messages_out
+            .lock()
+            .expect("stats lock poisoned")
+            .clone()
+    }
+
+    fn count(counts: &Mutex<BTreeMap<String, u64>>, kind: &str) {
+        let mut counts = counts.lock().expect("stats lock poisoned");
+        match counts.get_mut(kind) {
+            Some(count) => *count += 1,
+            None => {
+                counts.insert(kind.to_string(), 1);
+            }
+        }
+    }
+}
+
+/// Answers admin requests for one node, holding the config it runs with.
+pub struct Introspection<N> {
+    node: Arc<N>,
+    config: Mutex<Config>,
+}
+
+impl<N: Node> Introspection<N> {
+    pub fn new(node: Arc<N>, config: &Config) -> Self {
+        Introspection {
+            node,
+            config: Mutex::new(config.clone()),
+        }
+    }
+
+    pub fn handle(&self, outbox: &Outbox, message: Message<Admin>) -> message::Result<()> {
+        let in_reply_to = message.body.msg_id.unwrap_or(1);
+        let reply = match message.body.data {
+            Admin::Stats => Ok(Admin::StatsOk {
+                in_reply_to,
+                messages_in: outbox.stats().messages_in(),
+                messages_out: outbox.stats().messages_out(),
+                queue_depth: outbox.stats().queue_depth(),
+                dropped_events: outbox.stats().dropped_events(),
+                coalesced_events: outbox.stats().coalesced_events(),
+                node: self.node.stats(),
+            }),
+            Admin::DumpState => Ok(Admin::DumpStateOk {
+                in_reply_to,
+                state: self.node.dump_state(),
+            }),
+            Admin::SetConfig { config } => {
+                self.set_config(config).map(|config| Admin::SetConfigOk {
+                    in_reply_to,
+                    config,
+                })
+            }
+            // answers are only ever sent.
+            Admin::StatsOk { .. } | Admin::DumpStateOk { .. } | Admin::SetConfigOk { .. } => {
+                return Ok(())
+            }
+        };
+        match reply {
+            Ok(reply) => outbox.send(&Message::new(
+                message.dst,
+                message.src,
+                Payload::new(reply, None),
+            )),
+            Err(error) => {
+                let error = ErrorReply::Error {
+                    in_reply_to,
+                    code: ErrorCode::MalformedRequest,
+                    text: error.to_string(),
+                };
+                outbox.send(&Message::new(
+                    message.dst,
+                    message.src,
+                    Payload::new(error, None),
+                ))
+            }
+        }
+    }
+
+    /// Applies every key or none of them, the node only sees a valid config.
+    fn set_config(&self, changes: Map<String, Value>) -> message::Result<Config> {
+        let mut config = self.config.lock().expect("config lock poisoned");
+        let mut changed = config.clone();
+        for (key, value) in changes {
+            changed.set(&key, &value.to_string())?;
+            if !N::LIVE_KEYS.contains(&key.as_str()) {
+                bail!("{} only changes on restart", key);
+            }
+        }
+        changed.validate()?;
+        self.node.set_config(&changed);
+        *config = changed.clone();
+        Ok(changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        broadcase_handler::{Broadcast, BroadcastNode},
+        message::Handler,
+        scheduler::VirtualTime,
+    };
+
+    fn request(admin: Admin, msg_id: usize) -> Message<Admin> {
+        Message::new(
+            "c1".to_string(),
+            "n1".to_string(),
+            Payload::new(admin, Some(msg_id)),
+        )
+    }
+
+    fn replies(lines: &std::sync::mpsc::Receiver<String>) -> Vec<Value> {
+        lines
+            .try_iter()
+            .map(|line| {
+                serde_json::from_str::<Message<Value>>(&line)
+                    .unwrap()
+                    .body
+                    .data
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_admin_requests_are_answered() {
+        let time = VirtualTime::default();
+        let node = Arc::new(BroadcastNode::from_init(
+            "n1".to_string(),
+            vec!["n1".to_string(), "n2".to_string()],
+            Arc::new(time.scheduler("n1")),
+            &Config::default(),
+        ));
+        let introspection = Introspection::new(node.clone(), &Config::default());
+        let (outbox, lines) = Outbox::channel();
+        for message in [3, 4, 5] {
+            let broadcast = Message::new(
+                "c1".to_string(),
+                "n1".to_string(),
+                Payload::new(Broadcast::Broadcast { message }, Some(message)),
+            );
+            node.handle(&outbox, broadcast).unwrap();
+        }
+        lines.try_iter().for_each(drop);
+
+        introspection
+            .handle(&outbox, request(Admin::DumpState, 10))
+            .unwrap();
+        introspection
+            .handle(&outbox, request(Admin::Stats, 11))
+            .unwrap();
+        let config = serde_json::from_str(r#"{"gossip_interval_ms":200,"fanout":5}"#).unwrap();
+        introspection
+            .handle(&outbox, request(Admin::SetConfig { config }, 12))
+            .unwrap();
+        let config = serde_json::from_str(r#"{"fanout":2,"gossip_interval_ms":0}"#).unwrap();
+        introspection
+            .handle(&outbox, request(Admin::SetConfig { config }, 13))
+            .unwrap();
+        // broadcast nodes never look at raft settings.
+        let config = serde_json::from_str(r#"{"fanout":2,"raft_max_entries":8}"#).unwrap();
+        introspection
+            .handle(&outbox, request(Admin::SetConfig { config }, 14))
+            .unwrap();
+
+        let replies = replies(&lines);
+        assert_eq!("dump_state_ok", replies[0]["type"]);
+        assert_eq!(serde_json::json!([[3, 5]]), replies[0]["state"]["received"]);
+        assert_eq!("stats_ok", replies[1]["type"]);
+        assert_eq!(3, replies[1]["messages_out"]["broadcast_ok"]);
+        assert_eq!(3, replies[1]["node"]["values"]);
+        assert_eq!("set_config_ok", replies[2]["type"]);
+        assert_eq!(200, replies[2]["config"]["gossip_interval_ms"]);
+        assert_eq!("error", replies[3]["type"]);
+        assert_eq!(12, replies[3]["code"]);
+        assert_eq!("error", replies[4]["type"]);
+        assert_eq!(
+            "raft_max_entries only changes on restart",
+            replies[4]["text"]
+        );
+        // the rejected change left the config alone.
+        let config = introspection.config.lock().unwrap().clone();
+        assert_eq!(Duration::from_millis(200), config.gossip_interval);
+        assert_eq!(5, config.fanout);
+    }
+
+    #[test]
+    fn test_live_keys_are_config_keys() {
+        let keys = Config::keys();
+        let live = [
+            BroadcastNode::LIVE_KEYS,
+            crate::counter::CounterNode::LIVE_KEYS,
+        ];
+        for key in live.concat() {
+            assert!(keys.iter().any(|known| known == key), "{}", key);
+        }
+    }
+}
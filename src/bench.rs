@@ -0,0 +1,418 @@
+//! Load generator measuring throughput and request latency of a cluster,
+//! either in process or as child processes through the harness.
+use std::{
+    collections::HashMap,
+    fmt,
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    broadcase_handler::{Broadcast, BroadcastNode},
+    config::Config,
+    counter::{Counter, CounterNode},
+    echo_handler::{Echo, EchoNode},
+    harness::{self, Client, Cluster},
+    lin_kv::{LinKv, LinKvNode},
+    message::{self, Message},
+    sim::Rng,
+    unique_id_handler::{Generate, UniqueIdNode},
+};
+
+/// Replies arriving later than this count as timeouts.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    /// Nodes run on threads of the benchmark and are fed through channels.
+    InProcess,
+    /// Nodes are child processes of the given binary, fed through pipes.
+    Subprocess(PathBuf),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Load {
+    /// Every client keeps exactly one request in flight.
+    Closed,
+    /// Requests go out at `rate` per second over all clients, whether or not
+    /// earlier ones were answered.
+    Open { rate: f64 },
+}
+
+impl fmt::Display for Load {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Load::Closed => write!(f, "closed"),
+            Load::Open { rate } => write!(f, "open@{}", rate),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    pub target: Target,
+    pub nodes: usize,
+    pub clients: usize,
+    /// Worker threads per in process node.
+    pub workers: usize,
+    pub load: Load,
+    pub duration: Duration,
+    pub seed: u64,
+    pub verbose: bool,
+    /// Node settings of the in process target, subprocesses read `FLY_DIS_*`.
+    pub config: Config,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        BenchOptions {
+            target: Target::InProcess,
+            nodes: 3,
+            clients: 4,
+            workers: 1,
+            load: Load::Closed,
+            duration: Duration::from_secs(5),
+            seed: 0,
+            verbose: false,
+            config: Config::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct ClientStats {
+    ok: usize,
+    errors: usize,
+    timeouts: usize,
+    latencies: Vec<Duration>,
+}
+
+impl ClientStats {
+    fn record(&mut self, reply: &Message<Value>, sent: Instant) {
+        if reply.body.data["type"] == "error" {
+            self.errors += 1;
+        } else {
+            self.ok += 1;
+            self.latencies.push(sent.elapsed());
+        }
+    }
+
+    fn merge(&mut self, other: ClientStats) {
+        self.ok += other.ok;
+        self.errors += other.errors;
+        self.timeouts += other.timeouts;
+        self.latencies.extend(other.latencies);
+    }
+}
+
+/// Result of one run, one line of text or JSON so runs of different commits
+/// can be diffed. Latencies are in microseconds.
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchReport {
+    pub workload: String,
+    pub target: String,
+    pub load: String,
+    pub nodes: usize,
+    pub clients: usize,
+    pub ok: usize,
+    pub errors: usize,
+    pub timeouts: usize,
+    pub elapsed_ms: u64,
+    pub ops_per_sec: f64,
+    pub p50_us: u64,
+    pub p99_us: u64,
+    pub p999_us: u64,
+    pub max_us: u64,
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<11} {:<11} {:<10} nodes={:<2} clients={:<2} ok={} errors={} timeouts={} \
+             ops/s={:.1} p50={}us p99={}us p999={}us max={}us",
+            self.workload,
+            self.target,
+            self.load,
+            self.nodes,
+            self.clients,
+            self.ok,
+            self.errors,
+            self.timeouts,
+            self.ops_per_sec,
+            self.p50_us,
+            self.p99_us,
+            self.p999_us,
+            self.max_us
+        )
+    }
+}
+
+/// Latency below which a `quantile` of the sorted `latencies` fall.
+fn percentile(latencies: &[Duration], quantile: f64) -> Duration {
+    if latencies.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (quantile * latencies.len() as f64).ceil() as usize;
+    latencies[rank.clamp(1, latencies.len()) - 1]
+}
+
+/// Runs `workload` for `options.duration` and measures every reply.
+pub fn run(workload: &str, options: &BenchOptions) -> message::Result<BenchReport> {
+    if options.nodes == 0 || options.clients == 0 {
+        bail!("need at least one node and one client");
+    }
+    if let Load::Open { rate } = options.load {
+        if rate <= 0.0 {
+            bail!("open loop rate must be positive");
+        }
+    }
+    let cluster = match &options.target {
+        Target::Subprocess(binary) => {
+            Cluster::spawn(binary, workload, options.nodes, options.verbose)?
+        }
+        Target::InProcess => {
+            let (nodes, workers, verbose) = (options.nodes, options.workers, options.verbose);
+            let config = &options.config;
+            match workload {
+                "echo" => Cluster::in_process::<EchoNode>(nodes, workers, config, verbose),
+                "unique-ids" => {
+                    Cluster::in_process::<UniqueIdNode>(nodes, workers, config, verbose)
+                }
+                "broadcast" => {
+                    Cluster::in_process::<BroadcastNode>(nodes, workers, config, verbose)
+                }
+                "g-counter" => Cluster::in_process::<CounterNode>(nodes, workers, config, verbose),
+                "lin-kv" => Cluster::in_process::<LinKvNode>(nodes, workers, config, verbose),
+                _ => bail!("unknown workload {}", workload),
+            }
+        }
+    };
+    if workload == "broadcast" {
+        let mut client = cluster.client("setup")?;
+        let topology = harness::line_topology(cluster.node_ids());
+        for node in cluster.node_ids() {
+            let topology = Broadcast::Topology {
+                topology: topology.clone(),
+            };
+            client.request(node, topology)?;
+        }
+    }
+
+    let start = Instant::now();
+    let stats = thread::scope(|scope| {
+        let workers = (0..options.clients)
+            .map(|index| {
+                let client = cluster.client(&format!("c{}", index + 1))?;
+                let generator = Generator {
+                    workload,
+                    node_ids: cluster.node_ids(),
+                    rng: Rng::new(options.seed.wrapping_add(index as u64)),
+                    client: index,
+                    clients: options.clients,
+                    op: 0,
+                };
+                Ok(scope.spawn(move || match options.load {
+                    Load::Closed => closed_loop(client, generator, options.duration),
+                    Load::Open { rate } => {
+                        let interval = options.clients as f64 / rate;
+                        open_loop(
+                            client,
+                            generator,
+                            Duration::from_secs_f64(interval),
+                            options.duration,
+                        )
+                    }
+                }))
+            })
+            .collect::<message::Result<Vec<_>>>()?;
+        let mut stats = ClientStats::default();
+        for worker in workers {
+            stats.merge(worker.join().map_err(|_| anyhow!("client panicked"))??);
+        }
+        Ok::<_, anyhow::Error>(stats)
+    })?;
+    let elapsed = start.elapsed();
+    cluster.shutdown()?;
+
+    let mut latencies = stats.latencies;
+    latencies.sort();
+    let micros = |quantile| percentile(&latencies, quantile).as_micros() as u64;
+    Ok(BenchReport {
+        workload: workload.to_string(),
+        target: match options.target {
+            Target::InProcess => "in-process".to_string(),
+            Target::Subprocess(_) => "subprocess".to_string(),
+        },
+        load: options.load.to_string(),
+        nodes: options.nodes,
+        clients: options.clients,
+        ok: stats.ok,
+        errors: stats.errors,
+        timeouts: stats.timeouts,
+        elapsed_ms: elapsed.as_millis() as u64,
+        ops_per_sec: stats.ok as f64 / elapsed.as_secs_f64(),
+        p50_us: micros(0.5),
+        p99_us: micros(0.99),
+        p999_us: micros(0.999),
+        max_us: micros(1.0),
+    })
+}
+
+/// Requests of one client, spread over the nodes at random.
+struct Generator<'a> {
+    workload: &'a str,
+    node_ids: &'a [String],
+    rng: Rng,
+    client: usize,
+    clients: usize,
+    op: usize,
+}
+
+impl Generator<'_> {
+    fn next(&mut self) -> message::Result<(&str, Value)> {
+        let node = &self.node_ids[self.rng.below(self.node_ids.len() as u64) as usize];
+        self.op += 1;
+        let request = match self.workload {
+            "echo" => serde_json::to_value(Echo::Echo {
+                echo: format!("bench {}", self.op),
+            })?,
+            "unique-ids" => serde_json::to_value(Generate::Generate)?,
+            // values stay unique over all clients.
+            "broadcast" => serde_json::to_value(Broadcast::Broadcast {
+                message: self.op * self.clients + self.client,
+            })?,
+            // mostly adds with a read every tenth request.
+            "g-counter" if self.op.is_multiple_of(10) => serde_json::to_value(Counter::Read)?,
+            "g-counter" => serde_json::to_value(Counter::Add { delta: 1 })?,
+            // even split of reads and writes over a few keys.
+            "lin-kv" if self.op.is_multiple_of(2) => serde_json::to_value(LinKv::Read {
+                key: self.rng.below(8).into(),
+            })?,
+            "lin-kv" => serde_json::to_value(LinKv::Write {
+                key: self.rng.below(8).into(),
+                value: self.op.into(),
+            })?,
+            workload => bail!("unknown workload {}", workload),
+        };
+        Ok((node, request))
+    }
+}
+
+fn closed_loop(
+    mut client: Client,
+    mut generator: Generator,
+    duration: Duration,
+) -> message::Result<ClientStats> {
+    let mut stats = ClientStats::default();
+    let end = Instant::now() + duration;
+    while Instant::now() < end {
+        let (node, request) = generator.next()?;
+        let sent = Instant::now();
+        let msg_id = client.send(node, request)?;
+        loop {
+            let timeout = REQUEST_TIMEOUT.saturating_sub(sent.elapsed());
+            match client.recv(timeout)? {
+                Some(reply) if reply.body.data["in_reply_to"] == msg_id => {
+                    stats.record(&reply, sent);
+                    break;
+                }
+                Some(_) => continue,
+                None => {
+                    stats.timeouts += 1;
+                    break;
+                }
+            }
+        }
+    }
+    Ok(stats)
+}
+
+/// Latency is measured from when a request was due rather than when it
+/// went out, so a node falling behind shows up in the tail instead of
+/// silently slowing the load down.
+fn open_loop(
+    mut client: Client,
+    mut generator: Generator,
+    interval: Duration,
+    duration: Duration,
+) -> message::Result<ClientStats> {
+    let mut stats = ClientStats::default();
+    let mut pending = HashMap::new();
+    let start = Instant::now();
+    let end = start + duration;
+    let mut next = start;
+    loop {
+        let now = Instant::now();
+        if now >= end && (pending.is_empty() || now >= end + REQUEST_TIMEOUT) {
+            break;
+        }
+        if now >= next && now < end {
+            let (node, request) = generator.next()?;
+            pending.insert(client.send(node, request)?, next);
+            next += interval;
+            continue;
+        }
+        let wake = if now < end {
+            next.min(end)
+        } else {
+            end + REQUEST_TIMEOUT
+        };
+        if let Some(reply) = client.recv(wake - now)? {
+            let in_reply_to = reply.body.data["in_reply_to"].as_u64();
+            if let Some(sent) = in_reply_to.and_then(|id| pending.remove(&(id as usize))) {
+                stats.record(&reply, sent);
+            }
+        }
+    }
+    stats.timeouts += pending.len();
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile_picks_nearest_rank() {
+        let latencies = (1..=1000).map(Duration::from_micros).collect::<Vec<_>>();
+        assert_eq!(Duration::from_micros(500), percentile(&latencies, 0.5));
+        assert_eq!(Duration::from_micros(990), percentile(&latencies, 0.99));
+        assert_eq!(Duration::from_micros(999), percentile(&latencies, 0.999));
+        assert_eq!(Duration::from_micros(1000), percentile(&latencies, 1.0));
+        assert_eq!(Duration::ZERO, percentile(&[], 0.5));
+    }
+
+    #[test]
+    fn test_closed_loop_in_process_counter() {
+        let options = BenchOptions {
+            duration: Duration::from_millis(200),
+            ..BenchOptions::default()
+        };
+        let report = run("g-counter", &options).unwrap();
+        assert!(report.ok > 0);
+        assert_eq!((0, 0), (report.errors, report.timeouts));
+        assert!(report.p50_us <= report.p99_us && report.p99_us <= report.max_us);
+    }
+
+    #[test]
+    fn test_open_loop_sends_at_rate() {
+        let options = BenchOptions {
+            load: Load::Open { rate: 200.0 },
+            duration: Duration::from_millis(500),
+            clients: 2,
+            ..BenchOptions::default()
+        };
+        let report = run("broadcast", &options).unwrap();
+        assert_eq!(0, report.timeouts);
+        // 100 when on time, a client stalled past the end skips what was
+        // still due.
+        let answered = report.ok + report.errors;
+        assert!((90..=100).contains(&answered), "{} answered", answered);
+    }
+}
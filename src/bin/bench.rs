@@ -0,0 +1,90 @@
+use std::{env, path::PathBuf, str::FromStr, time::Duration};
+
+use anyhow::{anyhow, bail};
+use fly_dis::{
+    bench::{self, BenchOptions, Load, Target},
+    config::Config,
+    message,
+};
+
+const USAGE: &str = "usage: bench <workload> [--target in-process|subprocess] [--bin path] \
+                     [--nodes N[,N..]] [--rate R[,R..]] [--clients N] [--workers N] \
+                     [--duration-ms N] [--seed N] [--json] [--verbose] [--<config-key> V]\n\
+                     Without --rate the load is closed loop, every combination of \
+                     nodes and rate is one report line. Config flags only reach \
+                     in process nodes.";
+
+fn list<T: FromStr>(value: &str) -> message::Result<Vec<T>>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    Ok(value
+        .split(',')
+        .map(str::parse)
+        .collect::<Result<Vec<_>, _>>()?)
+}
+
+fn main() -> message::Result<()> {
+    let mut args = env::args().skip(1);
+    let workload = args.next().ok_or_else(|| anyhow!(USAGE))?;
+    let mut options = BenchOptions::default();
+    let mut subprocess = false;
+    let mut binary = env::current_exe()?.with_file_name("fly_dis");
+    let mut nodes = vec![options.nodes];
+    let mut rates = Vec::new();
+    let mut json = false;
+    let mut config_flags = Vec::new();
+    while let Some(flag) = args.next() {
+        match flag.as_str() {
+            "--json" => json = true,
+            "--verbose" => options.verbose = true,
+            _ => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| anyhow!("{} needs a value, {}", flag, USAGE))?;
+                match flag.as_str() {
+                    "--target" => {
+                        subprocess = match value.as_str() {
+                            "in-process" => false,
+                            "subprocess" => true,
+                            _ => bail!("unknown target {}, {}", value, USAGE),
+                        }
+                    }
+                    "--bin" => binary = PathBuf::from(value),
+                    "--nodes" => nodes = list(&value)?,
+                    "--rate" => rates = list(&value)?,
+                    "--clients" => options.clients = value.parse()?,
+                    "--workers" => options.workers = value.parse()?,
+                    "--duration-ms" => options.duration = Duration::from_millis(value.parse()?),
+                    "--seed" => options.seed = value.parse()?,
+                    _ => config_flags.extend([flag, value]),
+                }
+            }
+        }
+    }
+    options.config = Config::load(&config_flags, |key| env::var(key).ok())?.0;
+    if subprocess {
+        options.target = Target::Subprocess(binary);
+    }
+    let loads = match rates.is_empty() {
+        true => vec![Load::Closed],
+        false => rates.into_iter().map(|rate| Load::Open { rate }).collect(),
+    };
+
+    for nodes in nodes {
+        for load in loads.iter() {
+            let options = BenchOptions {
+                nodes,
+                load: *load,
+                ..options.clone()
+            };
+            let report = bench::run(&workload, &options)?;
+            if json {
+                println!("{}", serde_json::to_string(&report)?);
+            } else {
+                println!("{}", report);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,61 @@
+use std::{env, path::PathBuf, process::ExitCode, time::Duration};
+
+use anyhow::{anyhow, bail};
+use fly_dis::{
+    harness::{self, WorkloadOptions},
+    message,
+};
+
+const USAGE: &str = "usage: harness <workload|all> [--nodes N] [--clients N] [--ops N] \
+                     [--seed N] [--settle-ms N] [--bin path] [--verbose]";
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(error) => {
+            eprintln!("{}", error);
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn run() -> message::Result<bool> {
+    let mut args = env::args().skip(1);
+    let workload = args.next().ok_or_else(|| anyhow!(USAGE))?;
+    let mut options = WorkloadOptions::default();
+    let mut binary = env::current_exe()?.with_file_name("fly_dis");
+    while let Some(flag) = args.next() {
+        if flag == "--verbose" {
+            options.verbose = true;
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| anyhow!("{} needs a value, {}", flag, USAGE))?;
+        match flag.as_str() {
+            "--nodes" => options.nodes = value.parse()?,
+            "--clients" => options.clients = value.parse()?,
+            "--ops" => options.ops = value.parse()?,
+            "--seed" => options.seed = value.parse()?,
+            "--settle-ms" => options.settle = Duration::from_millis(value.parse()?),
+            "--bin" => binary = PathBuf::from(value),
+            _ => bail!("unknown flag {}, {}", flag, USAGE),
+        }
+    }
+    if options.nodes == 0 || options.clients == 0 {
+        bail!("need at least one node and one client");
+    }
+
+    let workloads = match workload.as_str() {
+        "all" => harness::WORKLOADS.to_vec(),
+        workload => vec![workload],
+    };
+    let mut passed = true;
+    for workload in workloads {
+        let report = harness::run_workload(&binary, workload, &options)?;
+        print!("{}", report);
+        passed &= report.passed();
+    }
+    Ok(passed)
+}
@@ -1,26 +1,34 @@
 #![allow(dead_code)]
 use std::{
-    cell::{Cell, RefCell},
-    collections::{HashMap, HashSet},
-    io::{self, BufRead},
+    collections::{BTreeSet, HashMap},
     sync::{
-        atomic::AtomicBool,
-        mpsc::{channel, Receiver, Sender},
-        Arc,
+        atomic::{AtomicUsize, Ordering},
+        Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard,
     },
-    thread::{self, JoinHandle},
     time::Duration,
 };
 
 use anyhow::Ok;
 
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use serde_with::DurationMilliSeconds;
+use serde_json::{json, Value};
+use serde_with::{serde_as, DurationMilliSeconds};
 
-use crate::message::{self, Handler, Init, Message, Payload, Result};
+use crate::{
+    config::Config,
+    digest::{self, BucketHashes},
+    interval_set::IntervalSet,
+    message::{self, BodyTypes, Handler, Message, Payload},
+    output::Outbox,
+    runtime::Node,
+    scheduler::{Scheduler, SchedulerRef, Timer},
+};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq)]
 #[serde(tag = "type", rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)]
 pub enum Broadcast {
     Broadcast {
         message: usize,
@@ -30,7 +38,7 @@ pub enum Broadcast {
     },
     Read,
     ReadOk {
-        messages: HashSet<usize>,
+        messages: BTreeSet<usize>,
         in_reply_to: usize,
     },
     Topology {
@@ -40,118 +48,173 @@ pub enum Broadcast {
         in_reply_to: usize,
     },
     TriggerGossip,
-    Gossip {
-        seen: HashSet<usize>,
+    // anti-entropy between nodes, so the compact forms need not follow the spec.
+    /// Root hash of the sender's values, sent to every neighbour each round.
+    Digest {
+        root: u64,
+    },
+    /// Reply to a `digest` that did not match, `[[bucket, hash], ...]` since
+    /// number keys do not survive the tagged enum.
+    DigestBuckets {
+        #[serde_as(as = "Vec<(_, _)>")]
+        #[schemars(with = "Vec<(usize, u64)>")]
+        buckets: BucketHashes,
+    },
+    /// Values of the sender in `buckets`, the receiver answers with what it
+    /// has there on top unless `buckets` is empty.
+    Repair {
+        seen: IntervalSet,
+        buckets: Vec<usize>,
     },
     Quit,
 }
 
-#[derive(Debug)]
+impl BodyTypes for Broadcast {
+    const TYPES: &'static [&'static str] = &[
+        "broadcast",
+        "broadcast_ok",
+        "read",
+        "read_ok",
+        "topology",
+        "topology_ok",
+        "trigger_gossip",
+        "digest",
+        "digest_buckets",
+        "repair",
+        "quit",
+    ];
+}
+
 pub struct BroadcastNode {
     node_id: String,
-    received_messages: RefCell<HashSet<usize>>,
-    topology: RefCell<Vec<String>>,
-    gossip_handler: JoinHandle<()>,
-    quit: Arc<AtomicBool>,
+    peers: Vec<String>,
+    config: RwLock<Config>,
+    scheduler: SchedulerRef<Message<Broadcast>>,
+    received_messages: RwLock<IntervalSet>,
+    // neighbours once a `topology` message arrived.
+    topology: RwLock<Option<Vec<String>>>,
+    gossip_handler: Mutex<Option<Timer>>,
+    gossip_rounds: AtomicUsize,
 }
 
 impl BroadcastNode {
-    pub fn new(node_id: String, tx: Sender<Message<Broadcast>>) -> Self {
-        let quit_status = Arc::new(AtomicBool::new(false));
-
-        let gossip_handler = BroadcastNode::start_gossip_signal_producer(tx, quit_status.clone());
+    pub fn new(
+        node_id: String,
+        node_ids: Vec<String>,
+        config: &Config,
+        scheduler: SchedulerRef<Message<Broadcast>>,
+    ) -> Self {
+        let gossip_handler =
+            BroadcastNode::start_gossip_signal_producer(scheduler.as_ref(), config.gossip_interval);
+        let peers = node_ids.into_iter().filter(|id| *id != node_id).collect();
 
         BroadcastNode {
             node_id,
-            received_messages: RefCell::default(),
-            topology: RefCell::default(),
-            gossip_handler,
-            quit: quit_status,
+            peers,
+            config: RwLock::new(config.clone()),
+            scheduler,
+            received_messages: RwLock::default(),
+            topology: RwLock::default(),
+            gossip_handler: Mutex::new(Some(gossip_handler)),
+            gossip_rounds: AtomicUsize::new(0),
         }
     }
 
+    fn received(&self) -> RwLockReadGuard<'_, IntervalSet> {
+        self.received_messages
+            .read()
+            .expect("received messages lock poisoned")
+    }
+
+    fn received_mut(&self) -> RwLockWriteGuard<'_, IntervalSet> {
+        self.received_messages
+            .write()
+            .expect("received messages lock poisoned")
+    }
+
     fn start_gossip_signal_producer(
-        tx: Sender<Message<Broadcast>>,
-        quit_status: Arc<AtomicBool>,
-    ) -> JoinHandle<()> {
-        thread::spawn(move || loop {
-            if quit_status.load(std::sync::atomic::Ordering::Acquire) {
-                break;
-            }
-            if let Err(_) = tx.send(Message {
+        scheduler: &dyn Scheduler<Message<Broadcast>>,
+        interval: Duration,
+    ) -> Timer {
+        scheduler.every(
+            interval,
+            Box::new(|| Message {
                 src: "Self".to_string(),
                 dst: "Self".to_string(),
-                body: Payload {
-                    data: Broadcast::TriggerGossip {},
-                    msg_id: None,
-                },
-            }) {
-                break;
-            }
-            // we are parking not sleeping as it will allow node to close ASAP.
-            thread::park_timeout(Duration::from_millis(500));
+                body: Payload::new(Broadcast::TriggerGossip {}, None),
+            }),
+        )
+    }
+}
+
+impl Node for BroadcastNode {
+    type Payload = Broadcast;
+    type Event = Message<Broadcast>;
+
+    const LIVE_KEYS: &'static [&'static str] = &["gossip_interval_ms", "peer_selection", "fanout"];
+
+    fn from_init(
+        node_id: String,
+        node_ids: Vec<String>,
+        scheduler: SchedulerRef<Self::Event>,
+        config: &Config,
+    ) -> Self {
+        BroadcastNode::new(node_id, node_ids, config, scheduler)
+    }
+
+    fn shutdown_event() -> Option<Self::Event> {
+        Some(Message {
+            src: "Self".to_string(),
+            dst: "Self".to_string(),
+            body: Payload::new(Broadcast::Quit, None),
         })
     }
 
-    pub fn main_loop() -> message::Result<()> {
-        let mut stdin = std::io::stdin().lock();
-        let mut init_message = String::new();
-        stdin.read_line(&mut init_message)?;
-        let mut stdout = std::io::stdout().lock();
-
-        let init_message = serde_json::from_str::<Message<Init>>(&init_message)?;
-        let _ = init_message
-            .body
-            .data
-            .handle(&mut stdout, init_message.clone())?;
-
-        let (tx, rx) = channel();
-
-        drop(stdin);
-        let tx_cloned = tx.clone();
-        let join_handler = thread::spawn(move || {
-            let stdin = std::io::stdin().lock();
-            for line in stdin.lines() {
-                let incoming = line?.parse::<Message<Broadcast>>()?;
-                tx_cloned.send(incoming)?;
-            }
-            tx_cloned.send(Message {
-                src: "Self".to_string(),
-                dst: "Self".to_string(),
-                body: Payload::new(Broadcast::Quit, None),
-            })?;
-            Ok(())
-        });
-
-        let broadcast_node = BroadcastNode::new(
-            match init_message.body.data {
-                Init::Init { node_id, .. } => node_id.clone(),
-                _ => panic!("First message should be of type echo"),
-            },
-            tx,
-        );
-
-        for message in rx {
-            broadcast_node.handle(&mut stdout, message)?;
-        }
+    fn ordering_key(event: &Self::Event) -> Option<&str> {
+        Some(&event.src)
+    }
 
-        let _ = join_handler.join().expect("Main panicked");
+    fn stats(&self) -> Value {
+        let received = self.received();
+        json!({
+            "gossip_rounds": self.gossip_rounds.load(Ordering::Relaxed),
+            "values": received.len(),
+            "ranges": received.ranges().len(),
+        })
+    }
 
-        Ok(())
+    fn dump_state(&self) -> Value {
+        json!({
+            "received": *self.received(),
+            "peers": self.peers,
+            "topology": *self.topology.read().expect("topology lock poisoned"),
+        })
+    }
+
+    fn set_config(&self, config: &Config) {
+        let mut current = self.config.write().expect("config lock poisoned");
+        let mut gossip_handler = self
+            .gossip_handler
+            .lock()
+            .expect("gossip handler lock poisoned");
+        // a stopped node stays stopped.
+        if config.gossip_interval != current.gossip_interval && gossip_handler.is_some() {
+            *gossip_handler = Some(BroadcastNode::start_gossip_signal_producer(
+                self.scheduler.as_ref(),
+                config.gossip_interval,
+            ));
+        }
+        *current = config.clone();
     }
 }
 
 impl Handler<Message<Broadcast>> for BroadcastNode {
-    fn handle(
-        &self,
-        writer: &mut dyn io::Write,
-        message: message::Message<Broadcast>,
-    ) -> message::Result<()> {
+    fn handle(&self, outbox: &Outbox, message: message::Message<Broadcast>) -> message::Result<()> {
         let broadcast_reponse = match message.body.data {
             Broadcast::Broadcast { message: incoming } => {
                 // No action when message is seen.
-                if !self.received_messages.borrow().contains(&incoming) {
-                    self.received_messages.borrow_mut().insert(incoming);
+                if !self.received().contains(incoming) {
+                    self.received_mut().insert(incoming);
                 }
 
                 Some(Broadcast::BroadcastOk {
@@ -160,7 +223,7 @@ impl Handler<Message<Broadcast>> for BroadcastNode {
             }
             Broadcast::BroadcastOk { in_reply_to } => Some(Broadcast::BroadcastOk { in_reply_to }),
             Broadcast::Read => Some(Broadcast::ReadOk {
-                messages: self.received_messages.borrow().clone(),
+                messages: self.received().iter().collect(),
                 in_reply_to: message.body.msg_id.unwrap_or(1),
             }),
             Broadcast::ReadOk {
@@ -173,40 +236,60 @@ impl Handler<Message<Broadcast>> for BroadcastNode {
             Broadcast::Topology { mut topology } => {
                 // update topology of current node with its neighbor.
                 if let Some(neighbours) = topology.remove(&self.node_id) {
-                    self.topology.borrow_mut().clear();
-                    self.topology.borrow_mut().extend(neighbours.into_iter());
+                    *self.topology.write().expect("topology lock poisoned") = Some(neighbours);
                 }
                 Some(Broadcast::TopologyOk {
                     in_reply_to: message.body.msg_id.unwrap_or(1),
                 })
             }
             Broadcast::TopologyOk { in_reply_to } => Some(Broadcast::TopologyOk { in_reply_to }),
-            Broadcast::Gossip { seen } => {
-                self.received_messages.borrow_mut().extend(seen.into_iter());
-                None
+            Broadcast::Digest { root } => {
+                let buckets = digest::bucket_hashes(&self.received());
+                (digest::root(&buckets) != root).then_some(Broadcast::DigestBuckets { buckets })
+            }
+            Broadcast::DigestBuckets { buckets } => {
+                let received = self.received();
+                let differing = digest::differing(&digest::bucket_hashes(&received), &buckets);
+                (!differing.is_empty()).then(|| Broadcast::Repair {
+                    seen: digest::in_buckets(&received, &differing),
+                    buckets: differing,
+                })
+            }
+            Broadcast::Repair { seen, buckets } => {
+                let mut received = self.received_mut();
+                let missing = digest::in_buckets(&received, &buckets).difference(&seen);
+                received.union(&seen);
+                (!missing.is_empty()).then_some(Broadcast::Repair {
+                    seen: missing,
+                    buckets: Vec::new(),
+                })
             }
             Broadcast::TriggerGossip => {
-                for neighbor in self.topology.borrow().iter() {
-                    serde_json::to_writer(
-                        &mut *writer,
-                        &Message::new(
-                            self.node_id.clone(),
-                            neighbor.clone(),
-                            Payload {
-                                data: Broadcast::Gossip {
-                                    seen: self.received_messages.borrow().clone(),
-                                },
-                                msg_id: None,
-                            },
-                        ),
-                    )?;
-                    writer.write_all(b"\n")?;
+                self.gossip_rounds.fetch_add(1, Ordering::Relaxed);
+                let root = digest::root(&digest::bucket_hashes(&self.received()));
+                let neighbours = self.topology.read().expect("topology lock poisoned");
+                let peers = self
+                    .config
+                    .read()
+                    .expect("config lock poisoned")
+                    .select_peers(&self.peers, neighbours.as_deref(), || {
+                        self.scheduler.random()
+                    });
+                for neighbor in peers.iter() {
+                    outbox.send(&Message::new(
+                        self.node_id.clone(),
+                        neighbor.clone(),
+                        Payload::new(Broadcast::Digest { root }, None),
+                    ))?;
                 }
                 None
             }
             Broadcast::Quit => {
-                self.quit.store(true, std::sync::atomic::Ordering::Release);
-                self.gossip_handler.thread().unpark();
+                // dropping the timer stops the gossip ticks.
+                self.gossip_handler
+                    .lock()
+                    .expect("gossip handler lock poisoned")
+                    .take();
                 None
             }
         };
@@ -217,8 +300,7 @@ impl Handler<Message<Broadcast>> for BroadcastNode {
                 message.src,
                 Payload::new(broadcast_reponse, message.body.msg_id),
             );
-            serde_json::to_writer(&mut *writer, &message_response)?;
-            writer.write_all(b"\n")?;
+            outbox.send(&message_response)?;
         }
 
         Ok(())
@@ -0,0 +1,202 @@
+#![allow(dead_code)]
+//! Logical clocks carried in the envelope of messages between nodes. The
+//! outbox ticks them on send and merges them on receive, so handlers only
+//! read `Payload::clock` to order or discard what they got.
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap, HashSet},
+};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Vector clock keyed by node id, missing entries count as 0.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, Default, Hash, PartialEq, Eq)]
+pub struct VectorClock(BTreeMap<String, u64>);
+
+impl VectorClock {
+    pub fn get(&self, node_id: &str) -> u64 {
+        self.0.get(node_id).copied().unwrap_or(0)
+    }
+
+    pub fn increment(&mut self, node_id: &str) {
+        *self.0.entry(node_id.to_string()).or_default() += 1;
+    }
+
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (node_id, count) in other.0.iter() {
+            let entry = self.0.entry(node_id.clone()).or_default();
+            *entry = (*entry).max(*count);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Neither clock happened before the other.
+    pub fn concurrent(&self, other: &VectorClock) -> bool {
+        self.partial_cmp(other).is_none()
+    }
+}
+
+impl PartialOrd for VectorClock {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        let mut ordering = Ordering::Equal;
+        for node_id in self.0.keys().chain(other.0.keys()) {
+            match (ordering, self.get(node_id).cmp(&other.get(node_id))) {
+                (_, Ordering::Equal) => {}
+                (Ordering::Equal, next) => ordering = next,
+                (current, next) if current != next => return None,
+                _ => {}
+            }
+        }
+        Some(ordering)
+    }
+}
+
+impl<const N: usize> From<[(&str, u64); N]> for VectorClock {
+    fn from(entries: [(&str, u64); N]) -> Self {
+        VectorClock(
+            entries
+                .into_iter()
+                .map(|(node_id, count)| (node_id.to_string(), count))
+                .collect(),
+        )
+    }
+}
+
+/// Stamp of one message, `{"lamport":3,"vector":{"n1":2,"n2":1}}` on the wire.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, Default, Hash, PartialEq, Eq)]
+pub struct Clock {
+    pub lamport: u64,
+    #[serde(default, skip_serializing_if = "VectorClock::is_empty")]
+    pub vector: VectorClock,
+}
+
+impl Clock {
+    /// Whether `self` is strictly older than `other`. Vector clocks decide
+    /// when both carry one, Lamport timestamps otherwise.
+    pub fn before(&self, other: &Clock) -> bool {
+        if self.vector.is_empty() || other.vector.is_empty() {
+            return self.lamport < other.lamport;
+        }
+        self.vector < other.vector
+    }
+}
+
+/// Clock of a node, only messages to `peers` are stamped so clients see the
+/// plain Maelstrom protocol.
+#[derive(Debug)]
+pub struct NodeClock {
+    node_id: String,
+    peers: HashSet<String>,
+    clock: Clock,
+}
+
+impl NodeClock {
+    pub fn new(node_id: &str, node_ids: &[String]) -> Self {
+        NodeClock {
+            node_id: node_id.to_string(),
+            peers: node_ids
+                .iter()
+                .filter(|id| **id != node_id)
+                .cloned()
+                .collect(),
+            clock: Clock::default(),
+        }
+    }
+
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    pub fn set(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    /// Ticks for a message to `dest` and returns its stamp, `None` for clients.
+    pub fn send(&mut self, dest: &str) -> Option<Clock> {
+        if !self.peers.contains(dest) {
+            return None;
+        }
+        self.clock.lamport += 1;
+        self.clock.vector.increment(&self.node_id);
+        Some(self.clock.clone())
+    }
+
+    pub fn receive(&mut self, stamp: &Clock) {
+        self.clock.lamport = self.clock.lamport.max(stamp.lamport) + 1;
+        self.clock.vector.merge(&stamp.vector);
+        self.clock.vector.increment(&self.node_id);
+    }
+}
+
+/// Last stamp accepted from every sender, for handlers of state snapshots
+/// like `current` that have to drop what arrives out of order.
+#[derive(Debug, Default)]
+pub struct Latest {
+    by_node: HashMap<String, Clock>,
+}
+
+impl Latest {
+    /// Records `stamp` unless an older or equal one than already accepted
+    /// from `src`. Unstamped messages are always accepted.
+    pub fn accept(&mut self, src: &str, stamp: Option<&Clock>) -> bool {
+        let Some(stamp) = stamp else {
+            return true;
+        };
+        match self.by_node.get(src) {
+            Some(latest) if !latest.before(stamp) => false,
+            _ => {
+                self.by_node.insert(src.to_string(), stamp.clone());
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vector_clocks_order_partially() {
+        let a = VectorClock::from([("n1", 1)]);
+        let b = VectorClock::from([("n1", 1), ("n2", 1)]);
+        let c = VectorClock::from([("n1", 2)]);
+        assert!(a < b);
+        assert!(a < c);
+        assert!(b.concurrent(&c));
+        assert_eq!(Some(Ordering::Equal), a.partial_cmp(&a.clone()));
+    }
+
+    #[test]
+    fn test_receive_orders_after_send() {
+        let node_ids = ["n1".to_string(), "n2".to_string()];
+        let mut n1 = NodeClock::new("n1", &node_ids);
+        let mut n2 = NodeClock::new("n2", &node_ids);
+        assert_eq!(None, n1.send("c1"));
+        let first = n1.send("n2").unwrap();
+        n2.receive(&first);
+        let reply = n2.send("n1").unwrap();
+        assert!(first.before(&reply));
+        assert_eq!(3, reply.lamport);
+        let concurrent = n1.send("n2").unwrap();
+        assert!(concurrent.vector.concurrent(&reply.vector));
+        n1.receive(&reply);
+        assert!(reply.before(n1.clock()) && concurrent.before(n1.clock()));
+    }
+
+    #[test]
+    fn test_latest_drops_reordered_stamps() {
+        let node_ids = ["n1".to_string(), "n2".to_string()];
+        let mut n1 = NodeClock::new("n1", &node_ids);
+        let (first, second) = (n1.send("n2").unwrap(), n1.send("n2").unwrap());
+        let mut latest = Latest::default();
+        assert!(latest.accept("n1", Some(&second)));
+        assert!(!latest.accept("n1", Some(&first)));
+        assert!(!latest.accept("n1", Some(&second)));
+        assert!(latest.accept("n1", None));
+    }
+}
@@ -0,0 +1,376 @@
+#![allow(dead_code)]
+//! Tunables of the nodes. Defaults are overridden in order by a TOML or
+//! JSON file named by `--config` or `FLY_DIS_CONFIG`, by `FLY_DIS_<KEY>`
+//! environment variables and by `--<key>` flags, where `<key>` is a field
+//! name with dashes, e.g. `--gossip-interval-ms 200`.
+//!
+//! Every default lives here. Modules shared by several nodes keep their own
+//! config struct, built from this one by `raft`, `election` and `reliable`,
+//! so they do not depend on the tunables of the others.
+use std::{fs, path::Path, time::Duration};
+
+use anyhow::{anyhow, bail};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use serde_with::{serde_as, DurationMilliSeconds};
+
+use crate::{
+    election::ElectionConfig, event_queue::OverflowPolicy, message, raft::RaftConfig,
+    reliable::ReliableConfig,
+};
+
+/// Which peers a node gossips with every round.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerSelection {
+    All,
+    /// Neighbours from the `topology` message, all peers for nodes without one.
+    Topology,
+    /// `fanout` peers drawn anew every round.
+    Random,
+}
+
+/// How far a g-counter read goes before it replies.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadConsistency {
+    /// Local count plus the last counts gossiped by peers.
+    Local,
+    /// Polls peers until a majority of the cluster answered.
+    Quorum,
+    /// Polls peers until every one of them answered.
+    All,
+}
+
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Broadcast digest round.
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    #[serde(rename = "gossip_interval_ms")]
+    pub gossip_interval: Duration,
+    /// g-counter round sending the local count to peers.
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    #[serde(rename = "counter_interval_ms")]
+    pub counter_interval: Duration,
+    pub peer_selection: PeerSelection,
+    pub fanout: usize,
+    pub read_consistency: ReadConsistency,
+    /// Polling reads missing answers by then reply with what they know.
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    #[serde(rename = "read_timeout_ms")]
+    pub read_timeout: Duration,
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    #[serde(rename = "raft_election_timeout_ms")]
+    pub raft_election_timeout: Duration,
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    #[serde(rename = "raft_heartbeat_interval_ms")]
+    pub raft_heartbeat_interval: Duration,
+    /// Most entries sent in one `append_entries`.
+    pub raft_max_entries: usize,
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    #[serde(rename = "election_heartbeat_interval_ms")]
+    pub election_heartbeat_interval: Duration,
+    /// Silence after which a peer is suspected to have failed.
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    #[serde(rename = "election_failure_timeout_ms")]
+    pub election_failure_timeout: Duration,
+    /// How often unacked reliable messages are checked for being due again.
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    #[serde(rename = "retransmit_interval_ms")]
+    pub retransmit_interval: Duration,
+    /// Wait before the first retransmit, doubled on every further one.
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    #[serde(rename = "retransmit_initial_backoff_ms")]
+    pub retransmit_initial_backoff: Duration,
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    #[serde(rename = "retransmit_max_backoff_ms")]
+    pub retransmit_max_backoff: Duration,
+    /// Requests relayed to the leader without reply by then get a `timeout` error.
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    #[serde(rename = "forward_timeout_ms")]
+    pub forward_timeout: Duration,
+    /// Client requests whose reply is kept for retries, 0 turns it off.
+    pub dedup_capacity: usize,
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    #[serde(rename = "dedup_ttl_ms")]
+    pub dedup_ttl: Duration,
+    /// Messages waiting for the node before `overflow_policy` kicks in.
+    pub queue_capacity: usize,
+    pub overflow_policy: OverflowPolicy,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            gossip_interval: Duration::from_millis(500),
+            counter_interval: Duration::from_secs(1),
+            peer_selection: PeerSelection::Topology,
+            fanout: 3,
+            read_consistency: ReadConsistency::Local,
+            read_timeout: Duration::from_millis(500),
+            raft_election_timeout: Duration::from_millis(300),
+            raft_heartbeat_interval: Duration::from_millis(50),
+            raft_max_entries: 64,
+            election_heartbeat_interval: Duration::from_millis(100),
+            election_failure_timeout: Duration::from_millis(500),
+            retransmit_interval: Duration::from_millis(50),
+            retransmit_initial_backoff: Duration::from_millis(200),
+            retransmit_max_backoff: Duration::from_secs(5),
+            forward_timeout: Duration::from_secs(1),
+            dedup_capacity: 10_000,
+            dedup_ttl: Duration::from_secs(60),
+            queue_capacity: 1024,
+            overflow_policy: OverflowPolicy::Block,
+        }
+    }
+}
+
+impl Config {
+    /// Reads `path` as TOML unless it ends in `.json`, missing keys keep
+    /// their defaults.
+    pub fn from_file(path: impl AsRef<Path>) -> message::Result<Config> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|error| anyhow!("cannot read {}: {}", path.display(), error))?;
+        let config = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => serde_json::from_str(&text)?,
+            _ => toml::from_str(&text)?,
+        };
+        Ok(config)
+    }
+
+    /// Sets `key` from its text form, numbers and names alike.
+    pub fn set(&mut self, key: &str, value: &str) -> message::Result<()> {
+        let mut fields = serde_json::to_value(&*self)?;
+        let Some(field) = fields.get_mut(key) else {
+            bail!("unknown config key {}", key);
+        };
+        *field = serde_json::from_str(value).unwrap_or_else(|_| Value::from(value));
+        *self = serde_json::from_value(fields)
+            .map_err(|error| anyhow!("invalid {} {:?}: {}", key, value, error))?;
+        Ok(())
+    }
+
+    pub fn keys() -> Vec<String> {
+        match serde_json::to_value(Config::default()) {
+            Ok(Value::Object(fields)) => fields.keys().cloned().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn validate(&self) -> message::Result<()> {
+        let intervals = [
+            ("gossip_interval_ms", self.gossip_interval),
+            ("counter_interval_ms", self.counter_interval),
+            ("read_timeout_ms", self.read_timeout),
+            ("raft_heartbeat_interval_ms", self.raft_heartbeat_interval),
+            (
+                "election_heartbeat_interval_ms",
+                self.election_heartbeat_interval,
+            ),
+            ("retransmit_interval_ms", self.retransmit_interval),
+            (
+                "retransmit_initial_backoff_ms",
+                self.retransmit_initial_backoff,
+            ),
+            ("forward_timeout_ms", self.forward_timeout),
+            ("dedup_ttl_ms", self.dedup_ttl),
+        ];
+        for (key, interval) in intervals {
+            if interval.is_zero() {
+                bail!("{} must be positive", key);
+            }
+        }
+        if self.fanout == 0 {
+            bail!("fanout must be at least 1");
+        }
+        if self.queue_capacity == 0 {
+            bail!("queue_capacity must be at least 1");
+        }
+        if self.raft_max_entries == 0 {
+            bail!("raft_max_entries must be at least 1");
+        }
+        if self.raft_election_timeout <= self.raft_heartbeat_interval {
+            bail!("raft_election_timeout_ms must be above raft_heartbeat_interval_ms");
+        }
+        if self.election_failure_timeout <= self.election_heartbeat_interval {
+            bail!("election_failure_timeout_ms must be above election_heartbeat_interval_ms");
+        }
+        if self.retransmit_max_backoff < self.retransmit_initial_backoff {
+            bail!("retransmit_max_backoff_ms must not be below retransmit_initial_backoff_ms");
+        }
+        Ok(())
+    }
+
+    /// Builds the config from every source and returns it with the
+    /// arguments that are not config flags.
+    pub fn load(
+        args: &[String],
+        env: impl Fn(&str) -> Option<String>,
+    ) -> message::Result<(Config, Vec<String>)> {
+        let mut flags = Vec::new();
+        let mut rest = Vec::new();
+        let mut file = env("FLY_DIS_CONFIG");
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let Some(key) = arg.strip_prefix("--") else {
+                rest.push(arg.clone());
+                continue;
+            };
+            let Some(value) = args.next() else {
+                bail!("{} needs a value", arg);
+            };
+            match key {
+                "config" => file = Some(value.clone()),
+                _ => flags.push((key.replace('-', "_"), value.clone())),
+            }
+        }
+
+        let mut config = match file {
+            Some(file) => Config::from_file(file)?,
+            None => Config::default(),
+        };
+        for key in Config::keys() {
+            if let Some(value) = env(&format!("FLY_DIS_{}", key.to_uppercase())) {
+                config.set(&key, &value)?;
+            }
+        }
+        for (key, value) in flags {
+            config.set(&key, &value)?;
+        }
+        config.validate()?;
+        Ok((config, rest))
+    }
+
+    pub fn raft(&self) -> RaftConfig {
+        RaftConfig {
+            election_timeout: self.raft_election_timeout,
+            heartbeat_interval: self.raft_heartbeat_interval,
+            max_entries: self.raft_max_entries,
+        }
+    }
+
+    pub fn election(&self) -> ElectionConfig {
+        ElectionConfig {
+            heartbeat_interval: self.election_heartbeat_interval,
+            failure_timeout: self.election_failure_timeout,
+        }
+    }
+
+    pub fn reliable(&self) -> ReliableConfig {
+        ReliableConfig {
+            retransmit_interval: self.retransmit_interval,
+            initial_backoff: self.retransmit_initial_backoff,
+            max_backoff: self.retransmit_max_backoff,
+        }
+    }
+
+    /// Peers to gossip with this round. `neighbours` is the topology when
+    /// the node got one, `random` draws the peers for `Random`.
+    pub fn select_peers(
+        &self,
+        peers: &[String],
+        neighbours: Option<&[String]>,
+        mut random: impl FnMut() -> u64,
+    ) -> Vec<String> {
+        match self.peer_selection {
+            PeerSelection::All => peers.to_vec(),
+            PeerSelection::Topology => neighbours.unwrap_or(peers).to_vec(),
+            PeerSelection::Random => {
+                let mut peers = peers.to_vec();
+                let fanout = self.fanout.min(peers.len());
+                // partial Fisher-Yates, the first `fanout` are the picks.
+                for index in 0..fanout {
+                    let pick = index + (random() % (peers.len() - index) as u64) as usize;
+                    peers.swap(index, pick);
+                }
+                peers.truncate(fanout);
+                peers
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_flags_override_env_override_file() {
+        let dir = std::env::temp_dir().join(format!("fly_dis_config_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("run.toml");
+        fs::write(
+            &file,
+            "gossip_interval_ms = 100\ncounter_interval_ms = 200\nfanout = 5\n",
+        )
+        .unwrap();
+        let env = HashMap::from([
+            ("FLY_DIS_CONFIG", file.display().to_string()),
+            ("FLY_DIS_COUNTER_INTERVAL_MS", "300".to_string()),
+            ("FLY_DIS_PEER_SELECTION", "random".to_string()),
+            ("FLY_DIS_FANOUT", "4".to_string()),
+        ]);
+        let (config, rest) = Config::load(&args(&["broadcast", "--fanout", "2"]), |key| {
+            env.get(key).cloned()
+        })
+        .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(args(&["broadcast"]), rest);
+        assert_eq!(Duration::from_millis(100), config.gossip_interval);
+        assert_eq!(Duration::from_millis(300), config.counter_interval);
+        assert_eq!(PeerSelection::Random, config.peer_selection);
+        assert_eq!(2, config.fanout);
+        assert_eq!(Config::default().raft(), config.raft());
+    }
+
+    #[test]
+    fn test_invalid_settings_are_rejected() {
+        let load = |flags: &[&str]| Config::load(&args(flags), |_| None);
+        assert!(load(&["--gossip-interval-ms", "0"]).is_err());
+        assert!(load(&["--fanout", "many"]).is_err());
+        assert!(load(&["--peer-selection", "nearest"]).is_err());
+        assert!(load(&["--gossip-interval"]).is_err());
+        assert!(load(&["--no-such-key", "1"]).is_err());
+        assert!(load(&["--raft-election-timeout-ms", "50"]).is_err());
+        assert!(load(&["--election-failure-timeout-ms", "100"]).is_err());
+        assert!(load(&["--retransmit-max-backoff-ms", "100"]).is_err());
+        assert!(serde_json::from_str::<Config>(r#"{"fanout":2,"typo":1}"#).is_err());
+        let json = serde_json::from_str::<Config>(r#"{"peer_selection":"all"}"#).unwrap();
+        assert_eq!(PeerSelection::All, json.peer_selection);
+    }
+
+    #[test]
+    fn test_random_selection_picks_fanout_distinct_peers() {
+        let config = Config {
+            peer_selection: PeerSelection::Random,
+            fanout: 2,
+            ..Config::default()
+        };
+        let peers = args(&["n2", "n3", "n4", "n5"]);
+        let mut seed = 7u64;
+        for _ in 0..20 {
+            let picked = config.select_peers(&peers, None, || {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+                seed >> 33
+            });
+            assert_eq!(2, picked.len());
+            assert_ne!(picked[0], picked[1]);
+            assert!(picked.iter().all(|peer| peers.contains(peer)));
+        }
+        let topology = args(&["n3"]);
+        let config = Config::default();
+        assert_eq!(topology, config.select_peers(&peers, Some(&topology), || 0));
+        assert_eq!(peers, config.select_peers(&peers, None, || 0));
+    }
+}
@@ -1,114 +1,397 @@
 #![allow(dead_code, unused_variables)]
 use std::{
-    cell::{Cell, RefCell},
-    collections::HashMap,
-    io::BufRead,
-    sync::mpsc::{channel, Sender},
-    thread,
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, MutexGuard, RwLock,
+    },
     time::Duration,
 };
 
 use anyhow::Ok;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
 use crate::{
-    message::{self, Handler, Init, Message, Payload},
-    periodic_thread::PeriodicThread,
+    clock::Latest,
+    config::{Config, ReadConsistency},
+    message::{self, BodyTypes, Handler, Message, Payload},
+    output::Outbox,
+    reliable::{Reliable, ReliableChannel},
+    runtime::Node,
+    scheduler::{Scheduler, SchedulerRef, Timer},
 };
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum Counter {
-    Add { delta: usize },
-    AddOk { in_reply_to: usize },
+    Add {
+        delta: usize,
+    },
+    AddOk {
+        in_reply_to: usize,
+    },
     Read,
-    ReadOk { value: usize, in_reply_to: usize },
-    Current { value: usize },
+    ReadOk {
+        value: usize,
+        in_reply_to: usize,
+    },
+    Current {
+        value: usize,
+    },
+    /// Asks a peer for its own count on behalf of a polling read.
+    Poll,
+    PollOk {
+        value: usize,
+        in_reply_to: usize,
+    },
+}
+
+/// Counter bodies, and the deltas of every `add` that go to each peer over
+/// the reliable channel.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum CounterPayload {
+    Counter(Counter),
+    Delta(Reliable<usize>),
 }
 
-#[derive(Debug, Clone)]
+impl BodyTypes for CounterPayload {
+    const TYPES: &'static [&'static str] = &[
+        "add",
+        "add_ok",
+        "read",
+        "read_ok",
+        "current",
+        "poll",
+        "poll_ok",
+        "deliver",
+        "ack",
+        "retransmit",
+    ];
+}
+
+impl From<Counter> for CounterPayload {
+    fn from(counter: Counter) -> Self {
+        CounterPayload::Counter(counter)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum Internal {
     TriggerDispatch,
     TerminateDispatcher,
+    /// A polling read gives up waiting for answers.
+    ReadDeadline {
+        read: usize,
+    },
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum ExternalInternal {
     External(Message<Counter>),
+    Delta(Message<Reliable<usize>>),
     Internal(Internal),
 }
 
-#[derive(Debug)]
+/// What this node knows of the own count of a peer. Both parts only grow
+/// and neither goes beyond the count of the peer.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+struct PeerCount {
+    /// Sum of the deltas the peer sent, in the order it added them.
+    delivered: usize,
+    /// Largest count the peer reported in a `current` or `poll_ok`.
+    reported: usize,
+}
+
+impl PeerCount {
+    fn value(&self) -> usize {
+        self.delivered.max(self.reported)
+    }
+}
+
+/// Client read waiting for answers to the polls, keyed by the `msg_id` the
+/// polls were sent with.
+struct PendingRead {
+    request: Message<Counter>,
+    answered: HashSet<String>,
+    needed: usize,
+    deadline: Timer,
+}
+
 pub struct CounterNode {
     node_id: String,
     all_node_ids: Vec<String>,
-    current_count: Cell<usize>,
-    other_node_count_map: RefCell<HashMap<String, usize>>,
-    gossip_trigger_task: RefCell<Option<PeriodicThread>>,
+    config: RwLock<Config>,
+    scheduler: SchedulerRef<ExternalInternal>,
+    current_count: AtomicUsize,
+    other_node_count_map: Mutex<HashMap<String, PeerCount>>,
+    channel: Mutex<ReliableChannel<usize, ExternalInternal>>,
+    latest_current: Mutex<Latest>,
+    gossip_trigger_task: Mutex<Option<Timer>>,
+    dispatch_rounds: AtomicUsize,
+    pending_reads: Mutex<HashMap<usize, PendingRead>>,
+    next_read: AtomicUsize,
+    read_fallbacks: AtomicUsize,
 }
 
 impl CounterNode {
-    pub fn new(node_id: String, all_node_ids: Vec<String>, tx: Sender<ExternalInternal>) -> Self {
-        let gossip_trigger_task = PeriodicThread::new(
-            move || {
-                tx.send(ExternalInternal::Internal(Internal::TriggerDispatch {}))?;
-                Ok(())
-            },
-            Duration::from_secs(1),
-        );
+    pub fn new(
+        node_id: String,
+        all_node_ids: Vec<String>,
+        config: &Config,
+        scheduler: SchedulerRef<ExternalInternal>,
+    ) -> Self {
+        let gossip_trigger_task = CounterNode::start_dispatch(scheduler.as_ref(), config);
         let other_node_count_map = all_node_ids
             .iter()
             .filter(|id| node_id != **id)
-            .map(|node_id| (node_id.clone(), 0))
+            .map(|node_id| (node_id.clone(), PeerCount::default()))
             .collect();
+        let channel = ReliableChannel::new(node_id.clone(), config.reliable(), scheduler.clone());
         CounterNode {
             node_id,
-            all_node_ids: all_node_ids,
-            current_count: Cell::new(0),
-            other_node_count_map: RefCell::new(other_node_count_map),
-            gossip_trigger_task: RefCell::new(Some(gossip_trigger_task)),
+            all_node_ids,
+            config: RwLock::new(config.clone()),
+            scheduler,
+            current_count: AtomicUsize::new(0),
+            other_node_count_map: Mutex::new(other_node_count_map),
+            channel: Mutex::new(channel),
+            latest_current: Mutex::default(),
+            gossip_trigger_task: Mutex::new(Some(gossip_trigger_task)),
+            dispatch_rounds: AtomicUsize::new(0),
+            pending_reads: Mutex::default(),
+            next_read: AtomicUsize::new(0),
+            read_fallbacks: AtomicUsize::new(0),
         }
     }
 
-    pub fn main_loop() -> message::Result<()> {
-        let mut stdin = std::io::stdin().lock();
-        let mut init_message = String::new();
-        stdin.read_line(&mut init_message)?;
-        let mut stdout = std::io::stdout().lock();
+    fn start_dispatch(scheduler: &dyn Scheduler<ExternalInternal>, config: &Config) -> Timer {
+        scheduler.every(
+            config.counter_interval,
+            Box::new(|| ExternalInternal::Internal(Internal::TriggerDispatch {})),
+        )
+    }
 
-        let init_message = serde_json::from_str::<Message<Init>>(&init_message)?;
-        let _ = init_message
-            .body
-            .data
-            .handle(&mut stdout, init_message.clone())?;
+    fn peer_counts(&self) -> MutexGuard<'_, HashMap<String, PeerCount>> {
+        self.other_node_count_map
+            .lock()
+            .expect("count map lock poisoned")
+    }
 
-        let (tx, rx) = channel();
+    fn channel(&self) -> MutexGuard<'_, ReliableChannel<usize, ExternalInternal>> {
+        self.channel.lock().expect("channel lock poisoned")
+    }
 
-        drop(stdin);
-        let tx_cloned = tx.clone();
-        let join_handler = thread::spawn(move || {
-            let stdin = std::io::stdin().lock();
-            for line in stdin.lines() {
-                let incoming = line?.parse::<Message<Counter>>()?;
-                tx_cloned.send(ExternalInternal::External(incoming))?;
-            }
-            tx_cloned.send(ExternalInternal::Internal(Internal::TerminateDispatcher))?;
-            Ok(())
-        });
+    fn peers(&self) -> Vec<String> {
+        self.all_node_ids
+            .iter()
+            .filter(|id| **id != self.node_id)
+            .cloned()
+            .collect()
+    }
 
-        let (node_id, all_nodes_ids) = match init_message.body.data {
-            Init::Init { node_id, node_ids } => (node_id.clone(), node_ids),
-            _ => panic!("First message should be of type echo"),
+    fn pending_reads(&self) -> MutexGuard<'_, HashMap<usize, PendingRead>> {
+        self.pending_reads
+            .lock()
+            .expect("pending reads lock poisoned")
+    }
+
+    fn total_count(&self) -> usize {
+        self.peer_counts()
+            .values()
+            .fold(0usize, |total, count| total.saturating_add(count.value()))
+            .saturating_add(self.current_count.load(Ordering::SeqCst))
+    }
+
+    fn read_reply(&self, request: Message<Counter>) -> Message<Counter> {
+        let in_reply_to = request.body.msg_id.unwrap_or(1);
+        Message::to_response(
+            request,
+            Counter::ReadOk {
+                value: self.total_count(),
+                in_reply_to,
+            },
+        )
+    }
+
+    /// Replies right away for `Local`, otherwise polls every peer and
+    /// replies once enough of them answered or the deadline passed.
+    fn read(&self, outbox: &Outbox, request: Message<Counter>) -> message::Result<()> {
+        let peers = self.peers();
+        let (consistency, timeout) = {
+            let config = self.config.read().expect("config lock poisoned");
+            (config.read_consistency, config.read_timeout)
         };
-        let counter_node = CounterNode::new(node_id, all_nodes_ids, tx);
+        // a majority of the cluster, this node included.
+        let needed = match consistency {
+            ReadConsistency::Local => 0,
+            ReadConsistency::Quorum => peers.len().div_ceil(2),
+            ReadConsistency::All => peers.len(),
+        };
+        if needed == 0 {
+            return outbox.send(&self.read_reply(request));
+        }
+        let read = self.next_read.fetch_add(1, Ordering::Relaxed);
+        let deadline = self.scheduler.after(
+            timeout,
+            ExternalInternal::Internal(Internal::ReadDeadline { read }),
+        );
+        self.pending_reads().insert(
+            read,
+            PendingRead {
+                request,
+                answered: HashSet::new(),
+                needed,
+                deadline,
+            },
+        );
+        for peer in peers {
+            outbox.send(&Message::new(
+                self.node_id.clone(),
+                peer,
+                Payload::new(Counter::Poll, Some(read)),
+            ))?;
+        }
+        Ok(())
+    }
 
-        for message in rx {
-            counter_node.handle(&mut stdout, message)?;
+    fn poll_answered(
+        &self,
+        outbox: &Outbox,
+        from: String,
+        value: usize,
+        read: usize,
+    ) -> message::Result<()> {
+        // counts only grow, an answer never takes one back.
+        self.peer_counts()
+            .entry(from.clone())
+            .and_modify(|count| count.reported = count.reported.max(value));
+        let done = {
+            let mut pending_reads = self.pending_reads();
+            match pending_reads.get_mut(&read) {
+                Some(pending) => {
+                    pending.answered.insert(from);
+                    match pending.answered.len() >= pending.needed {
+                        true => pending_reads.remove(&read),
+                        false => None,
+                    }
+                }
+                None => None,
+            }
+        };
+        match done {
+            Some(pending) => outbox.send(&self.read_reply(pending.request)),
+            None => Ok(()),
         }
+    }
+}
 
-        let _ = join_handler.join().expect("Main panicked");
+impl From<Message<Counter>> for ExternalInternal {
+    fn from(message: Message<Counter>) -> Self {
+        ExternalInternal::External(message)
+    }
+}
 
-        Ok(())
+impl From<Message<Reliable<usize>>> for ExternalInternal {
+    fn from(message: Message<Reliable<usize>>) -> Self {
+        ExternalInternal::Delta(message)
+    }
+}
+
+impl From<Message<CounterPayload>> for ExternalInternal {
+    fn from(message: Message<CounterPayload>) -> Self {
+        let Message { src, dst, body } = message;
+        let Payload {
+            data,
+            msg_id,
+            clock,
+        } = body;
+        match data {
+            CounterPayload::Counter(data) => ExternalInternal::External(Message {
+                src,
+                dst,
+                body: Payload {
+                    data,
+                    msg_id,
+                    clock,
+                },
+            }),
+            CounterPayload::Delta(data) => ExternalInternal::Delta(Message {
+                src,
+                dst,
+                body: Payload {
+                    data,
+                    msg_id,
+                    clock,
+                },
+            }),
+        }
+    }
+}
+
+impl Node for CounterNode {
+    type Payload = CounterPayload;
+    type Event = ExternalInternal;
+
+    const LIVE_KEYS: &'static [&'static str] = &[
+        "counter_interval_ms",
+        "peer_selection",
+        "fanout",
+        "read_consistency",
+        "read_timeout_ms",
+    ];
+
+    fn from_init(
+        node_id: String,
+        node_ids: Vec<String>,
+        scheduler: SchedulerRef<Self::Event>,
+        config: &Config,
+    ) -> Self {
+        CounterNode::new(node_id, node_ids, config, scheduler)
+    }
+
+    fn shutdown_event() -> Option<Self::Event> {
+        Some(ExternalInternal::Internal(Internal::TerminateDispatcher))
+    }
+
+    fn ordering_key(event: &Self::Event) -> Option<&str> {
+        match event {
+            ExternalInternal::External(message) => Some(&message.src),
+            ExternalInternal::Delta(message) => Some(&message.src),
+            ExternalInternal::Internal(_) => None,
+        }
+    }
+
+    fn stats(&self) -> Value {
+        json!({
+            "dispatch_rounds": self.dispatch_rounds.load(Ordering::Relaxed),
+            "peers": self.peer_counts().len(),
+            "pending_reads": self.pending_reads().len(),
+            "unacked_deltas": self.channel().unacked(),
+            "read_fallbacks": self.read_fallbacks.load(Ordering::Relaxed),
+        })
+    }
+
+    fn dump_state(&self) -> Value {
+        json!({
+            "local_count": self.current_count.load(Ordering::SeqCst),
+            "peer_counts": BTreeMap::from_iter(self.peer_counts().clone()),
+        })
+    }
+
+    fn set_config(&self, config: &Config) {
+        let mut current = self.config.write().expect("config lock poisoned");
+        let mut task = self
+            .gossip_trigger_task
+            .lock()
+            .expect("gossip task lock poisoned");
+        // a stopped node stays stopped.
+        if config.counter_interval != current.counter_interval && task.is_some() {
+            *task = Some(CounterNode::start_dispatch(self.scheduler.as_ref(), config));
+        }
+        *current = config.clone();
     }
 }
 
@@ -126,16 +409,17 @@ impl<T> ToResponse<T> for Message<T> {
     }
 }
 impl Handler<ExternalInternal> for CounterNode {
-    fn handle(
-        &self,
-        writer: &mut dyn std::io::Write,
-        message: ExternalInternal,
-    ) -> message::Result<()> {
+    fn handle(&self, outbox: &Outbox, message: ExternalInternal) -> message::Result<()> {
         let maybe_response = match message {
             ExternalInternal::External(message) => match message.body.data {
                 Counter::Add { delta } => {
-                    let updated_value = self.current_count.get() + delta;
-                    self.current_count.set(updated_value);
+                    self.current_count.fetch_add(delta, Ordering::SeqCst);
+                    if delta > 0 {
+                        let mut channel = self.channel();
+                        for peer in self.peers() {
+                            channel.send(outbox, &peer, delta)?;
+                        }
+                    }
                     let in_reply_to = message.body.msg_id.unwrap_or(1);
                     Some(Message::to_response(
                         message,
@@ -144,17 +428,22 @@ impl Handler<ExternalInternal> for CounterNode {
                 }
                 Counter::AddOk { .. } => None,
                 Counter::Read => {
-                    let total_count = self.other_node_count_map.borrow().values().sum::<usize>();
+                    self.read(outbox, message)?;
+                    None
+                }
+                Counter::ReadOk { .. } => None,
+                Counter::Poll => {
                     let in_reply_to = message.body.msg_id.unwrap_or(1);
+                    let value = self.current_count.load(Ordering::SeqCst);
                     Some(Message::to_response(
                         message,
-                        Counter::ReadOk {
-                            value: total_count + self.current_count.get(),
-                            in_reply_to,
-                        },
+                        Counter::PollOk { value, in_reply_to },
                     ))
                 }
-                Counter::ReadOk { .. } => None,
+                Counter::PollOk { value, in_reply_to } => {
+                    self.poll_answered(outbox, message.src, value, in_reply_to)?;
+                    None
+                }
                 Counter::Current { value } => {
                     // println!(
                     //     "Received {} for node {} with map {:?}",
@@ -163,48 +452,133 @@ impl Handler<ExternalInternal> for CounterNode {
                     //     self.other_node_count_map.borrow()
                     // );
                     let from = message.src;
+                    let fresh = self
+                        .latest_current
+                        .lock()
+                        .expect("latest current lock poisoned")
+                        .accept(&from, message.body.clock.as_ref());
+                    // an older count overtaken on the way would go back in time.
+                    if !fresh {
+                        return Ok(());
+                    }
                     self.other_node_count_map
-                        .borrow_mut()
+                        .lock()
+                        .expect("count map lock poisoned")
                         .entry(from)
-                        .and_modify(|count| *count = value);
+                        // a poll answer may have brought a newer count already.
+                        .and_modify(|count| count.reported = count.reported.max(value));
                     None
                 }
             },
+            ExternalInternal::Delta(message) => {
+                let delivered = self.channel().handle(outbox, message)?;
+                let mut counts = self.peer_counts();
+                for delta in delivered {
+                    counts
+                        .entry(delta.src)
+                        .and_modify(|count| count.delivered += delta.body.data);
+                }
+                None
+            }
             ExternalInternal::Internal(message) => match message {
                 Internal::TerminateDispatcher => {
-                    if let Some(handler) = self.gossip_trigger_task.take() {
-                        println!("requesting close of thread");
+                    let handler = self
+                        .gossip_trigger_task
+                        .lock()
+                        .expect("gossip task lock poisoned")
+                        .take();
+                    if let Some(handler) = handler {
+                        eprintln!("requesting close of thread");
                         drop(handler);
                     }
+                    self.channel().stop();
                     None
                 }
                 Internal::TriggerDispatch => {
+                    self.dispatch_rounds.fetch_add(1, Ordering::Relaxed);
                     let current_message = Counter::Current {
-                        value: self.current_count.get(),
+                        value: self.current_count.load(Ordering::SeqCst),
                     };
-                    for other in self.all_node_ids.iter() {
-                        if *other == self.node_id {
-                            continue;
-                        }
+                    let peers = self.peers();
+                    let targets = self
+                        .config
+                        .read()
+                        .expect("config lock poisoned")
+                        .select_peers(&peers, None, || self.scheduler.random());
+                    for other in targets.iter() {
                         let current_message = Message::new(
                             self.node_id.clone(),
                             other.clone(),
-                            Payload {
-                                data: current_message.clone(),
-                                msg_id: None,
-                            },
+                            Payload::new(current_message.clone(), None),
                         );
-                        serde_json::to_writer(&mut *writer, &current_message)?;
-                        writer.write_all(b"\n")?;
+                        outbox.send(&current_message)?;
                     }
                     None
                 }
+                Internal::ReadDeadline { read } => {
+                    let pending = self.pending_reads().remove(&read);
+                    // too few answers, reply with the counts known so far.
+                    pending.map(|pending| {
+                        self.read_fallbacks.fetch_add(1, Ordering::Relaxed);
+                        self.read_reply(pending.request)
+                    })
+                }
             },
         };
         if let Some(response) = maybe_response {
-            serde_json::to_writer(&mut *writer, &response)?;
-            writer.write_all(b"\n")?;
+            outbox.send(&response)?;
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::scheduler::VirtualTime;
+
+    fn from_n2(counter: Counter, msg_id: Option<usize>) -> ExternalInternal {
+        ExternalInternal::External(Message::new(
+            "n2".to_string(),
+            "n1".to_string(),
+            Payload::new(counter, msg_id),
+        ))
+    }
+
+    #[test]
+    fn test_late_current_does_not_lower_polled_count() {
+        let node = CounterNode::new(
+            "n1".to_string(),
+            vec!["n1".into(), "n2".into(), "n3".into()],
+            &Config::default(),
+            Arc::new(VirtualTime::default().scheduler("n1")),
+        );
+        let (outbox, replies) = Outbox::channel();
+        let poll_ok = Counter::PollOk {
+            value: 5,
+            in_reply_to: 1,
+        };
+        node.handle(&outbox, from_n2(poll_ok, None)).unwrap();
+        // sent before n2 answered the poll, delivered after.
+        node.handle(&outbox, from_n2(Counter::Current { value: 3 }, None))
+            .unwrap();
+        let read = ExternalInternal::External(Message::new(
+            "c1".to_string(),
+            "n1".to_string(),
+            Payload::new(Counter::Read, Some(2)),
+        ));
+        node.handle(&outbox, read).unwrap();
+
+        let reply = replies.try_iter().last().unwrap();
+        let reply = reply.parse::<Message<Counter>>().unwrap();
+        assert_eq!(
+            Counter::ReadOk {
+                value: 5,
+                in_reply_to: 2
+            },
+            reply.body.data
+        );
+    }
+}
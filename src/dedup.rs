@@ -0,0 +1,230 @@
+#![allow(dead_code)]
+//! Replies to client requests remembered by `(src, msg_id)`, so a retried
+//! request gets the original reply again instead of being applied twice.
+//! The runtime consults it for every message read before the node sees it
+//! and fills it from the outbox, handlers need not do anything.
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::scheduler::Clock;
+
+/// What to do with a request read from a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Seen {
+    /// First time, let the node handle it.
+    New,
+    /// The original is still being handled, its reply will answer both.
+    InProgress,
+    /// Already answered with this line.
+    Replied(String),
+}
+
+struct Entry {
+    at: Duration,
+    reply: Option<String>,
+}
+
+type Key = (String, usize);
+
+/// Bounded to `capacity` requests, each forgotten `ttl` after it was read.
+pub struct ReplyCache {
+    capacity: usize,
+    ttl: Duration,
+    // messages from nodes are never cached, only those from clients.
+    node_ids: HashSet<String>,
+    clock: Arc<dyn Clock>,
+    entries: HashMap<Key, Entry>,
+    // read order, a key whose entry was replaced may appear twice.
+    order: VecDeque<(Key, Duration)>,
+}
+
+impl fmt::Debug for ReplyCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReplyCache")
+            .field("capacity", &self.capacity)
+            .field("ttl", &self.ttl)
+            .field("len", &self.entries.len())
+            .finish()
+    }
+}
+
+impl ReplyCache {
+    pub fn new(capacity: usize, ttl: Duration, node_ids: &[String], clock: Arc<dyn Clock>) -> Self {
+        ReplyCache {
+            capacity,
+            ttl,
+            node_ids: node_ids.iter().cloned().collect(),
+            clock,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Looks up a request and starts tracking it when it is new. Requests
+    /// from nodes or without `msg_id` are always new.
+    pub fn admit(&mut self, src: &str, msg_id: Option<usize>) -> Seen {
+        let Some(msg_id) = msg_id.filter(|_| self.tracks(src)) else {
+            return Seen::New;
+        };
+        let now = self.clock.now();
+        self.expire(now);
+        let key = (src.to_string(), msg_id);
+        if let Some(entry) = self.entries.get(&key) {
+            return match &entry.reply {
+                Some(reply) => Seen::Replied(reply.clone()),
+                None => Seen::InProgress,
+            };
+        }
+        if self.capacity == 0 {
+            return Seen::New;
+        }
+        while self.entries.len() >= self.capacity {
+            self.evict_oldest();
+        }
+        self.entries.insert(
+            key.clone(),
+            Entry {
+                at: now,
+                reply: None,
+            },
+        );
+        self.order.push_back((key, now));
+        Seen::New
+    }
+
+    /// Remembers `line` as the answer to `in_reply_to` from `dest`, unless
+    /// that request was never admitted or has been forgotten since.
+    pub fn reply(&mut self, dest: &str, in_reply_to: usize, line: &str) {
+        let key = (dest.to_string(), in_reply_to);
+        if let Some(entry) = self.entries.get_mut(&key) {
+            entry.reply.get_or_insert_with(|| line.to_string());
+        }
+    }
+
+    /// Forgets a request still waiting for its reply, the node will never
+    /// see it and a retry has to get through.
+    pub fn forget(&mut self, src: &str, msg_id: usize) {
+        let key = (src.to_string(), msg_id);
+        if self
+            .entries
+            .get(&key)
+            .is_some_and(|entry| entry.reply.is_none())
+        {
+            self.entries.remove(&key);
+        }
+    }
+
+    /// Whether replies to `dest` may be cached at all.
+    pub fn tracks(&self, src: &str) -> bool {
+        !self.node_ids.contains(src)
+    }
+
+    fn expire(&mut self, now: Duration) {
+        while self
+            .order
+            .front()
+            .is_some_and(|(_, at)| now.saturating_sub(*at) >= self.ttl)
+        {
+            self.evict_oldest();
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        let Some((key, at)) = self.order.pop_front() else {
+            return;
+        };
+        if self.entries.get(&key).is_some_and(|entry| entry.at == at) {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct ManualClock(Mutex<Duration>);
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Duration {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn cache(capacity: usize, clock: &Arc<ManualClock>) -> ReplyCache {
+        ReplyCache::new(
+            capacity,
+            Duration::from_secs(10),
+            &["n1".to_string(), "n2".to_string()],
+            clock.clone(),
+        )
+    }
+
+    #[test]
+    fn test_retries_get_the_original_reply() {
+        let clock = Arc::new(ManualClock::default());
+        let mut cache = cache(8, &clock);
+        assert_eq!(Seen::New, cache.admit("c1", Some(1)));
+        assert_eq!(Seen::InProgress, cache.admit("c1", Some(1)));
+        cache.reply("c1", 1, "first");
+        cache.reply("c1", 1, "second");
+        assert_eq!(
+            Seen::Replied("first".to_string()),
+            cache.admit("c1", Some(1))
+        );
+        assert_eq!(Seen::New, cache.admit("c2", Some(1)));
+        assert_eq!(Seen::New, cache.admit("c1", None));
+        // nodes resend on purpose, their messages are never held back.
+        assert_eq!(Seen::New, cache.admit("n2", Some(1)));
+        assert_eq!(Seen::New, cache.admit("n2", Some(1)));
+        cache.reply("c3", 9, "never asked");
+        assert_eq!(2, cache.len());
+    }
+
+    #[test]
+    fn test_oldest_requests_are_forgotten_first() {
+        let clock = Arc::new(ManualClock::default());
+        let mut cache = cache(3, &clock);
+        for msg_id in 1..=4 {
+            *clock.0.lock().unwrap() = Duration::from_secs(msg_id as u64);
+            assert_eq!(Seen::New, cache.admit("c1", Some(msg_id)));
+            cache.reply("c1", msg_id, "ok");
+        }
+        assert_eq!(3, cache.len());
+        assert_eq!(Seen::New, cache.admit("c1", Some(1)));
+        assert_eq!(Seen::Replied("ok".to_string()), cache.admit("c1", Some(4)));
+
+        // 3 expires at 13s, 4 and the admitted again 1 at 14s.
+        *clock.0.lock().unwrap() = Duration::from_secs(13);
+        assert_eq!(Seen::New, cache.admit("c1", Some(3)));
+        assert_eq!(Seen::Replied("ok".to_string()), cache.admit("c1", Some(4)));
+        *clock.0.lock().unwrap() = Duration::from_secs(14);
+        assert_eq!(Seen::New, cache.admit("c1", Some(4)));
+        assert_eq!(Seen::InProgress, cache.admit("c1", Some(3)));
+    }
+
+    #[test]
+    fn test_zero_capacity_caches_nothing() {
+        let clock = Arc::new(ManualClock::default());
+        let mut cache = cache(0, &clock);
+        assert_eq!(Seen::New, cache.admit("c1", Some(1)));
+        cache.reply("c1", 1, "ok");
+        assert_eq!(Seen::New, cache.admit("c1", Some(1)));
+        assert!(cache.is_empty());
+    }
+}
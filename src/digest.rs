@@ -0,0 +1,92 @@
+#![allow(dead_code)]
+//! Digests of a broadcast value set for anti-entropy. Values fall into
+//! buckets of `BUCKET_SIZE`, each bucket is hashed and the root hashes the
+//! bucket hashes. Nodes that agree only exchange roots, otherwise the
+//! bucket hashes tell which values have to be sent.
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet},
+    hash::{Hash, Hasher},
+};
+
+use crate::interval_set::IntervalSet;
+
+pub const BUCKET_SIZE: usize = 128;
+
+/// Hash of every non-empty bucket by bucket number.
+pub type BucketHashes = BTreeMap<usize, u64>;
+
+// hashes stay exact for JSON readers that parse numbers as doubles.
+const HASH_MASK: u64 = (1 << 53) - 1;
+
+fn bucket_range(bucket: usize) -> (usize, usize) {
+    // bucket numbers come from peers, so out of range ones must not overflow.
+    let start = bucket.saturating_mul(BUCKET_SIZE);
+    (start, start.saturating_add(BUCKET_SIZE - 1))
+}
+
+pub fn bucket_hashes(set: &IntervalSet) -> BucketHashes {
+    let mut hashers = BTreeMap::<usize, DefaultHasher>::new();
+    for &(start, end) in set.ranges() {
+        for bucket in start / BUCKET_SIZE..=end / BUCKET_SIZE {
+            let (bucket_start, bucket_end) = bucket_range(bucket);
+            let hasher = hashers.entry(bucket).or_default();
+            (start.max(bucket_start), end.min(bucket_end)).hash(hasher);
+        }
+    }
+    hashers
+        .into_iter()
+        .map(|(bucket, hasher)| (bucket, hasher.finish() & HASH_MASK))
+        .collect()
+}
+
+pub fn root(buckets: &BucketHashes) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    buckets.hash(&mut hasher);
+    hasher.finish() & HASH_MASK
+}
+
+/// Buckets whose hash differs or that only one side has.
+pub fn differing(local: &BucketHashes, remote: &BucketHashes) -> Vec<usize> {
+    local
+        .keys()
+        .chain(remote.keys())
+        .filter(|bucket| local.get(bucket) != remote.get(bucket))
+        .copied()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// Values of `set` that fall into `buckets`.
+pub fn in_buckets(set: &IntervalSet, buckets: &[usize]) -> IntervalSet {
+    let mut selected = IntervalSet::new();
+    for bucket in buckets {
+        let (start, end) = bucket_range(*bucket);
+        selected.union(&set.clamped(start, end));
+    }
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_changed_buckets_differ() {
+        let mut local = (0..1000).collect::<IntervalSet>();
+        let remote = bucket_hashes(&local);
+        assert_eq!(8, remote.len());
+        assert_eq!(root(&remote), root(&bucket_hashes(&local)));
+
+        local.insert(300);
+        assert_eq!(root(&remote), root(&bucket_hashes(&local)));
+        local.insert(5000);
+        local.insert(1000);
+        let changed = differing(&bucket_hashes(&local), &remote);
+        assert_eq!(vec![7, 39], changed);
+        assert_eq!(
+            &[(896, 1000), (5000, 5000)],
+            in_buckets(&local, &changed).ranges()
+        );
+    }
+}
@@ -1,19 +1,27 @@
 #![allow(dead_code)]
 
-use std::io;
-
-use anyhow::Ok;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use crate::message::{self, Handler, Message, Payload};
+use crate::{
+    config::Config,
+    message::{self, BodyTypes, Handler, Message, Payload},
+    output::Outbox,
+    runtime::Node,
+    scheduler::SchedulerRef,
+};
 
-#[derive(Debug, Serialize, Clone, Deserialize, Hash, PartialEq, Eq)]
+#[derive(Debug, Serialize, Clone, Deserialize, JsonSchema, Hash, PartialEq, Eq)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum Echo {
     Echo { echo: String },
     EchoOk { echo: String, in_reply_to: usize },
 }
 
+impl BodyTypes for Echo {
+    const TYPES: &'static [&'static str] = &["echo", "echo_ok"];
+}
+
 #[derive(Debug, Clone)]
 pub struct EchoNode {
     node: String,
@@ -25,16 +33,31 @@ impl EchoNode {
     }
 }
 
+impl Node for EchoNode {
+    type Payload = Echo;
+    type Event = Message<Echo>;
+
+    fn from_init(
+        node_id: String,
+        _node_ids: Vec<String>,
+        _scheduler: SchedulerRef<Self::Event>,
+        _config: &Config,
+    ) -> Self {
+        EchoNode::new(node_id)
+    }
+
+    fn ordering_key(event: &Self::Event) -> Option<&str> {
+        Some(&event.src)
+    }
+}
+
 impl Handler<Message<Echo>> for EchoNode {
-    fn handle(
-        &self,
-        writer: &mut dyn io::Write,
-        message: message::Message<Echo>,
-    ) -> message::Result<()> {
+    fn handle(&self, outbox: &Outbox, message: message::Message<Echo>) -> message::Result<()> {
         let echo_response = Echo::EchoOk {
             echo: match message.body.data {
                 Echo::Echo { ref echo } => echo.clone(),
-                _ => panic!("should not receive this"),
+                // replies are never addressed to this node, nothing to answer.
+                Echo::EchoOk { .. } => return Ok(()),
             },
             in_reply_to: message.body.msg_id.unwrap_or(1),
         };
@@ -44,8 +67,6 @@ impl Handler<Message<Echo>> for EchoNode {
             Payload::new(echo_response, message.body.msg_id),
         );
 
-        serde_json::to_writer(&mut *writer, &message)?;
-        writer.write_all(b"\n")?;
-        Ok(())
+        outbox.send(&message)
     }
 }
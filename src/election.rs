@@ -0,0 +1,357 @@
+#![allow(dead_code)]
+//! Leader election over a heartbeat failure detector. Every node pings its
+//! peers each `heartbeat_interval` and suspects those it has not heard from
+//! for `failure_timeout`. The lowest live id leads, but only while it sees a
+//! majority, so the minority side of a partition has no leader at all. Ids
+//! compare by their numeric suffix, so `n2` comes before `n10`.
+//!
+//! This is not consensus: with asymmetric links two nodes can briefly both
+//! claim leadership. Terms only grow, so writers that need safety can fence
+//! with them, anything stronger should go through `raft`.
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::Duration,
+};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Config,
+    message::{self, BodyTypes, Message, Payload},
+    output::Outbox,
+    scheduler::{SchedulerRef, Timer},
+};
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ElectionMessage {
+    /// `leader` is who the sender follows, itself when it leads.
+    Heartbeat {
+        term: u64,
+        leader: Option<String>,
+    },
+    // timer event a node sends itself.
+    Tick,
+}
+
+impl BodyTypes for ElectionMessage {
+    const TYPES: &'static [&'static str] = &["heartbeat", "tick"];
+}
+
+#[derive(Debug, Clone)]
+pub struct ElectionConfig {
+    pub heartbeat_interval: Duration,
+    /// Silence after which a peer is suspected to have failed.
+    pub failure_timeout: Duration,
+}
+
+impl Default for ElectionConfig {
+    fn default() -> Self {
+        Config::default().election()
+    }
+}
+
+/// Leader as seen by one node, `None` while there is no majority or the
+/// lowest live node has not claimed leadership yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Leadership {
+    pub leader: Option<String>,
+    pub term: u64,
+}
+
+/// Election state of one node. Ticks are scheduled as messages of type `E`
+/// the node sends itself and must hand back to `handle`.
+pub struct Election<E> {
+    node_id: String,
+    peers: Vec<String>,
+    config: ElectionConfig,
+    scheduler: SchedulerRef<E>,
+    // time is counted in ticks so it follows the scheduler, virtual or not.
+    ticks: u64,
+    last_seen: HashMap<String, u64>,
+    // term of the peers whose last heartbeat claimed leadership.
+    claims: HashMap<String, u64>,
+    term: u64,
+    leader: Option<String>,
+    timer: Option<Timer>,
+}
+
+impl<E> Election<E>
+where
+    E: From<Message<ElectionMessage>> + Send + 'static,
+{
+    pub fn new(
+        node_id: String,
+        node_ids: Vec<String>,
+        config: ElectionConfig,
+        scheduler: SchedulerRef<E>,
+    ) -> Self {
+        let peers = node_ids
+            .into_iter()
+            .filter(|id| *id != node_id)
+            .collect::<Vec<_>>();
+        let tick_node = node_id.clone();
+        let timer = scheduler.every(
+            config.heartbeat_interval,
+            Box::new(move || {
+                E::from(Message::new(
+                    tick_node.clone(),
+                    tick_node.clone(),
+                    Payload::new(ElectionMessage::Tick, None),
+                ))
+            }),
+        );
+        // peers get a full timeout to show up before they are suspected.
+        let last_seen = peers.iter().map(|peer| (peer.clone(), 0)).collect();
+        Election {
+            node_id,
+            peers,
+            config,
+            scheduler,
+            ticks: 0,
+            last_seen,
+            claims: HashMap::new(),
+            term: 0,
+            leader: None,
+            timer: Some(timer),
+        }
+    }
+
+    pub fn leadership(&self) -> Leadership {
+        Leadership {
+            leader: self.leader.clone(),
+            term: self.term,
+        }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.leader.as_deref() == Some(self.node_id.as_str())
+    }
+
+    /// This node and the peers it is not suspecting.
+    pub fn live(&self) -> BTreeSet<&str> {
+        let timeout = self.timeout_ticks();
+        self.last_seen
+            .iter()
+            .filter(|(_, seen)| self.ticks - **seen <= timeout)
+            .map(|(peer, _)| peer.as_str())
+            .chain([self.node_id.as_str()])
+            .collect()
+    }
+
+    /// Cancels the ticks, the node stops sending heartbeats.
+    pub fn stop(&mut self) {
+        self.timer = None;
+    }
+
+    /// Handles a heartbeat or tick and returns the new leadership when it changed.
+    pub fn handle(
+        &mut self,
+        outbox: &Outbox,
+        message: Message<ElectionMessage>,
+    ) -> message::Result<Option<Leadership>> {
+        match message.body.data {
+            ElectionMessage::Tick => {
+                self.ticks += 1;
+                for peer in &self.peers {
+                    let heartbeat = ElectionMessage::Heartbeat {
+                        term: self.term,
+                        leader: self.leader.clone(),
+                    };
+                    outbox.send(&Message::new(
+                        self.node_id.clone(),
+                        peer.clone(),
+                        Payload::new(heartbeat, None),
+                    ))?;
+                }
+            }
+            ElectionMessage::Heartbeat { term, leader } => {
+                let Some(seen) = self.last_seen.get_mut(&message.src) else {
+                    return Ok(None);
+                };
+                *seen = self.ticks;
+                self.term = self.term.max(term);
+                if leader.as_ref() == Some(&message.src) {
+                    self.claims.insert(message.src, term);
+                } else {
+                    self.claims.remove(&message.src);
+                }
+            }
+        }
+        Ok(self.elect())
+    }
+
+    fn timeout_ticks(&self) -> u64 {
+        let interval = self.config.heartbeat_interval.as_micros().max(1);
+        self.config.failure_timeout.as_micros().div_ceil(interval) as u64
+    }
+
+    fn elect(&mut self) -> Option<Leadership> {
+        let before = self.leadership();
+        let live = self.live();
+        let live_count = live.len();
+        let cluster = self.peers.len() + 1;
+        let quorum = cluster / 2 + 1;
+        let candidate = live
+            .into_iter()
+            .min_by_key(|id| id_order(id))
+            .map(str::to_string);
+        match candidate {
+            _ if live_count < quorum => self.leader = None,
+            Some(candidate) if candidate == self.node_id => {
+                if !self.is_leader() {
+                    self.term += 1;
+                    self.leader = Some(candidate);
+                }
+            }
+            // follow once the candidate has claimed leadership itself.
+            Some(candidate) => match self.claims.get(&candidate) {
+                Some(term) if *term >= self.term => {
+                    self.term = *term;
+                    self.leader = Some(candidate);
+                }
+                _ => self.leader = None,
+            },
+            None => self.leader = None,
+        }
+        let after = self.leadership();
+        (after != before).then_some(after)
+    }
+}
+
+/// Sort key of a node id: the text before its trailing digits, then those
+/// digits as a number, then the id itself.
+fn id_order(id: &str) -> (&str, Option<u64>, &str) {
+    let prefix = id.trim_end_matches(|c: char| c.is_ascii_digit());
+    (prefix, id[prefix.len()..].parse().ok(), id)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{message::Handler, runtime::Node, sim::Simulation};
+
+    struct ElectionNode {
+        election: Mutex<Election<Message<ElectionMessage>>>,
+        changes: Mutex<Vec<Leadership>>,
+    }
+
+    impl Node for ElectionNode {
+        type Payload = ElectionMessage;
+        type Event = Message<ElectionMessage>;
+
+        fn from_init(
+            node_id: String,
+            node_ids: Vec<String>,
+            scheduler: SchedulerRef<Self::Event>,
+            config: &Config,
+        ) -> Self {
+            let election = Election::new(node_id, node_ids, config.election(), scheduler);
+            ElectionNode {
+                election: Mutex::new(election),
+                changes: Mutex::new(Vec::new()),
+            }
+        }
+
+        fn ordering_key(_event: &Self::Event) -> Option<&str> {
+            None
+        }
+    }
+
+    impl Handler<Message<ElectionMessage>> for ElectionNode {
+        fn handle(
+            &self,
+            outbox: &Outbox,
+            message: Message<ElectionMessage>,
+        ) -> message::Result<()> {
+            let change = self.election.lock().unwrap().handle(outbox, message)?;
+            self.changes.lock().unwrap().extend(change);
+            Ok(())
+        }
+    }
+
+    const NODES: [&str; 5] = ["n1", "n2", "n3", "n4", "n5"];
+
+    fn leadership(sim: &Simulation<ElectionNode>, node: &str) -> Leadership {
+        sim.node(node)
+            .unwrap()
+            .election
+            .lock()
+            .unwrap()
+            .leadership()
+    }
+
+    fn leader(sim: &Simulation<ElectionNode>, node: &str) -> Option<String> {
+        leadership(sim, node).leader
+    }
+
+    #[test]
+    fn test_lowest_id_leads_and_everyone_agrees() {
+        let mut sim = Simulation::<ElectionNode>::new(&NODES, 1);
+        sim.run_for(Duration::from_secs(1)).unwrap();
+        let term = leadership(&sim, "n1").term;
+        for node in NODES {
+            let expected = Leadership {
+                leader: Some("n1".to_string()),
+                term,
+            };
+            assert_eq!(expected, leadership(&sim, node), "{}", node);
+            let changes = sim.node(node).unwrap().changes.lock().unwrap();
+            assert_eq!(Some(&expected), changes.last(), "{}", node);
+        }
+    }
+
+    #[test]
+    fn test_isolated_leader_is_replaced_and_takes_over_again_after_heal() {
+        let mut sim = Simulation::<ElectionNode>::new(&NODES, 2);
+        sim.run_for(Duration::from_secs(1)).unwrap();
+        let first_term = leadership(&sim, "n1").term;
+
+        sim.partition(&[&["n1"], &["n2", "n3", "n4", "n5"]]);
+        sim.run_for(Duration::from_secs(2)).unwrap();
+        assert_eq!(None, leader(&sim, "n1"));
+        let second = leadership(&sim, "n2");
+        assert_eq!(Some("n2".to_string()), second.leader);
+        assert!(second.term > first_term);
+        for node in ["n3", "n4", "n5"] {
+            assert_eq!(second, leadership(&sim, node), "{}", node);
+        }
+
+        sim.heal();
+        sim.run_for(Duration::from_secs(1)).unwrap();
+        let third = leadership(&sim, "n1");
+        assert_eq!(Some("n1".to_string()), third.leader);
+        assert!(third.term > second.term);
+        for node in NODES {
+            assert_eq!(third, leadership(&sim, node), "{}", node);
+        }
+    }
+
+    #[test]
+    fn test_ids_compare_by_number() {
+        let nodes = ["n10", "n2", "n11"];
+        let mut sim = Simulation::<ElectionNode>::new(&nodes, 4);
+        sim.run_for(Duration::from_secs(1)).unwrap();
+        for node in nodes {
+            assert_eq!(Some("n2".to_string()), leader(&sim, node), "{}", node);
+        }
+        assert!(id_order("n9") < id_order("n10"));
+        assert!(id_order("n10") < id_order("n10a"));
+    }
+
+    #[test]
+    fn test_minority_side_has_no_leader() {
+        let mut sim = Simulation::<ElectionNode>::new(&NODES, 3);
+        sim.run_for(Duration::from_secs(1)).unwrap();
+        sim.partition(&[&["n1", "n2"], &["n3", "n4", "n5"]]);
+        sim.run_for(Duration::from_secs(2)).unwrap();
+        assert_eq!(None, leader(&sim, "n1"));
+        assert_eq!(None, leader(&sim, "n2"));
+        for node in ["n3", "n4", "n5"] {
+            assert_eq!(Some("n3".to_string()), leader(&sim, node), "{}", node);
+        }
+    }
+}
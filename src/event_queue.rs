@@ -0,0 +1,316 @@
+#![allow(dead_code)]
+//! Bounded queue of the events of a node. Messages read from stdin take
+//! the `capacity`, what happens once it is reached is up to the
+//! `OverflowPolicy`. Events the node sends itself, such as gossip ticks, are
+//! never dropped nor block a timer, instead they are coalesced: a tick equal
+//! to one still waiting is left out, so a slow node does not run a backlog
+//! of gossip rounds once it catches up.
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+};
+
+use anyhow::anyhow;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{admin::Stats, message};
+
+/// What the stdin reader does with a message that finds the queue full.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Wait for room, which stops reading stdin until the node catches up.
+    Block,
+    /// Drop the message that does not fit.
+    DropNewest,
+    /// Drop the oldest queued message to make room.
+    DropOldest,
+}
+
+/// `(src, msg_id)` of the client request behind a queued message.
+pub type Request = (String, usize);
+
+struct Queued<E> {
+    event: E,
+    // serialized form of an internal event, `None` for messages.
+    internal: Option<String>,
+    request: Option<Request>,
+}
+
+struct State<E> {
+    events: VecDeque<Queued<E>>,
+    messages: usize,
+    closed: bool,
+}
+
+struct Shared<E> {
+    state: Mutex<State<E>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: usize,
+    policy: OverflowPolicy,
+    stats: Option<Arc<Stats>>,
+}
+
+/// Cloneable handle, every producer and the dispatcher share one queue.
+pub struct EventQueue<E> {
+    shared: Arc<Shared<E>>,
+}
+
+impl<E> Clone for EventQueue<E> {
+    fn clone(&self) -> Self {
+        EventQueue {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<E: Serialize> EventQueue<E> {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        EventQueue::build(capacity, policy, None)
+    }
+
+    /// Queue reporting its depth, drops and coalesced events to `stats`.
+    pub fn with_stats(capacity: usize, policy: OverflowPolicy, stats: Arc<Stats>) -> Self {
+        EventQueue::build(capacity, policy, Some(stats))
+    }
+
+    fn build(capacity: usize, policy: OverflowPolicy, stats: Option<Arc<Stats>>) -> Self {
+        EventQueue {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    events: VecDeque::new(),
+                    messages: 0,
+                    closed: false,
+                }),
+                not_empty: Condvar::new(),
+                not_full: Condvar::new(),
+                capacity: capacity.max(1),
+                policy,
+                stats,
+            }),
+        }
+    }
+
+    /// Queues a message read from input, subject to the overflow policy.
+    /// Fails once the queue is closed.
+    pub fn push(&self, event: E) -> message::Result<()> {
+        self.push_request(event, None).map(|_| ())
+    }
+
+    /// Like `push` for a message answering `request`. Returns the request of
+    /// the message the policy dropped instead, this one or an older one, so
+    /// the reader can forget it was ever seen.
+    pub fn push_request(
+        &self,
+        event: E,
+        request: Option<Request>,
+    ) -> message::Result<Option<Request>> {
+        let mut dropped = None;
+        let mut state = self.lock();
+        while state.messages >= self.shared.capacity && !state.closed {
+            match self.shared.policy {
+                OverflowPolicy::Block => {
+                    state = self
+                        .shared
+                        .not_full
+                        .wait(state)
+                        .expect("event queue lock poisoned");
+                }
+                OverflowPolicy::DropNewest => {
+                    self.record(|stats| stats.record_dropped());
+                    return Ok(request);
+                }
+                OverflowPolicy::DropOldest => {
+                    let oldest = state
+                        .events
+                        .iter()
+                        .position(|queued| queued.internal.is_none())
+                        .expect("a full queue holds messages");
+                    dropped = state
+                        .events
+                        .remove(oldest)
+                        .and_then(|queued| queued.request);
+                    state.messages -= 1;
+                    self.record(|stats| stats.record_dropped());
+                }
+            }
+        }
+        if state.closed {
+            return Err(anyhow!("event queue closed"));
+        }
+        state.messages += 1;
+        state.events.push_back(Queued {
+            event,
+            internal: None,
+            request,
+        });
+        self.pushed(state);
+        Ok(dropped)
+    }
+
+    /// Queues an event the node sends itself, unless an equal one is still
+    /// waiting. Never blocks, dropped only once the queue is closed.
+    pub fn push_internal(&self, event: E) -> message::Result<()> {
+        let key = serde_json::to_string(&event)?;
+        let mut state = self.lock();
+        if state.closed {
+            return Err(anyhow!("event queue closed"));
+        }
+        if state
+            .events
+            .iter()
+            .any(|queued| queued.internal.as_ref() == Some(&key))
+        {
+            self.record(|stats| stats.record_coalesced());
+            return Ok(());
+        }
+        state.events.push_back(Queued {
+            event,
+            internal: Some(key),
+            request: None,
+        });
+        self.pushed(state);
+        Ok(())
+    }
+
+    /// Next event, waiting for one. `None` once closed and drained.
+    pub fn pop(&self) -> Option<E> {
+        let mut state = self.lock();
+        loop {
+            if let Some(queued) = state.events.pop_front() {
+                if queued.internal.is_none() {
+                    state.messages -= 1;
+                    self.shared.not_full.notify_one();
+                }
+                let depth = state.events.len();
+                self.record(|stats| stats.set_queue_depth(depth));
+                return Some(queued.event);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self
+                .shared
+                .not_empty
+                .wait(state)
+                .expect("event queue lock poisoned");
+        }
+    }
+
+    /// Refuses further events, those queued are still handed out.
+    pub fn close(&self) {
+        self.lock().closed = true;
+        self.shared.not_empty.notify_all();
+        self.shared.not_full.notify_all();
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().events.is_empty()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State<E>> {
+        self.shared.state.lock().expect("event queue lock poisoned")
+    }
+
+    fn pushed(&self, state: MutexGuard<'_, State<E>>) {
+        let depth = state.events.len();
+        self.record(|stats| stats.set_queue_depth(depth));
+        drop(state);
+        self.shared.not_empty.notify_one();
+    }
+
+    fn record(&self, update: impl FnOnce(&Stats)) {
+        if let Some(stats) = &self.shared.stats {
+            update(stats);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+
+    fn drain(queue: &EventQueue<String>) -> Vec<String> {
+        queue.close();
+        std::iter::from_fn(|| queue.pop()).collect()
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn test_equal_pending_ticks_are_coalesced() {
+        let stats = Arc::new(Stats::default());
+        let queue = EventQueue::with_stats(8, OverflowPolicy::Block, stats.clone());
+        for event in ["tick", "m1", "tick", "other", "tick", "m2"] {
+            match event.starts_with('m') {
+                true => queue.push(event.to_string()).unwrap(),
+                false => queue.push_internal(event.to_string()).unwrap(),
+            }
+        }
+        assert_eq!(4, stats.queue_depth());
+        assert_eq!(Some("tick".to_string()), queue.pop());
+        // the pending tick was handed out, the next one queues again.
+        queue.push_internal("tick".to_string()).unwrap();
+        assert_eq!(strings(&["m1", "other", "m2", "tick"]), drain(&queue));
+        assert_eq!(2, stats.coalesced_events());
+        assert!(queue.push("m3".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_drop_policies_keep_capacity() {
+        let fill = |policy| {
+            let stats = Arc::new(Stats::default());
+            let queue = EventQueue::with_stats(2, policy, stats.clone());
+            queue.push_internal("tick".to_string()).unwrap();
+            for message in ["m1", "m2", "m3", "m4"] {
+                queue.push(message.to_string()).unwrap();
+            }
+            assert_eq!(2, stats.dropped_events());
+            drain(&queue)
+        };
+        assert_eq!(
+            strings(&["tick", "m1", "m2"]),
+            fill(OverflowPolicy::DropNewest)
+        );
+        assert_eq!(
+            strings(&["tick", "m3", "m4"]),
+            fill(OverflowPolicy::DropOldest)
+        );
+    }
+
+    #[test]
+    fn test_full_queue_blocks_the_reader_until_popped() {
+        let queue = EventQueue::new(2, OverflowPolicy::Block);
+        let reader = {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                for message in 0..5 {
+                    queue.push(format!("m{}", message)).unwrap();
+                }
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(2, queue.len());
+        // ticks still get in while the reader waits.
+        queue.push_internal("tick".to_string()).unwrap();
+        assert_eq!(3, queue.len());
+
+        let mut popped = Vec::new();
+        while popped.len() < 6 {
+            popped.extend(queue.pop());
+        }
+        reader.join().unwrap();
+        assert_eq!(strings(&["m0", "m1", "tick"]), popped[..3]);
+        assert_eq!(strings(&["m2", "m3", "m4"]), popped[3..]);
+    }
+}
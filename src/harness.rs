@@ -0,0 +1,984 @@
+//! Local stand in for Maelstrom: runs nodes as child processes or in
+//! process, routes their output by `dest` and plays the clients of a workload.
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    process::{Command, Stdio},
+    sync::{
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    broadcase_handler::Broadcast,
+    config::Config,
+    counter::Counter,
+    echo_handler::Echo,
+    event_queue::EventQueue,
+    lin_kv::LinKv,
+    linearizability::{self, CheckResult, History, Register, RegisterInput, RegisterOutput},
+    message::{self, Incoming, Init, Message, Payload},
+    output::Outbox,
+    runtime::{self, Node},
+    scheduler::ThreadScheduler,
+    sim::Rng,
+    txn_list_append::{MicroOp, Txn},
+    unique_id_handler::Generate,
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+enum RouterCommand {
+    FromNode(String),
+    Register(String, Sender<Message<Value>>),
+    Stop,
+}
+
+/// Delivers a line addressed to one node.
+type NodeInput = Box<dyn FnMut(String) -> message::Result<()> + Send>;
+/// Waits for a node to stop once its input has been dropped.
+type NodeExit = Box<dyn FnOnce() -> message::Result<()> + Send>;
+
+/// Running cluster of nodes, either child processes or nodes running on
+/// threads of this process. Lines are routed like Maelstrom does: to the
+/// destination node, or to the registered client.
+pub struct Cluster {
+    node_ids: Vec<String>,
+    exits: Vec<NodeExit>,
+    to_router: Sender<RouterCommand>,
+    router: Option<JoinHandle<message::Result<()>>>,
+}
+
+impl Cluster {
+    /// Starts `nodes` copies of `binary workload` and performs the `init` handshake.
+    pub fn spawn(
+        binary: &Path,
+        workload: &str,
+        nodes: usize,
+        verbose: bool,
+    ) -> message::Result<Cluster> {
+        let node_ids = node_ids(nodes);
+        let (to_router, commands) = channel();
+        let mut inputs = HashMap::<_, NodeInput>::new();
+        let mut exits = Vec::<NodeExit>::new();
+        for node_id in node_ids.iter() {
+            let mut child = Command::new(binary)
+                .arg(workload)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(if verbose {
+                    Stdio::inherit()
+                } else {
+                    Stdio::null()
+                })
+                .spawn()
+                .map_err(|error| anyhow!("could not start {}: {}", binary.display(), error))?;
+            let stdout = child.stdout.take().expect("piped stdout");
+            let mut stdin = child.stdin.take().expect("piped stdin");
+            inputs.insert(
+                node_id.clone(),
+                Box::new(move |line| {
+                    stdin.write_all(line.as_bytes())?;
+                    stdin.write_all(b"\n")?;
+                    Ok(stdin.flush()?)
+                }),
+            );
+            let reader = forward(
+                BufReader::new(stdout).lines().map_while(|line| line.ok()),
+                to_router.clone(),
+            );
+            exits.push(Box::new(move || {
+                let status = child.wait()?;
+                let _ = reader.join();
+                if !status.success() {
+                    bail!("node exited with {}", status);
+                }
+                Ok(())
+            }));
+        }
+        let cluster = Cluster::start(node_ids, inputs, exits, to_router, commands, verbose);
+
+        let mut client = cluster.client("c0")?;
+        for node_id in cluster.node_ids.iter() {
+            let reply = client.request(
+                node_id,
+                Init::Init {
+                    node_id: node_id.clone(),
+                    node_ids: cluster.node_ids.clone(),
+                },
+            )?;
+            if reply.body.data["type"] != "init_ok" {
+                bail!("{} answered init with {:?}", node_id, reply.body.data);
+            }
+        }
+        Ok(cluster)
+    }
+
+    /// Runs `nodes` instances of `N` on threads of this process, each driven
+    /// by `runtime::dispatch` with `workers` threads. Nodes are built from
+    /// their init right away, so there is no handshake.
+    pub fn in_process<N: Node>(
+        nodes: usize,
+        workers: usize,
+        config: &Config,
+        verbose: bool,
+    ) -> Cluster {
+        let node_ids = node_ids(nodes);
+        let (to_router, commands) = channel();
+        let mut inputs = HashMap::<_, NodeInput>::new();
+        let mut exits = Vec::<NodeExit>::new();
+        for node_id in node_ids.iter() {
+            let (outbox, lines) = Outbox::channel();
+            let queue = EventQueue::with_stats(
+                config.queue_capacity,
+                config.overflow_policy,
+                outbox.stats_ref(),
+            );
+            let scheduler = Arc::new(ThreadScheduler::new(queue.clone()));
+            let outbox = runtime::with_reply_cache(outbox, &node_ids, config, scheduler.clone());
+            let node = Arc::new(N::from_init(
+                node_id.clone(),
+                node_ids.clone(),
+                scheduler,
+                config,
+            ));
+            let input_outbox = outbox.clone();
+            let dispatcher = {
+                let queue = queue.clone();
+                thread::spawn(move || runtime::dispatch(node, &outbox, queue, workers, None))
+            };
+            let writer = forward(
+                lines.into_iter().map(|line| line.trim_end().to_string()),
+                to_router.clone(),
+            );
+            let input_queue = queue.clone();
+            inputs.insert(
+                node_id.clone(),
+                Box::new(move |line| {
+                    // like stdin of a real node, lines it cannot parse are dropped.
+                    if let Incoming::Message(message) = message::parse_incoming(&line) {
+                        if !input_outbox.admit(&message.src, message.body.msg_id)? {
+                            return Ok(());
+                        }
+                        let request = message
+                            .body
+                            .msg_id
+                            .map(|msg_id| (message.src.clone(), msg_id));
+                        let dropped = input_queue
+                            .push_request(N::Event::from(message), request)
+                            .map_err(|_| anyhow!("node stopped"))?;
+                        if let Some((src, msg_id)) = dropped {
+                            input_outbox.forget(&src, msg_id);
+                        }
+                    }
+                    Ok(())
+                }),
+            );
+            exits.push(Box::new(move || {
+                let _ = N::shutdown_event().map(|shutdown| queue.push_internal(shutdown));
+                queue.close();
+                let dispatched = dispatcher.join().map_err(|_| anyhow!("node panicked"))?;
+                let _ = writer.join();
+                dispatched
+            }));
+        }
+        Cluster::start(node_ids, inputs, exits, to_router, commands, verbose)
+    }
+
+    fn start(
+        node_ids: Vec<String>,
+        inputs: HashMap<String, NodeInput>,
+        exits: Vec<NodeExit>,
+        to_router: Sender<RouterCommand>,
+        commands: Receiver<RouterCommand>,
+        verbose: bool,
+    ) -> Cluster {
+        let router = thread::spawn(move || route(commands, inputs, verbose));
+        Cluster {
+            node_ids,
+            exits,
+            to_router,
+            router: Some(router),
+        }
+    }
+
+    pub fn node_ids(&self) -> &[String] {
+        &self.node_ids
+    }
+
+    pub fn client(&self, name: &str) -> message::Result<Client> {
+        let (tx, replies) = channel();
+        self.to_router
+            .send(RouterCommand::Register(name.to_string(), tx))
+            .map_err(|_| anyhow!("router stopped"))?;
+        Ok(Client {
+            name: name.to_string(),
+            to_router: self.to_router.clone(),
+            replies,
+            next_msg_id: 0,
+        })
+    }
+
+    /// Closes the input of every node and waits for them to exit.
+    pub fn shutdown(mut self) -> message::Result<()> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> message::Result<()> {
+        let Some(router) = self.router.take() else {
+            return Ok(());
+        };
+        let _ = self.to_router.send(RouterCommand::Stop);
+        let routed = router.join().map_err(|_| anyhow!("router panicked"))?;
+        let mut result = routed;
+        for exit in self.exits.drain(..) {
+            let exited = exit();
+            if result.is_ok() {
+                result = exited;
+            }
+        }
+        result
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        if let Err(error) = self.stop() {
+            eprintln!("Cluster shutdown failed: {}", error);
+        }
+    }
+}
+
+fn node_ids(nodes: usize) -> Vec<String> {
+    (1..=nodes).map(|id| format!("n{}", id)).collect()
+}
+
+/// Hands every line a node writes to the router. Once the router stopped the
+/// lines are dropped, the node keeps writing while it shuts down.
+fn forward(
+    lines: impl Iterator<Item = String> + Send + 'static,
+    to_router: Sender<RouterCommand>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        for line in lines {
+            let _ = to_router.send(RouterCommand::FromNode(line));
+        }
+    })
+}
+
+fn route(
+    commands: Receiver<RouterCommand>,
+    mut inputs: HashMap<String, NodeInput>,
+    verbose: bool,
+) -> message::Result<()> {
+    let mut clients = HashMap::new();
+    for command in commands {
+        match command {
+            RouterCommand::FromNode(line) => {
+                let Ok(message) = serde_json::from_str::<Message<Value>>(&line) else {
+                    eprintln!("Node wrote malformed line {:?}", line);
+                    continue;
+                };
+                if verbose {
+                    eprintln!("{}", line);
+                }
+                if let Some(input) = inputs.get_mut(&message.dst) {
+                    input(line)?;
+                } else if let Some(client) = clients.get(&message.dst) {
+                    let _ = Sender::send(client, message);
+                }
+            }
+            RouterCommand::Register(name, tx) => {
+                clients.insert(name, tx);
+            }
+            RouterCommand::Stop => break,
+        }
+    }
+    // dropping the inputs closes stdin of child processes, they shut down then.
+    drop(inputs);
+    Ok(())
+}
+
+/// A Maelstrom client. `request` waits for each reply, `send` and `recv`
+/// let callers keep several requests in flight.
+pub struct Client {
+    name: String,
+    to_router: Sender<RouterCommand>,
+    replies: Receiver<Message<Value>>,
+    next_msg_id: usize,
+}
+
+impl Client {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn request<T: Serialize>(
+        &mut self,
+        dest: &str,
+        data: T,
+    ) -> message::Result<Message<Value>> {
+        let msg_id = self.send(dest, data)?;
+        let deadline = Instant::now() + REQUEST_TIMEOUT;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.recv(timeout)? {
+                Some(reply) if reply.body.data["in_reply_to"] == msg_id => return Ok(reply),
+                // late reply of an earlier request that already timed out.
+                Some(_) => continue,
+                None => bail!(
+                    "{} timed out waiting for {} msg {}",
+                    self.name,
+                    dest,
+                    msg_id
+                ),
+            }
+        }
+    }
+
+    /// Sends a request without waiting for the reply, returns its msg_id.
+    pub fn send<T: Serialize>(&mut self, dest: &str, data: T) -> message::Result<usize> {
+        self.next_msg_id += 1;
+        let line = serde_json::to_string(&Message::new(
+            self.name.clone(),
+            dest.to_string(),
+            Payload::new(data, Some(self.next_msg_id)),
+        ))?;
+        // requests enter the router like node output, so they get the same routing.
+        self.to_router
+            .send(RouterCommand::FromNode(line))
+            .map_err(|_| anyhow!("router stopped"))?;
+        Ok(self.next_msg_id)
+    }
+
+    /// Next message for this client, `None` if nothing arrived within `timeout`.
+    pub fn recv(&self, timeout: Duration) -> message::Result<Option<Message<Value>>> {
+        match self.replies.recv_timeout(timeout) {
+            Ok(reply) => Ok(Some(reply)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => bail!("router stopped"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WorkloadOptions {
+    pub nodes: usize,
+    pub clients: usize,
+    pub ops: usize,
+    pub seed: u64,
+    /// How long eventually consistent workloads get to converge.
+    pub settle: Duration,
+    pub verbose: bool,
+}
+
+impl Default for WorkloadOptions {
+    fn default() -> Self {
+        WorkloadOptions {
+            nodes: 3,
+            clients: 2,
+            ops: 50,
+            seed: 0,
+            settle: Duration::from_secs(5),
+            verbose: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WorkloadReport {
+    pub workload: String,
+    pub ops: usize,
+    pub failures: Vec<String>,
+    pub elapsed: Duration,
+}
+
+impl WorkloadReport {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+impl fmt::Display for WorkloadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{}: {} ({} ops in {:.2?})",
+            self.workload,
+            if self.passed() { "PASS" } else { "FAIL" },
+            self.ops,
+            self.elapsed
+        )?;
+        for failure in self.failures.iter() {
+            writeln!(f, "  {}", failure)?;
+        }
+        Ok(())
+    }
+}
+
+pub const WORKLOADS: [&str; 6] = [
+    "echo",
+    "unique-ids",
+    "broadcast",
+    "g-counter",
+    "lin-kv",
+    "txn-list-append",
+];
+
+/// Runs one workload against a fresh cluster of `binary` nodes.
+pub fn run_workload(
+    binary: &Path,
+    workload: &str,
+    options: &WorkloadOptions,
+) -> message::Result<WorkloadReport> {
+    if !WORKLOADS.contains(&workload) {
+        bail!("unknown workload {}", workload);
+    }
+    let start = Instant::now();
+    let cluster = Cluster::spawn(binary, workload, options.nodes, options.verbose)?;
+    let mut clients = (1..=options.clients)
+        .map(|id| cluster.client(&format!("c{}", id)))
+        .collect::<message::Result<Vec<_>>>()?;
+    let mut report = WorkloadReport {
+        workload: workload.to_string(),
+        ..WorkloadReport::default()
+    };
+    let mut rng = Rng::new(options.seed);
+    match workload {
+        "echo" => echo(&cluster, &mut clients, &mut rng, options, &mut report),
+        "unique-ids" => unique_ids(&cluster, &mut clients, &mut rng, options, &mut report),
+        "broadcast" => broadcast(&cluster, &mut clients, &mut rng, options, &mut report),
+        "g-counter" => g_counter(&cluster, &mut clients, &mut rng, options, &mut report),
+        "lin-kv" => lin_kv(&cluster, &mut clients, &mut rng, options, &mut report),
+        "txn-list-append" => {
+            txn_list_append(&cluster, &mut clients, &mut rng, options, &mut report)
+        }
+        _ => unreachable!("workload checked above"),
+    }
+    cluster.shutdown()?;
+    report.elapsed = start.elapsed();
+    Ok(report)
+}
+
+/// Client `op` runs as and node it picks, spread round robin and by rng.
+fn pick<'a>(
+    cluster: &'a Cluster,
+    clients: &'a mut [Client],
+    rng: &mut Rng,
+    op: usize,
+) -> (&'a mut Client, &'a str) {
+    let node = &cluster.node_ids()[rng.below(cluster.node_ids().len() as u64) as usize];
+    let client_count = clients.len();
+    (&mut clients[op % client_count], node)
+}
+
+fn echo(
+    cluster: &Cluster,
+    clients: &mut [Client],
+    rng: &mut Rng,
+    options: &WorkloadOptions,
+    report: &mut WorkloadReport,
+) {
+    for op in 0..options.ops {
+        let (client, node) = pick(cluster, clients, rng, op);
+        let echo = format!("Please echo {}", rng.next_u64());
+        report.ops += 1;
+        match client.request(node, Echo::Echo { echo: echo.clone() }) {
+            Ok(reply) if reply.body.data["echo"] == echo.as_str() => {}
+            Ok(reply) => report
+                .failures
+                .push(format!("{} echoed {:?}", node, reply.body.data)),
+            Err(error) => report.failures.push(error.to_string()),
+        }
+    }
+}
+
+fn unique_ids(
+    cluster: &Cluster,
+    clients: &mut [Client],
+    rng: &mut Rng,
+    options: &WorkloadOptions,
+    report: &mut WorkloadReport,
+) {
+    let mut seen = HashSet::new();
+    for op in 0..options.ops {
+        let (client, node) = pick(cluster, clients, rng, op);
+        report.ops += 1;
+        match client.request(node, Generate::Generate) {
+            Ok(reply) => {
+                let id = reply.body.data["id"].to_string();
+                if !seen.insert(id.clone()) {
+                    report
+                        .failures
+                        .push(format!("{} handed out {} twice", node, id));
+                }
+            }
+            Err(error) => report.failures.push(error.to_string()),
+        }
+    }
+}
+
+fn broadcast(
+    cluster: &Cluster,
+    clients: &mut [Client],
+    rng: &mut Rng,
+    options: &WorkloadOptions,
+    report: &mut WorkloadReport,
+) {
+    let node_ids = cluster.node_ids();
+    let topology = line_topology(node_ids);
+    for node in node_ids {
+        let topology = Broadcast::Topology {
+            topology: topology.clone(),
+        };
+        if let Err(error) = clients[0].request(node, topology) {
+            report.failures.push(error.to_string());
+        }
+    }
+
+    let mut acknowledged = BTreeSet::new();
+    for op in 0..options.ops {
+        let (client, node) = pick(cluster, clients, rng, op);
+        report.ops += 1;
+        match client.request(node, Broadcast::Broadcast { message: op }) {
+            Ok(_) => {
+                acknowledged.insert(op);
+            }
+            Err(error) => report.failures.push(error.to_string()),
+        }
+    }
+
+    let check = |clients: &mut [Client]| -> message::Result<Vec<String>> {
+        let mut missing = Vec::new();
+        for node in node_ids {
+            let reply = clients[0].request(node, Broadcast::Read)?;
+            let seen =
+                serde_json::from_value::<BTreeSet<usize>>(reply.body.data["messages"].clone())?;
+            let lost = acknowledged.difference(&seen).count();
+            if lost > 0 {
+                missing.push(format!("{} is missing {} acknowledged values", node, lost));
+            }
+        }
+        Ok(missing)
+    };
+    settle(clients, options.settle, report, check);
+}
+
+/// Every node neighbours the ones next to it in `node_ids`, so values have
+/// to travel through the whole cluster.
+pub fn line_topology(node_ids: &[String]) -> HashMap<String, Vec<String>> {
+    node_ids
+        .iter()
+        .enumerate()
+        .map(|(index, node)| {
+            let neighbours = [index.checked_sub(1), Some(index + 1)]
+                .into_iter()
+                .flatten()
+                .filter_map(|neighbour| node_ids.get(neighbour).cloned())
+                .collect();
+            (node.clone(), neighbours)
+        })
+        .collect()
+}
+
+fn g_counter(
+    cluster: &Cluster,
+    clients: &mut [Client],
+    rng: &mut Rng,
+    options: &WorkloadOptions,
+    report: &mut WorkloadReport,
+) {
+    let mut total = 0;
+    for op in 0..options.ops {
+        let (client, node) = pick(cluster, clients, rng, op);
+        let delta = rng.below(10) as usize;
+        report.ops += 1;
+        match client.request(node, Counter::Add { delta }) {
+            Ok(_) => total += delta,
+            Err(error) => report.failures.push(error.to_string()),
+        }
+    }
+
+    let node_ids = cluster.node_ids();
+    let check = |clients: &mut [Client]| -> message::Result<Vec<String>> {
+        let mut wrong = Vec::new();
+        for node in node_ids {
+            let reply = clients[0].request(node, Counter::Read)?;
+            if reply.body.data["value"] != total {
+                wrong.push(format!(
+                    "{} read {} instead of {}",
+                    node, reply.body.data["value"], total
+                ));
+            }
+        }
+        Ok(wrong)
+    };
+    settle(clients, options.settle, report, check);
+}
+
+/// Polls `check` until it finds nothing wrong or `within` runs out, the
+/// problems of the last attempt are the failures.
+fn settle(
+    clients: &mut [Client],
+    within: Duration,
+    report: &mut WorkloadReport,
+    check: impl Fn(&mut [Client]) -> message::Result<Vec<String>>,
+) {
+    let deadline = Instant::now() + within;
+    loop {
+        let problems = check(clients).unwrap_or_else(|error| vec![error.to_string()]);
+        if problems.is_empty() {
+            return;
+        }
+        if Instant::now() >= deadline {
+            report.failures.extend(problems);
+            return;
+        }
+        thread::sleep(Duration::from_millis(250));
+    }
+}
+
+/// Register operation the history records for a lin-kv reply, `None` when
+/// the request failed without telling whether it took effect.
+fn register_output(input: &RegisterInput<u64>, reply: &Value) -> Option<RegisterOutput> {
+    let code = reply["code"].as_u64();
+    match (input, reply["type"].as_str()?) {
+        (RegisterInput::Read(_), "read_ok") => {
+            Some(RegisterOutput::ReadOk(reply["value"].as_i64()))
+        }
+        (RegisterInput::Read(_), "error") if code == Some(20) => Some(RegisterOutput::ReadOk(None)),
+        (RegisterInput::Write(..), "write_ok") => Some(RegisterOutput::WriteOk),
+        (RegisterInput::Cas(..), "cas_ok") => Some(RegisterOutput::CasOk),
+        (RegisterInput::Cas(..), "error") if matches!(code, Some(20 | 22)) => {
+            Some(RegisterOutput::CasFailed)
+        }
+        _ => None,
+    }
+}
+
+/// Until a leader is elected every request fails with temporarily-unavailable,
+/// `probe` is sent until it gets another answer.
+fn wait_for_leader<T: Serialize + Clone>(
+    cluster: &Cluster,
+    clients: &mut [Client],
+    probe: T,
+    within: Duration,
+    report: &mut WorkloadReport,
+) -> bool {
+    let node = &cluster.node_ids()[0];
+    let ready = |clients: &mut [Client]| -> message::Result<Vec<String>> {
+        let reply = clients[0].request(node, probe.clone())?;
+        Ok(match reply.body.data["code"].as_u64() {
+            Some(11) => vec!["no leader elected".to_string()],
+            _ => Vec::new(),
+        })
+    };
+    settle(clients, within, report, ready);
+    report.passed()
+}
+
+/// Keeps one request per client in flight until `ops` were sent to random
+/// nodes. `next` builds a request along with the context `done` needs to
+/// judge its reply, `done` gets `None` once the request timed out.
+fn drive<S, C, T: Serialize>(
+    cluster: &Cluster,
+    clients: &mut [Client],
+    rng: &mut Rng,
+    ops: usize,
+    state: &mut S,
+    mut next: impl FnMut(&mut S, &str, &mut Rng) -> message::Result<(C, T)>,
+    mut done: impl FnMut(&mut S, &str, C, Option<&Value>) -> message::Result<()>,
+) -> message::Result<()> {
+    let node_ids = cluster.node_ids();
+    let mut sent_ops = 0;
+    let mut in_flight = clients.iter().map(|_| None).collect::<Vec<_>>();
+    while sent_ops < ops || in_flight.iter().any(Option::is_some) {
+        for (client, in_flight) in clients.iter_mut().zip(in_flight.iter_mut()) {
+            let Some((msg_id, context, sent)) = in_flight.take() else {
+                if sent_ops < ops {
+                    let node = &node_ids[rng.below(node_ids.len() as u64) as usize];
+                    let (context, request) = next(state, client.name(), rng)?;
+                    *in_flight = Some((client.send(node, request)?, context, Instant::now()));
+                    sent_ops += 1;
+                }
+                continue;
+            };
+            match client.recv(Duration::from_millis(1))? {
+                Some(reply) if reply.body.data["in_reply_to"] == msg_id => {
+                    done(state, client.name(), context, Some(&reply.body.data))?
+                }
+                _ if sent.elapsed() >= REQUEST_TIMEOUT => {
+                    done(state, client.name(), context, None)?
+                }
+                _ => *in_flight = Some((msg_id, context, sent)),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Every client keeps one random read, write or cas on a few keys in flight,
+/// the recorded history has to be linearizable.
+fn lin_kv(
+    cluster: &Cluster,
+    clients: &mut [Client],
+    rng: &mut Rng,
+    options: &WorkloadOptions,
+    report: &mut WorkloadReport,
+) {
+    let probe = LinKv::Read { key: 0.into() };
+    if !wait_for_leader(cluster, clients, probe, options.settle, report) {
+        return;
+    }
+    let next = |history: &mut History<_, _>, client: &str, rng: &mut Rng| {
+        let key = rng.below(3);
+        let value = |rng: &mut Rng| rng.below(5) as i64;
+        let (input, request) = match rng.below(3) {
+            0 => (RegisterInput::Read(key), LinKv::Read { key: key.into() }),
+            1 => {
+                let value = value(rng);
+                let request = LinKv::Write {
+                    key: key.into(),
+                    value: value.into(),
+                };
+                (RegisterInput::Write(key, value), request)
+            }
+            _ => {
+                let (from, to) = (value(rng), value(rng));
+                let request = LinKv::Cas {
+                    key: key.into(),
+                    from: from.into(),
+                    to: to.into(),
+                    create_if_not_exists: false,
+                };
+                (RegisterInput::Cas(key, from, to), request)
+            }
+        };
+        history.invoke(client, input.clone())?;
+        Ok((input, request))
+    };
+    let mut completed = 0;
+    let done = |history: &mut History<_, _>, client: &str, input, reply: Option<&Value>| match reply
+        .and_then(|reply| register_output(&input, reply))
+    {
+        Some(output) => {
+            completed += 1;
+            history.complete(client, output)
+        }
+        None => history.fail(client),
+    };
+    let mut history = History::default();
+    if let Err(error) = drive(cluster, clients, rng, options.ops, &mut history, next, done) {
+        report.failures.push(error.to_string());
+        return;
+    }
+    report.ops += options.ops;
+    if completed == 0 {
+        report.failures.push("no operation completed".to_string());
+    }
+    let result =
+        linearizability::check_partitioned(&Register::new(None), history.operations(), |input| {
+            *input.key()
+        });
+    if let CheckResult::NotLinearizable { counterexample } = result {
+        report
+            .failures
+            .push("history is not linearizable:".to_string());
+        for operation in counterexample {
+            report.failures.push(format!(
+                "  {} {:?} -> {:?}",
+                operation.process, operation.input, operation.output
+            ));
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct TxnOutcomes {
+    next_value: u64,
+    committed: Vec<Vec<MicroOp>>,
+    // appends of transactions that definitely did not run.
+    aborted: Vec<(Value, Value)>,
+}
+
+/// Random transactions of reads and appends of unique values. With every
+/// transaction applied in one serial order, each read has to be a prefix
+/// of the final list, committed appends show up once and aborted ones never.
+fn txn_list_append(
+    cluster: &Cluster,
+    clients: &mut [Client],
+    rng: &mut Rng,
+    options: &WorkloadOptions,
+    report: &mut WorkloadReport,
+) {
+    let keys = (0..3u64).map(Value::from).collect::<Vec<_>>();
+    let read_all = || Txn::Txn {
+        txn: keys
+            .iter()
+            .map(|key| MicroOp::Read {
+                key: key.clone(),
+                value: None,
+            })
+            .collect(),
+    };
+    if !wait_for_leader(cluster, clients, read_all(), options.settle, report) {
+        return;
+    }
+    let next = |outcomes: &mut TxnOutcomes, _: &str, rng: &mut Rng| {
+        let txn = (0..=rng.below(3))
+            .map(|_| {
+                let key = keys[rng.below(keys.len() as u64) as usize].clone();
+                match rng.below(2) {
+                    0 => MicroOp::Read { key, value: None },
+                    _ => {
+                        outcomes.next_value += 1;
+                        let value = outcomes.next_value.into();
+                        MicroOp::Append { key, value }
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+        Ok((txn.clone(), Txn::Txn { txn }))
+    };
+    let done = |outcomes: &mut TxnOutcomes, _: &str, txn: Vec<MicroOp>, reply: Option<&Value>| {
+        let Some(reply) = reply else {
+            return Ok(());
+        };
+        match (reply["type"].as_str(), reply["code"].as_u64()) {
+            (Some("txn_ok"), _) => outcomes
+                .committed
+                .push(serde_json::from_value(reply["txn"].clone())?),
+            (Some("error"), Some(11 | 30)) => {
+                outcomes
+                    .aborted
+                    .extend(txn.into_iter().filter_map(|op| match op {
+                        MicroOp::Append { key, value } => Some((key, value)),
+                        MicroOp::Read { .. } => None,
+                    }))
+            }
+            _ => {}
+        }
+        Ok(())
+    };
+    let mut outcomes = TxnOutcomes::default();
+    let driven = drive(
+        cluster,
+        clients,
+        rng,
+        options.ops,
+        &mut outcomes,
+        next,
+        done,
+    );
+    let last = driven.and_then(|_| clients[0].request(&cluster.node_ids()[0], read_all()));
+    let last = match last {
+        Ok(reply) => serde_json::from_value::<Vec<MicroOp>>(reply.body.data["txn"].clone()),
+        Err(error) => {
+            report.failures.push(error.to_string());
+            return;
+        }
+    };
+    let Ok(last) = last else {
+        report.failures.push("final read failed".to_string());
+        return;
+    };
+    report.ops += options.ops;
+    if outcomes.committed.is_empty() {
+        report.failures.push("no transaction committed".to_string());
+    }
+
+    let final_lists = last
+        .into_iter()
+        .filter_map(|op| match op {
+            MicroOp::Read {
+                key,
+                value: Some(list),
+            } => Some((key.to_string(), list)),
+            _ => None,
+        })
+        .collect::<HashMap<_, _>>();
+    let list = |key: &Value| {
+        final_lists
+            .get(&key.to_string())
+            .cloned()
+            .unwrap_or_default()
+    };
+    for op in outcomes.committed.iter().flatten() {
+        match op {
+            MicroOp::Read {
+                key,
+                value: Some(read),
+            } => {
+                if !list(key).starts_with(read) {
+                    report.failures.push(format!(
+                        "read of {} {:?} is not a prefix of {:?}",
+                        key,
+                        read,
+                        list(key)
+                    ));
+                }
+            }
+            MicroOp::Append { key, value } => {
+                let count = list(key).iter().filter(|seen| *seen == value).count();
+                if count != 1 {
+                    report.failures.push(format!(
+                        "committed append {} to {} is in the list {} times",
+                        value, key, count
+                    ));
+                }
+            }
+            MicroOp::Read { key, value: None } => {
+                report
+                    .failures
+                    .push(format!("read of {} returned nothing", key));
+            }
+        }
+    }
+    for (key, value) in outcomes.aborted.iter() {
+        if list(key).contains(value) {
+            report
+                .failures
+                .push(format!("aborted append {} to {} took effect", value, key));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_lists_failures() {
+        let report = WorkloadReport {
+            workload: "g-counter".to_string(),
+            ops: 2,
+            failures: vec!["n1 read 1 instead of 3".to_string()],
+            elapsed: Duration::from_millis(5),
+        };
+        assert!(!report.passed());
+        assert_eq!(
+            "g-counter: FAIL (2 ops in 5.00ms)\n  n1 read 1 instead of 3\n",
+            report.to_string()
+        );
+    }
+
+    #[test]
+    fn test_unknown_workload_is_rejected_before_spawning() {
+        let error = run_workload(
+            Path::new("/nonexistent"),
+            "kafka",
+            &WorkloadOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!("unknown workload kafka", error.to_string());
+    }
+}
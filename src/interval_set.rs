@@ -0,0 +1,271 @@
+#![allow(dead_code)]
+//! Set of integers kept as sorted, disjoint inclusive ranges. Broadcast
+//! values are mostly dense, so `[[0,999]]` replaces a thousand numbers in
+//! gossip between nodes.
+use std::fmt;
+
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    ser::SerializeSeq,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+/// Serialized as `[[start, end], ...]`, both ends included.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct IntervalSet {
+    // sorted, neither overlapping nor adjacent.
+    ranges: Vec<(usize, usize)>,
+}
+
+impl IntervalSet {
+    pub fn new() -> Self {
+        IntervalSet::default()
+    }
+
+    pub fn ranges(&self) -> &[(usize, usize)] {
+        &self.ranges
+    }
+
+    /// Number of values, not of ranges.
+    pub fn len(&self) -> usize {
+        self.ranges.iter().map(|(start, end)| end - start + 1).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn contains(&self, value: usize) -> bool {
+        let index = self.ranges.partition_point(|(_, end)| *end < value);
+        self.ranges
+            .get(index)
+            .is_some_and(|(start, _)| *start <= value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.ranges.iter().flat_map(|(start, end)| *start..=*end)
+    }
+
+    /// Returns whether `value` was new.
+    pub fn insert(&mut self, value: usize) -> bool {
+        self.insert_range(value, value)
+    }
+
+    /// Adds `start..=end`, returns whether any of it was new.
+    pub fn insert_range(&mut self, start: usize, end: usize) -> bool {
+        assert!(start <= end, "empty range {}..={}", start, end);
+        // ranges from `first` to `last` overlap or touch the new one.
+        let first = self
+            .ranges
+            .partition_point(|(_, existing_end)| existing_end.saturating_add(1) < start);
+        let last = self
+            .ranges
+            .partition_point(|(existing_start, _)| *existing_start <= end.saturating_add(1));
+        if first == last {
+            self.ranges.insert(first, (start, end));
+            return true;
+        }
+        let merged = (
+            start.min(self.ranges[first].0),
+            end.max(self.ranges[last - 1].1),
+        );
+        let covered = last - first == 1 && self.ranges[first] == merged;
+        self.ranges.splice(first..last, [merged]);
+        !covered
+    }
+
+    /// Values of `self` within `start..=end`.
+    pub fn clamped(&self, start: usize, end: usize) -> IntervalSet {
+        let first = self
+            .ranges
+            .partition_point(|(_, existing_end)| *existing_end < start);
+        let ranges = self.ranges[first..]
+            .iter()
+            .take_while(|(existing_start, _)| *existing_start <= end)
+            .map(|(existing_start, existing_end)| {
+                ((*existing_start).max(start), (*existing_end).min(end))
+            })
+            .collect();
+        IntervalSet { ranges }
+    }
+
+    /// Values of `self` missing from `other`.
+    pub fn difference(&self, other: &IntervalSet) -> IntervalSet {
+        let mut ranges = Vec::new();
+        for &(start, end) in self.ranges.iter() {
+            let mut next = Some(start);
+            for (removed_start, removed_end) in other.clamped(start, end).ranges {
+                let Some(from) = next else {
+                    break;
+                };
+                if removed_start > from {
+                    ranges.push((from, removed_start - 1));
+                }
+                next = removed_end.checked_add(1);
+            }
+            match next {
+                Some(from) if from <= end => ranges.push((from, end)),
+                _ => {}
+            }
+        }
+        IntervalSet { ranges }
+    }
+
+    /// Adds every value of `other` in one pass over both sets.
+    pub fn union(&mut self, other: &IntervalSet) {
+        if other.is_empty() {
+            return;
+        }
+        let mut merged: Vec<(usize, usize)> =
+            Vec::with_capacity(self.ranges.len() + other.ranges.len());
+        let (mut left, mut right) = (
+            self.ranges.iter().peekable(),
+            other.ranges.iter().peekable(),
+        );
+        loop {
+            let next = match (left.peek(), right.peek()) {
+                (Some(l), Some(r)) if l.0 <= r.0 => left.next(),
+                (Some(_), Some(_)) => right.next(),
+                (Some(_), None) => left.next(),
+                (None, _) => right.next(),
+            };
+            let Some(&(start, end)) = next else {
+                break;
+            };
+            match merged.last_mut() {
+                Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        self.ranges = merged;
+    }
+}
+
+impl FromIterator<usize> for IntervalSet {
+    fn from_iter<I: IntoIterator<Item = usize>>(values: I) -> Self {
+        let mut set = IntervalSet::new();
+        for value in values {
+            set.insert(value);
+        }
+        set
+    }
+}
+
+impl Extend<usize> for IntervalSet {
+    fn extend<I: IntoIterator<Item = usize>>(&mut self, values: I) {
+        for value in values {
+            self.insert(value);
+        }
+    }
+}
+
+impl Serialize for IntervalSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.ranges.len()))?;
+        for (start, end) in self.ranges.iter() {
+            seq.serialize_element(&[start, end])?;
+        }
+        seq.end()
+    }
+}
+
+impl JsonSchema for IntervalSet {
+    fn schema_name() -> String {
+        "IntervalSet".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        <Vec<[usize; 2]>>::json_schema(gen)
+    }
+}
+
+impl<'de> Deserialize<'de> for IntervalSet {
+    /// Accepts ranges in any order, overlapping ones are merged.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RangesVisitor;
+
+        impl<'de> Visitor<'de> for RangesVisitor {
+            type Value = IntervalSet;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a list of [start, end] pairs")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<IntervalSet, A::Error> {
+                let mut set = IntervalSet::new();
+                while let Some([start, end]) = seq.next_element::<[usize; 2]>()? {
+                    if start > end {
+                        return Err(de::Error::custom(format!(
+                            "range [{}, {}] ends before it starts",
+                            start, end
+                        )));
+                    }
+                    set.insert_range(start, end);
+                }
+                Ok(set)
+            }
+        }
+
+        deserializer.deserialize_seq(RangesVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inserts_merge_touching_ranges() {
+        let mut set = [5, 1, 3, 2, 9].into_iter().collect::<IntervalSet>();
+        assert_eq!(&[(1, 3), (5, 5), (9, 9)], set.ranges());
+        assert!(set.insert(4));
+        assert!(!set.insert(4));
+        assert_eq!(&[(1, 5), (9, 9)], set.ranges());
+        assert!(set.insert_range(6, 8));
+        assert_eq!(&[(1, 9)], set.ranges());
+        assert!(!set.insert_range(2, 7));
+        assert_eq!(9, set.len());
+        assert!(set.contains(1) && set.contains(9) && !set.contains(0) && !set.contains(10));
+    }
+
+    #[test]
+    fn test_union_merges_in_one_pass() {
+        let mut set = [0, 1, 2, 10, 20].into_iter().collect::<IntervalSet>();
+        set.union(&[3, 4, 11, 12, 30].into_iter().collect());
+        assert_eq!(&[(0, 4), (10, 12), (20, 20), (30, 30)], set.ranges());
+        set.union(&IntervalSet::new());
+        assert_eq!(4, set.ranges().len());
+    }
+
+    #[test]
+    fn test_clamp_and_difference() {
+        let set = [(0, 9), (20, 29), (40, 49)]
+            .into_iter()
+            .flat_map(|(start, end)| start..=end)
+            .collect::<IntervalSet>();
+        assert_eq!(&[(5, 9), (20, 22)], set.clamped(5, 22).ranges());
+        assert!(set.clamped(10, 19).is_empty());
+        let removed = [2, 3, 9, 25, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49]
+            .into_iter()
+            .collect::<IntervalSet>();
+        assert_eq!(
+            &[(0, 1), (4, 8), (20, 24), (26, 29)],
+            set.difference(&removed).ranges()
+        );
+        assert!(set.difference(&set).is_empty());
+    }
+
+    #[test]
+    fn test_wire_format_is_pairs() {
+        let set = (0..1000).chain([1500]).collect::<IntervalSet>();
+        assert_eq!(
+            "[[0,999],[1500,1500]]",
+            serde_json::to_string(&set).unwrap()
+        );
+        let parsed = serde_json::from_str::<IntervalSet>("[[8,9],[0,3],[2,5]]").unwrap();
+        assert_eq!(&[(0, 5), (8, 9)], parsed.ranges());
+        assert!(serde_json::from_str::<IntervalSet>("[[3,2]]").is_err());
+        assert!(serde_json::from_str::<IntervalSet>("[[1,2,3]]").is_err());
+    }
+}
@@ -0,0 +1,33 @@
+#![allow(unused_imports)]
+pub mod admin;
+pub mod bench;
+pub mod broadcase_handler;
+pub mod clock;
+pub mod config;
+pub mod counter;
+pub mod dedup;
+pub mod digest;
+pub mod echo_handler;
+pub mod election;
+pub mod event_queue;
+pub mod harness;
+pub mod interval_set;
+pub mod lin_kv;
+pub mod linearizability;
+pub mod message;
+pub mod output;
+pub mod periodic_thread;
+pub mod proxy;
+pub mod raft;
+pub mod raft_service;
+pub mod reliable;
+pub mod ring;
+pub mod runtime;
+pub mod scheduler;
+pub mod schema;
+pub mod sim;
+pub mod trace;
+pub mod txn_list_append;
+pub mod unique_id_handler;
+#[cfg(test)]
+mod wire_properties;
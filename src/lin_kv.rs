@@ -0,0 +1,336 @@
+#![allow(dead_code)]
+//! Maelstrom `lin-kv`: a key/value store replicated with Raft. Every
+//! operation, reads included, goes through the log and is answered by the
+//! leader once committed. Other nodes proxy client requests to the leader.
+use std::collections::BTreeMap;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    message::{BodyTypes, ErrorCode},
+    proxy::Reply,
+    raft::StateMachine,
+    raft_service::{RaftService, Replicated, ServicePayload},
+};
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LinKv {
+    Read {
+        key: Value,
+    },
+    ReadOk {
+        value: Value,
+        in_reply_to: usize,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    WriteOk {
+        in_reply_to: usize,
+    },
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+    CasOk {
+        in_reply_to: usize,
+    },
+    // only seen by proxies relaying the answer of the leader.
+    Error {
+        in_reply_to: usize,
+        code: ErrorCode,
+        text: String,
+    },
+}
+
+impl Reply for LinKv {
+    fn in_reply_to(&self) -> Option<usize> {
+        match self {
+            LinKv::ReadOk { in_reply_to, .. }
+            | LinKv::WriteOk { in_reply_to }
+            | LinKv::CasOk { in_reply_to }
+            | LinKv::Error { in_reply_to, .. } => Some(*in_reply_to),
+            LinKv::Read { .. } | LinKv::Write { .. } | LinKv::Cas { .. } => None,
+        }
+    }
+
+    fn with_in_reply_to(mut self, to: usize) -> Self {
+        match &mut self {
+            LinKv::ReadOk { in_reply_to, .. }
+            | LinKv::WriteOk { in_reply_to }
+            | LinKv::CasOk { in_reply_to }
+            | LinKv::Error { in_reply_to, .. } => *in_reply_to = to,
+            LinKv::Read { .. } | LinKv::Write { .. } | LinKv::Cas { .. } => {}
+        }
+        self
+    }
+}
+
+pub type LinKvPayload = ServicePayload<LinKv, LinKv>;
+
+impl BodyTypes for LinKvPayload {
+    const TYPES: &'static [&'static str] = &[
+        "read",
+        "read_ok",
+        "write",
+        "write_ok",
+        "cas",
+        "cas_ok",
+        "error",
+        "request_vote",
+        "request_vote_ok",
+        "append_entries",
+        "append_entries_ok",
+        "election_timeout",
+        "heartbeat",
+        "forward_timeout",
+    ];
+}
+
+/// Answer to a committed request, `in_reply_to` is filled in by the node.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KvReply {
+    ReadOk(Value),
+    WriteOk,
+    CasOk,
+    Error(ErrorCode, String),
+}
+
+impl KvReply {
+    fn into_body(self, in_reply_to: usize) -> LinKv {
+        match self {
+            KvReply::ReadOk(value) => LinKv::ReadOk { value, in_reply_to },
+            KvReply::WriteOk => LinKv::WriteOk { in_reply_to },
+            KvReply::CasOk => LinKv::CasOk { in_reply_to },
+            KvReply::Error(code, text) => LinKv::Error {
+                in_reply_to,
+                code,
+                text,
+            },
+        }
+    }
+}
+
+/// Keys are arbitrary JSON, they are stored under their serialized form.
+#[derive(Debug, Default)]
+pub struct KvStore {
+    data: BTreeMap<String, Value>,
+}
+
+impl KvStore {
+    pub fn get(&self, key: &Value) -> Option<&Value> {
+        self.data.get(&key.to_string())
+    }
+}
+
+impl StateMachine for KvStore {
+    type Command = LinKv;
+    type Output = KvReply;
+
+    fn apply(&mut self, command: &LinKv) -> KvReply {
+        let missing = |key: &Value| {
+            KvReply::Error(
+                ErrorCode::KeyDoesNotExist,
+                format!("key {} does not exist", key),
+            )
+        };
+        match command {
+            LinKv::Read { key } => match self.get(key) {
+                Some(value) => KvReply::ReadOk(value.clone()),
+                None => missing(key),
+            },
+            LinKv::Write { key, value } => {
+                self.data.insert(key.to_string(), value.clone());
+                KvReply::WriteOk
+            }
+            LinKv::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => match self.data.get_mut(&key.to_string()) {
+                Some(current) if current == from => {
+                    *current = to.clone();
+                    KvReply::CasOk
+                }
+                Some(current) => KvReply::Error(
+                    ErrorCode::PreconditionFailed,
+                    format!("expected {} but had {}", from, current),
+                ),
+                None if *create_if_not_exists => {
+                    self.data.insert(key.to_string(), to.clone());
+                    KvReply::CasOk
+                }
+                None => missing(key),
+            },
+            // replies never make it into the log.
+            _ => KvReply::Error(ErrorCode::MalformedRequest, "not a request".to_string()),
+        }
+    }
+}
+
+impl Replicated for KvStore {
+    type Body = LinKv;
+
+    const LOST_COMMIT: ErrorCode = ErrorCode::TemporarilyUnavailable;
+
+    fn command(body: LinKv) -> Result<LinKv, LinKv> {
+        match body.in_reply_to() {
+            None => Ok(body),
+            Some(_) => Err(body),
+        }
+    }
+
+    fn request(command: LinKv) -> LinKv {
+        command
+    }
+
+    fn reply(output: KvReply, in_reply_to: usize) -> LinKv {
+        output.into_body(in_reply_to)
+    }
+
+    fn error(code: ErrorCode, text: &str, in_reply_to: usize) -> LinKv {
+        KvReply::Error(code, text.to_string()).into_body(in_reply_to)
+    }
+}
+
+pub type LinKvNode = RaftService<KvStore>;
+
+impl LinKvNode {
+    /// Committed value of `key` as applied on this node.
+    pub fn get(&self, key: &Value) -> Option<Value> {
+        self.read(|store| store.get(key).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::sim::Simulation;
+
+    const NODES: [&str; 3] = ["n1", "n2", "n3"];
+
+    fn request(sim: &mut Simulation<LinKvNode>, node: &str, data: LinKv) -> Value {
+        let msg_id = sim
+            .client_send("c1", node, LinKvPayload::Client(data))
+            .unwrap();
+        sim.run_for(Duration::from_millis(200)).unwrap();
+        sim.reply_to("c1", msg_id).unwrap().body.data.clone()
+    }
+
+    fn elected() -> Simulation<LinKvNode> {
+        let mut sim = Simulation::new(&NODES, 5);
+        sim.run_for(Duration::from_secs(2)).unwrap();
+        sim
+    }
+
+    #[test]
+    fn test_store_applies_cas_rules() {
+        let mut store = KvStore::default();
+        let cas = |from, to, create_if_not_exists| LinKv::Cas {
+            key: json!(1),
+            from: json!(from),
+            to: json!(to),
+            create_if_not_exists,
+        };
+        let error = |reply| match reply {
+            KvReply::Error(code, _) => Some(code),
+            _ => None,
+        };
+        assert_eq!(
+            Some(ErrorCode::KeyDoesNotExist),
+            error(store.apply(&LinKv::Read { key: json!(1) }))
+        );
+        assert_eq!(
+            Some(ErrorCode::KeyDoesNotExist),
+            error(store.apply(&cas(0, 1, false)))
+        );
+        assert_eq!(KvReply::CasOk, store.apply(&cas(0, 1, true)));
+        assert_eq!(
+            Some(ErrorCode::PreconditionFailed),
+            error(store.apply(&cas(0, 2, false)))
+        );
+        assert_eq!(KvReply::CasOk, store.apply(&cas(1, 2, false)));
+        assert_eq!(
+            KvReply::ReadOk(json!(2)),
+            store.apply(&LinKv::Read { key: json!(1) })
+        );
+    }
+
+    #[test]
+    fn test_every_node_serves_linearizable_requests() {
+        let mut sim = elected();
+        let write = LinKv::Write {
+            key: json!("x"),
+            value: json!(1),
+        };
+        assert_eq!("write_ok", request(&mut sim, "n1", write)["type"]);
+        for node in NODES {
+            let read = request(&mut sim, node, LinKv::Read { key: json!("x") });
+            assert_eq!(
+                (&json!("read_ok"), &json!(1)),
+                (&read["type"], &read["value"])
+            );
+        }
+        let cas = LinKv::Cas {
+            key: json!("x"),
+            from: json!(5),
+            to: json!(6),
+            create_if_not_exists: false,
+        };
+        let failed = request(&mut sim, "n2", cas);
+        assert_eq!(json!("error"), failed["type"]);
+        assert_eq!(json!(22), failed["code"]);
+        let missing = request(&mut sim, "n3", LinKv::Read { key: json!("y") });
+        assert_eq!(json!(20), missing["code"]);
+        for node in NODES {
+            assert_eq!(Some(json!(1)), sim.node(node).unwrap().get(&json!("x")));
+        }
+    }
+
+    #[test]
+    fn test_requests_without_leader_fail_definitely() {
+        let mut sim = Simulation::<LinKvNode>::new(&NODES, 5);
+        let reply = request(&mut sim, "n1", LinKv::Read { key: json!(1) });
+        assert_eq!(json!(11), reply["code"]);
+    }
+
+    #[test]
+    fn test_request_relayed_to_lost_leader_times_out() {
+        let mut sim = elected();
+        let leader = NODES
+            .into_iter()
+            .find(|node| sim.node(node).unwrap().is_leader())
+            .unwrap();
+        let follower = NODES.into_iter().find(|node| *node != leader).unwrap();
+        // the follower still knows the old leader, the request is lost on the way.
+        sim.partition(&[&[leader], &[follower]]);
+        let msg_id = sim
+            .client_send(
+                "c1",
+                follower,
+                LinKvPayload::Client(LinKv::Read { key: json!(1) }),
+            )
+            .unwrap();
+        sim.run_for(Duration::from_millis(900)).unwrap();
+        assert!(sim.reply_to("c1", msg_id).is_none());
+        sim.run_for(Duration::from_millis(200)).unwrap();
+        let reply = &sim.reply_to("c1", msg_id).unwrap().body.data;
+        assert_eq!(
+            (&json!("error"), &json!(0)),
+            (&reply["type"], &reply["code"])
+        );
+        assert_eq!(0, sim.node(follower).unwrap().forwarding());
+    }
+}
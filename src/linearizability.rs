@@ -0,0 +1,604 @@
+#![allow(dead_code)]
+//! Linearizability checking of recorded histories, following Wing & Gong's
+//! search with Lowe's memoization of `(linearized set, model state)` pairs.
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    hash::Hash,
+};
+
+use anyhow::bail;
+
+use crate::message;
+
+/// Sequential specification of the object under test.
+pub trait Model: Clone + Eq + Hash + Debug {
+    type Input: Clone + Debug;
+    type Output: Clone + Debug;
+
+    /// State after applying `input` with the observed `output`, `None` when
+    /// the output is impossible. `output` is `None` for operations that never
+    /// completed, those may take effect with whatever result.
+    fn step(&self, input: &Self::Input, output: Option<&Self::Output>) -> Option<Self>;
+}
+
+/// One operation of a history, times are positions in the history.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Operation<I, O> {
+    pub process: String,
+    pub input: I,
+    pub output: Option<O>,
+    pub call: usize,
+    /// `None` when the operation never completed.
+    pub ret: Option<usize>,
+}
+
+/// Concurrent history built from invocations and completions in the order
+/// they were observed.
+#[derive(Debug, Clone)]
+pub struct History<I, O> {
+    operations: Vec<Operation<I, O>>,
+    open: HashMap<String, usize>,
+    clock: usize,
+}
+
+impl<I, O> Default for History<I, O> {
+    fn default() -> Self {
+        History {
+            operations: Vec::new(),
+            open: HashMap::new(),
+            clock: 0,
+        }
+    }
+}
+
+impl<I, O> History<I, O> {
+    pub fn invoke(&mut self, process: &str, input: I) -> message::Result<()> {
+        if self.open.contains_key(process) {
+            bail!("process {} already has an operation in flight", process);
+        }
+        self.open.insert(process.to_string(), self.operations.len());
+        let call = self.tick();
+        self.operations.push(Operation {
+            process: process.to_string(),
+            input,
+            output: None,
+            call,
+            ret: None,
+        });
+        Ok(())
+    }
+
+    pub fn complete(&mut self, process: &str, output: O) -> message::Result<()> {
+        let Some(index) = self.open.remove(process) else {
+            bail!("process {} has no operation in flight", process);
+        };
+        let ret = self.tick();
+        let operation = &mut self.operations[index];
+        operation.output = Some(output);
+        operation.ret = Some(ret);
+        Ok(())
+    }
+
+    /// The operation of `process` will never complete (crash, timeout), it
+    /// may or may not have taken effect.
+    pub fn fail(&mut self, process: &str) -> message::Result<()> {
+        if self.open.remove(process).is_none() {
+            bail!("process {} has no operation in flight", process);
+        }
+        Ok(())
+    }
+
+    pub fn operations(&self) -> &[Operation<I, O>] {
+        &self.operations
+    }
+
+    fn tick(&mut self) -> usize {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckResult<I, O> {
+    Linearizable,
+    /// Operations of the shortest failing prefix that are needed to show the
+    /// violation. The last one is the operation that could not be placed,
+    /// the others are linearizable on their own.
+    NotLinearizable {
+        counterexample: Vec<Operation<I, O>>,
+    },
+}
+
+impl<I, O> CheckResult<I, O> {
+    pub fn is_linearizable(&self) -> bool {
+        matches!(self, CheckResult::Linearizable)
+    }
+}
+
+pub fn check<M: Model>(
+    initial: &M,
+    operations: &[Operation<M::Input, M::Output>],
+) -> CheckResult<M::Input, M::Output> {
+    if is_linearizable(initial, operations) {
+        return CheckResult::Linearizable;
+    }
+    // failing is monotone in the prefix length, binary search the shortest one.
+    let mut completions = operations
+        .iter()
+        .filter_map(|operation| operation.ret)
+        .collect::<Vec<_>>();
+    completions.sort();
+    if completions.is_empty() {
+        return CheckResult::NotLinearizable {
+            counterexample: operations.to_vec(),
+        };
+    }
+    let (mut low, mut high) = (0, completions.len() - 1);
+    while low < high {
+        let middle = (low + high) / 2;
+        if is_linearizable(initial, &prefix(operations, completions[middle])) {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    let mut counterexample = prefix(operations, completions[low]);
+    let failing = counterexample
+        .iter()
+        .position(|operation| operation.ret == Some(completions[low]))
+        .expect("prefix ends with a completion");
+    let failing = counterexample.remove(failing);
+
+    // drop operations as long as the rest still explains itself and the
+    // failing operation still cannot be placed.
+    let mut index = 0;
+    while index < counterexample.len() {
+        let mut candidate = counterexample.clone();
+        candidate.remove(index);
+        let mut with_failing = candidate.clone();
+        with_failing.push(failing.clone());
+        if is_linearizable(initial, &candidate) && !is_linearizable(initial, &with_failing) {
+            counterexample = candidate;
+        } else {
+            index += 1;
+        }
+    }
+    counterexample.push(failing);
+    CheckResult::NotLinearizable { counterexample }
+}
+
+/// History as it looked at time `at`, later completions become pending.
+fn prefix<I: Clone, O: Clone>(operations: &[Operation<I, O>], at: usize) -> Vec<Operation<I, O>> {
+    operations
+        .iter()
+        .filter(|operation| operation.call < at)
+        .map(|operation| match operation.ret {
+            Some(ret) if ret <= at => operation.clone(),
+            _ => Operation {
+                output: None,
+                ret: None,
+                ..operation.clone()
+            },
+        })
+        .collect()
+}
+
+/// Checks every key on its own, linearizability is compositional so the
+/// history is linearizable iff every per key history is.
+pub fn check_partitioned<M, K>(
+    initial: &M,
+    operations: &[Operation<M::Input, M::Output>],
+    key: impl Fn(&M::Input) -> K,
+) -> CheckResult<M::Input, M::Output>
+where
+    M: Model,
+    K: Hash + Eq + Ord,
+{
+    let mut partitions = HashMap::<K, Vec<_>>::new();
+    for operation in operations {
+        partitions
+            .entry(key(&operation.input))
+            .or_default()
+            .push(operation.clone());
+    }
+    let mut keys = partitions.keys().collect::<Vec<_>>();
+    keys.sort();
+    for key in keys {
+        let result = check(initial, &partitions[key]);
+        if !result.is_linearizable() {
+            return result;
+        }
+    }
+    CheckResult::Linearizable
+}
+
+const NONE: usize = usize::MAX;
+
+struct Entry {
+    operation: usize,
+    // index of the matching return entry, only set on calls.
+    ret: Option<usize>,
+}
+
+/// Doubly linked list of call and return entries ordered by time, entry 0
+/// is the head sentinel.
+struct Entries {
+    entries: Vec<Entry>,
+    prev: Vec<usize>,
+    next: Vec<usize>,
+}
+
+impl Entries {
+    fn new<I, O>(operations: &[Operation<I, O>]) -> Self {
+        // operations that never returned return after everything else.
+        let mut timed = Vec::with_capacity(operations.len() * 2);
+        for (index, operation) in operations.iter().enumerate() {
+            timed.push((operation.call, false, index));
+            timed.push((operation.ret.unwrap_or(NONE), true, index));
+        }
+        timed.sort();
+        let mut entries = vec![Entry {
+            operation: NONE,
+            ret: None,
+        }];
+        let mut call_entry = vec![0; operations.len()];
+        for (_, is_return, operation) in timed {
+            if is_return {
+                entries[call_entry[operation]].ret = Some(entries.len());
+            } else {
+                call_entry[operation] = entries.len();
+            }
+            entries.push(Entry {
+                operation,
+                ret: None,
+            });
+        }
+        let len = entries.len();
+        Entries {
+            entries,
+            prev: (0..len).map(|index| index.wrapping_sub(1)).collect(),
+            next: (1..=len)
+                .map(|index| if index == len { NONE } else { index })
+                .collect(),
+        }
+    }
+
+    fn unlink(&mut self, index: usize) {
+        let (prev, next) = (self.prev[index], self.next[index]);
+        self.next[prev] = next;
+        if next != NONE {
+            self.prev[next] = prev;
+        }
+    }
+
+    fn relink(&mut self, index: usize) {
+        let (prev, next) = (self.prev[index], self.next[index]);
+        self.next[prev] = index;
+        if next != NONE {
+            self.prev[next] = index;
+        }
+    }
+
+    /// Removes a call and its return, they are put back in reverse order.
+    fn lift(&mut self, call: usize) {
+        self.unlink(call);
+        if let Some(ret) = self.entries[call].ret {
+            self.unlink(ret);
+        }
+    }
+
+    fn unlift(&mut self, call: usize) {
+        if let Some(ret) = self.entries[call].ret {
+            self.relink(ret);
+        }
+        self.relink(call);
+    }
+}
+
+fn is_linearizable<M: Model>(initial: &M, operations: &[Operation<M::Input, M::Output>]) -> bool {
+    let mut entries = Entries::new(operations);
+    let mut linearized = vec![0u64; operations.len().div_ceil(64)];
+    let mut cache = HashSet::new();
+    let mut calls: Vec<(usize, M)> = Vec::new();
+    let mut state = initial.clone();
+    let mut entry = entries.next[0];
+    while entries.next[0] != NONE {
+        if entry == NONE {
+            return false;
+        }
+        let operation = entries.entries[entry].operation;
+        if entries.entries[entry].ret.is_some() {
+            let op = &operations[operation];
+            if let Some(next_state) = state.step(&op.input, op.output.as_ref()) {
+                linearized[operation / 64] |= 1 << (operation % 64);
+                if cache.insert((linearized.clone(), next_state.clone())) {
+                    calls.push((entry, std::mem::replace(&mut state, next_state)));
+                    entries.lift(entry);
+                    entry = entries.next[0];
+                    continue;
+                }
+                linearized[operation / 64] &= !(1 << (operation % 64));
+            }
+            entry = entries.next[entry];
+        } else {
+            // reached a return whose call could not be placed, backtrack.
+            let Some((call, previous_state)) = calls.pop() else {
+                return false;
+            };
+            let operation = entries.entries[call].operation;
+            linearized[operation / 64] &= !(1 << (operation % 64));
+            state = previous_state;
+            entries.unlift(call);
+            entry = entries.next[call];
+        }
+    }
+    true
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegisterInput<K> {
+    Read(K),
+    Write(K, i64),
+    Cas(K, i64, i64),
+}
+
+impl<K> RegisterInput<K> {
+    pub fn key(&self) -> &K {
+        match self {
+            RegisterInput::Read(key)
+            | RegisterInput::Write(key, _)
+            | RegisterInput::Cas(key, ..) => key,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegisterOutput {
+    /// `None` when the key does not exist.
+    ReadOk(Option<i64>),
+    WriteOk,
+    CasOk,
+    /// The register did not hold the expected value (or did not exist).
+    CasFailed,
+}
+
+/// Single read/write/cas register as in Maelstrom's `lin-kv`. Histories over
+/// several keys are checked with `check_partitioned` on `RegisterInput::key`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Register<K> {
+    pub value: Option<i64>,
+    key: std::marker::PhantomData<K>,
+}
+
+impl<K> Register<K> {
+    pub fn new(value: Option<i64>) -> Self {
+        Register {
+            value,
+            key: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<K: Clone + Debug + Eq + Hash> Model for Register<K> {
+    type Input = RegisterInput<K>;
+    type Output = RegisterOutput;
+
+    fn step(&self, input: &Self::Input, output: Option<&Self::Output>) -> Option<Self> {
+        match (input, output) {
+            (RegisterInput::Read(_), None) => Some(self.clone()),
+            (RegisterInput::Read(_), Some(RegisterOutput::ReadOk(value))) => {
+                (*value == self.value).then(|| self.clone())
+            }
+            (RegisterInput::Write(_, value), None | Some(RegisterOutput::WriteOk)) => {
+                Some(Register::new(Some(*value)))
+            }
+            (RegisterInput::Cas(_, from, to), None) => Some(match self.value == Some(*from) {
+                true => Register::new(Some(*to)),
+                false => self.clone(),
+            }),
+            (RegisterInput::Cas(_, from, to), Some(RegisterOutput::CasOk)) => {
+                (self.value == Some(*from)).then(|| Register::new(Some(*to)))
+            }
+            (RegisterInput::Cas(_, from, _), Some(RegisterOutput::CasFailed)) => {
+                (self.value != Some(*from)).then(|| self.clone())
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CounterInput {
+    Add(i64),
+    Read,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CounterOutput {
+    AddOk,
+    ReadOk(i64),
+}
+
+/// Counter as in Maelstrom's `g-counter`/`pn-counter`, checked strictly.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct CounterModel {
+    pub value: i64,
+}
+
+impl Model for CounterModel {
+    type Input = CounterInput;
+    type Output = CounterOutput;
+
+    fn step(&self, input: &Self::Input, output: Option<&Self::Output>) -> Option<Self> {
+        match (input, output) {
+            (CounterInput::Add(delta), None | Some(CounterOutput::AddOk)) => Some(CounterModel {
+                value: self.value + delta,
+            }),
+            (CounterInput::Read, None) => Some(self.clone()),
+            (CounterInput::Read, Some(CounterOutput::ReadOk(value))) => {
+                (*value == self.value).then(|| self.clone())
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type RegisterHistory = History<RegisterInput<&'static str>, RegisterOutput>;
+
+    fn register() -> Register<&'static str> {
+        Register::new(None)
+    }
+
+    #[test]
+    fn test_concurrent_register_history_is_linearizable() {
+        let mut history = RegisterHistory::default();
+        history.invoke("c1", RegisterInput::Write("x", 1)).unwrap();
+        history.invoke("c2", RegisterInput::Read("x")).unwrap();
+        history.invoke("c3", RegisterInput::Cas("x", 1, 2)).unwrap();
+        // the read overlaps the write, it may see either value.
+        history
+            .complete("c2", RegisterOutput::ReadOk(Some(2)))
+            .unwrap();
+        history.complete("c1", RegisterOutput::WriteOk).unwrap();
+        history.complete("c3", RegisterOutput::CasOk).unwrap();
+        history.invoke("c2", RegisterInput::Read("x")).unwrap();
+        history
+            .complete("c2", RegisterOutput::ReadOk(Some(2)))
+            .unwrap();
+
+        assert!(check(&register(), history.operations()).is_linearizable());
+    }
+
+    #[test]
+    fn test_stale_read_yields_minimal_counterexample() {
+        let mut history = RegisterHistory::default();
+        history.invoke("c1", RegisterInput::Write("x", 1)).unwrap();
+        history.complete("c1", RegisterOutput::WriteOk).unwrap();
+        history.invoke("c3", RegisterInput::Read("x")).unwrap();
+        history
+            .complete("c3", RegisterOutput::ReadOk(Some(1)))
+            .unwrap();
+        history.invoke("c1", RegisterInput::Write("x", 2)).unwrap();
+        history.complete("c1", RegisterOutput::WriteOk).unwrap();
+        history.invoke("c2", RegisterInput::Read("x")).unwrap();
+        history
+            .complete("c2", RegisterOutput::ReadOk(Some(1)))
+            .unwrap();
+
+        let CheckResult::NotLinearizable { counterexample } =
+            check(&register(), history.operations())
+        else {
+            panic!("stale read accepted");
+        };
+        let inputs = counterexample
+            .iter()
+            .map(|operation| operation.input.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                RegisterInput::Write("x", 1),
+                RegisterInput::Write("x", 2),
+                RegisterInput::Read("x")
+            ],
+            inputs
+        );
+    }
+
+    #[test]
+    fn test_pending_write_may_take_effect() {
+        let mut history = RegisterHistory::default();
+        history.invoke("c1", RegisterInput::Write("x", 5)).unwrap();
+        history.fail("c1").unwrap();
+        history.invoke("c2", RegisterInput::Read("x")).unwrap();
+        history
+            .complete("c2", RegisterOutput::ReadOk(None))
+            .unwrap();
+        history.invoke("c2", RegisterInput::Read("x")).unwrap();
+        history
+            .complete("c2", RegisterOutput::ReadOk(Some(5)))
+            .unwrap();
+        assert!(check(&register(), history.operations()).is_linearizable());
+
+        // once seen, the write cannot be undone.
+        history.invoke("c2", RegisterInput::Read("x")).unwrap();
+        history
+            .complete("c2", RegisterOutput::ReadOk(None))
+            .unwrap();
+        assert!(!check(&register(), history.operations()).is_linearizable());
+    }
+
+    #[test]
+    fn test_partitioned_check_by_key() {
+        let mut history = RegisterHistory::default();
+        history.invoke("c1", RegisterInput::Write("x", 1)).unwrap();
+        history.invoke("c2", RegisterInput::Write("y", 2)).unwrap();
+        history.complete("c1", RegisterOutput::WriteOk).unwrap();
+        history.complete("c2", RegisterOutput::WriteOk).unwrap();
+        history.invoke("c1", RegisterInput::Cas("y", 1, 3)).unwrap();
+        history.complete("c1", RegisterOutput::CasFailed).unwrap();
+        history.invoke("c2", RegisterInput::Read("x")).unwrap();
+        history
+            .complete("c2", RegisterOutput::ReadOk(Some(1)))
+            .unwrap();
+        let result = check_partitioned(&register(), history.operations(), |input| *input.key());
+        assert!(result.is_linearizable());
+
+        history.invoke("c2", RegisterInput::Read("y")).unwrap();
+        history
+            .complete("c2", RegisterOutput::ReadOk(Some(3)))
+            .unwrap();
+        let result = check_partitioned(&register(), history.operations(), |input| *input.key());
+        assert!(!result.is_linearizable());
+    }
+
+    #[test]
+    fn test_counter_histories() {
+        let mut history = History::default();
+        history.invoke("c1", CounterInput::Add(2)).unwrap();
+        history.invoke("c2", CounterInput::Add(3)).unwrap();
+        history.invoke("c3", CounterInput::Read).unwrap();
+        history.complete("c3", CounterOutput::ReadOk(3)).unwrap();
+        history.complete("c1", CounterOutput::AddOk).unwrap();
+        history.complete("c2", CounterOutput::AddOk).unwrap();
+        history.invoke("c3", CounterInput::Read).unwrap();
+        history.complete("c3", CounterOutput::ReadOk(5)).unwrap();
+        assert!(check(&CounterModel::default(), history.operations()).is_linearizable());
+
+        history.invoke("c3", CounterInput::Read).unwrap();
+        history.complete("c3", CounterOutput::ReadOk(4)).unwrap();
+        let CheckResult::NotLinearizable { counterexample } =
+            check(&CounterModel::default(), history.operations())
+        else {
+            panic!("decreasing counter accepted");
+        };
+        assert_eq!(
+            Some(CounterOutput::ReadOk(4)),
+            counterexample.last().unwrap().output
+        );
+    }
+
+    #[test]
+    fn test_wide_concurrent_history_stays_fast() {
+        // many overlapping writes make the naive search explode, the cache
+        // keeps it to a handful of states.
+        let mut history = History::default();
+        for process in 0..40 {
+            history
+                .invoke(&format!("c{}", process), CounterInput::Add(1))
+                .unwrap();
+        }
+        for process in 0..40 {
+            history
+                .complete(&format!("c{}", process), CounterOutput::AddOk)
+                .unwrap();
+        }
+        history.invoke("r", CounterInput::Read).unwrap();
+        history.complete("r", CounterOutput::ReadOk(40)).unwrap();
+        assert!(check(&CounterModel::default(), history.operations()).is_linearizable());
+    }
+}
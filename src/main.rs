@@ -1,24 +1,94 @@
-#![allow(unused_imports)]
-use std::{
-    io::{BufRead, Write},
-    sync::mpsc::channel,
-    thread,
+use anyhow::bail;
+use fly_dis::{
+    broadcase_handler::BroadcastNode,
+    config::Config,
+    counter::CounterNode,
+    echo_handler::EchoNode,
+    lin_kv::LinKvNode,
+    message,
+    runtime::{self, RuntimeOptions},
+    schema, trace,
+    txn_list_append::TxnNode,
+    unique_id_handler::UniqueIdNode,
 };
 
-use anyhow::Ok;
-use broadcase_handler::{Broadcast, BroadcastNode};
-use counter::{Counter, CounterNode};
-use echo_handler::{Echo, EchoNode};
-use message::{Handler, Init, Message, Payload};
-use unique_id_handler::{Generate, UniqueIdNode};
+const USAGE: &str = "usage: fly_dis [workload] | fly_dis replay <workload> <trace.jsonl>, \
+                     both take [--config file] [--<config-key> value] | \
+                     fly_dis schema <workload> | fly_dis validate <workload> <trace.jsonl>";
 
-mod broadcase_handler;
-mod counter;
-mod echo_handler;
-mod message;
-mod periodic_thread;
-mod unique_id_handler;
 fn main() -> message::Result<()> {
-    CounterNode::main_loop()?;
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let (config, args) = Config::load(&args, |key| std::env::var(key).ok())?;
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        [] => run("g-counter", config),
+        ["replay", workload, trace] => replay(workload, trace, &config),
+        ["schema", workload] => print_schema(workload),
+        ["validate", workload, trace] => validate(workload, trace),
+        [workload] => run(workload, config),
+        _ => bail!(USAGE),
+    }
+}
+
+fn run(workload: &str, config: Config) -> message::Result<()> {
+    let options = RuntimeOptions {
+        config,
+        ..RuntimeOptions::from_env()?
+    };
+    match workload {
+        "echo" => runtime::run::<EchoNode>(options),
+        "unique-ids" => runtime::run::<UniqueIdNode>(options),
+        "broadcast" => runtime::run::<BroadcastNode>(options),
+        "g-counter" => runtime::run::<CounterNode>(options),
+        "lin-kv" => runtime::run::<LinKvNode>(options),
+        "txn-list-append" => runtime::run::<TxnNode>(options),
+        _ => bail!("unknown workload {}, {}", workload, USAGE),
+    }
+}
+
+fn replay(workload: &str, trace: &str, config: &Config) -> message::Result<()> {
+    let report = match workload {
+        "echo" => trace::replay_file::<EchoNode>(trace, config)?,
+        "unique-ids" => trace::replay_file::<UniqueIdNode>(trace, config)?,
+        "broadcast" => trace::replay_file::<BroadcastNode>(trace, config)?,
+        "g-counter" => trace::replay_file::<CounterNode>(trace, config)?,
+        "lin-kv" => trace::replay_file::<LinKvNode>(trace, config)?,
+        "txn-list-append" => trace::replay_file::<TxnNode>(trace, config)?,
+        _ => bail!("unknown workload {}, {}", workload, USAGE),
+    };
+    print!("{}", report);
+    if !report.matches() {
+        bail!("replay diverged from trace");
+    }
+    Ok(())
+}
+
+fn print_schema(workload: &str) -> message::Result<()> {
+    let schema = match workload {
+        "echo" => schema::traffic_schema::<EchoNode>(),
+        "unique-ids" => schema::traffic_schema::<UniqueIdNode>(),
+        "broadcast" => schema::traffic_schema::<BroadcastNode>(),
+        "g-counter" => schema::traffic_schema::<CounterNode>(),
+        "lin-kv" => schema::traffic_schema::<LinKvNode>(),
+        "txn-list-append" => schema::traffic_schema::<TxnNode>(),
+        _ => bail!("unknown workload {}, {}", workload, USAGE),
+    };
+    println!("{}", serde_json::to_string_pretty(&schema)?);
+    Ok(())
+}
+
+fn validate(workload: &str, trace: &str) -> message::Result<()> {
+    let report = match workload {
+        "echo" => schema::validate_file::<EchoNode>(trace)?,
+        "unique-ids" => schema::validate_file::<UniqueIdNode>(trace)?,
+        "broadcast" => schema::validate_file::<BroadcastNode>(trace)?,
+        "g-counter" => schema::validate_file::<CounterNode>(trace)?,
+        "lin-kv" => schema::validate_file::<LinKvNode>(trace)?,
+        "txn-list-append" => schema::validate_file::<TxnNode>(trace)?,
+        _ => bail!("unknown workload {}, {}", workload, USAGE),
+    };
+    print!("{}", report);
+    if !report.passed() {
+        bail!("trace violates the schema");
+    }
     Ok(())
 }
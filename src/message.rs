@@ -1,12 +1,29 @@
 #![allow(dead_code, unused_variables)]
 
-use std::{io, str::FromStr};
+use std::{
+    io::{self, BufRead},
+    str::FromStr,
+};
 
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{InstanceType, Schema, SchemaObject},
+    JsonSchema,
+};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::ser::{CompactFormatter, Formatter};
+
+use crate::{
+    admin::{Admin, AdminHandler},
+    clock::Clock,
+    event_queue::EventQueue,
+    output::Outbox,
+    trace::Trace,
+};
 
 pub type Result<T> = std::result::Result<T, anyhow::Error>;
 pub struct ParseError(String);
-#[derive(Debug, Serialize, Deserialize, Clone, Hash, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, Hash, PartialEq, Eq)]
 pub struct Message<T> {
     pub src: String,
     #[serde(rename = "dest")]
@@ -14,16 +31,27 @@ pub struct Message<T> {
     pub body: Payload<T>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Hash, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, Hash, PartialEq, Eq)]
 pub struct Payload<T> {
     #[serde(flatten)]
     pub data: T,
+    /// Left out rather than `null` when there is none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "usize")]
     pub msg_id: Option<usize>,
+    /// Stamped by the outbox of the sender when logical clocks are enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Clock")]
+    pub clock: Option<Clock>,
 }
 
 impl<T> Payload<T> {
     pub fn new(data: T, msg_id: Option<usize>) -> Self {
-        Payload { data, msg_id }
+        Payload {
+            data,
+            msg_id,
+            clock: None,
+        }
     }
 }
 
@@ -31,32 +59,40 @@ impl<T> Message<T> {
     pub fn new(src: String, dst: String, body: Payload<T>) -> Self {
         Message { src, dst, body }
     }
+
+    /// Same envelope around a converted body.
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Message<U> {
+        Message {
+            src: self.src,
+            dst: self.dst,
+            body: Payload {
+                data: f(self.body.data),
+                msg_id: self.body.msg_id,
+                clock: self.body.clock,
+            },
+        }
+    }
 }
 
 pub trait Handler<T> {
-    fn handle(&self, writer: &mut dyn io::Write, message: T) -> Result<()>;
+    fn handle(&self, outbox: &Outbox, message: T) -> Result<()>;
 }
 
 impl Handler<Message<Init>> for Init {
-    fn handle(&self, writer: &mut dyn io::Write, message: Message<Init>) -> Result<()> {
+    fn handle(&self, outbox: &Outbox, message: Message<Init>) -> Result<()> {
         let init_ok = Init::InitOk {
             in_reply_to: message.body.msg_id.unwrap_or(1),
         };
         let message = Message::new(
             message.dst.clone(),
             message.src.clone(),
-            Payload {
-                data: init_ok,
-                msg_id: None,
-            },
+            Payload::new(init_ok, None),
         );
-        serde_json::to_writer(&mut *writer, &message)?;
-        writer.write(b"\n")?;
-        Ok(())
+        outbox.send(&message)
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Hash, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, Hash, PartialEq, Eq)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum Init {
     Init {
@@ -68,6 +104,384 @@ pub enum Init {
     },
 }
 
+/// Error codes defined by the Maelstrom protocol.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Hash, PartialEq, Eq)]
+#[serde(into = "usize", try_from = "usize")]
+pub enum ErrorCode {
+    Timeout,
+    NotSupported,
+    TemporarilyUnavailable,
+    MalformedRequest,
+    Crash,
+    Abort,
+    KeyDoesNotExist,
+    KeyAlreadyExists,
+    PreconditionFailed,
+    TxnConflict,
+}
+
+impl From<ErrorCode> for usize {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::Timeout => 0,
+            ErrorCode::NotSupported => 10,
+            ErrorCode::TemporarilyUnavailable => 11,
+            ErrorCode::MalformedRequest => 12,
+            ErrorCode::Crash => 13,
+            ErrorCode::Abort => 14,
+            ErrorCode::KeyDoesNotExist => 20,
+            ErrorCode::KeyAlreadyExists => 21,
+            ErrorCode::PreconditionFailed => 22,
+            ErrorCode::TxnConflict => 30,
+        }
+    }
+}
+
+impl ErrorCode {
+    pub const ALL: [ErrorCode; 10] = [
+        ErrorCode::Timeout,
+        ErrorCode::NotSupported,
+        ErrorCode::TemporarilyUnavailable,
+        ErrorCode::MalformedRequest,
+        ErrorCode::Crash,
+        ErrorCode::Abort,
+        ErrorCode::KeyDoesNotExist,
+        ErrorCode::KeyAlreadyExists,
+        ErrorCode::PreconditionFailed,
+        ErrorCode::TxnConflict,
+    ];
+}
+
+/// The numeric code, as on the wire.
+impl JsonSchema for ErrorCode {
+    fn schema_name() -> String {
+        "ErrorCode".to_string()
+    }
+
+    fn json_schema(_gen: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            instance_type: Some(InstanceType::Integer.into()),
+            enum_values: Some(
+                ErrorCode::ALL
+                    .into_iter()
+                    .map(|code| usize::from(code).into())
+                    .collect(),
+            ),
+            ..SchemaObject::default()
+        }
+        .into()
+    }
+}
+
+impl TryFrom<usize> for ErrorCode {
+    type Error = String;
+
+    fn try_from(code: usize) -> std::result::Result<Self, Self::Error> {
+        Ok(match code {
+            0 => ErrorCode::Timeout,
+            10 => ErrorCode::NotSupported,
+            11 => ErrorCode::TemporarilyUnavailable,
+            12 => ErrorCode::MalformedRequest,
+            13 => ErrorCode::Crash,
+            14 => ErrorCode::Abort,
+            20 => ErrorCode::KeyDoesNotExist,
+            21 => ErrorCode::KeyAlreadyExists,
+            22 => ErrorCode::PreconditionFailed,
+            30 => ErrorCode::TxnConflict,
+            other => return Err(format!("unknown error code {}", other)),
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, Hash, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ErrorReply {
+    Error {
+        in_reply_to: usize,
+        code: ErrorCode,
+        text: String,
+    },
+}
+
+/// Result of parsing one line of node input.
+#[derive(Debug)]
+pub enum Incoming<T> {
+    Message(Message<T>),
+    /// Well formed envelope whose body `type` this node does not know.
+    Unsupported {
+        src: String,
+        dst: String,
+        kind: String,
+        msg_id: Option<usize>,
+    },
+    Malformed(anyhow::Error),
+}
+
+/// Body type and `in_reply_to` of a serialized message, the rest of the
+/// body is skipped.
+#[derive(Deserialize)]
+pub struct BodyHeader {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub in_reply_to: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct HeaderEnvelope {
+    body: BodyHeader,
+}
+
+pub fn body_header(line: &str) -> Result<BodyHeader> {
+    Ok(serde_json::from_str::<HeaderEnvelope>(line)?.body)
+}
+
+/// Serializes `message` into a line and picks up its body header while
+/// writing, so senders need not parse their own output again.
+pub fn to_line<T: Serialize>(message: &Message<T>) -> Result<(String, BodyHeader)> {
+    let mut header = BodyHeader {
+        kind: String::new(),
+        in_reply_to: None,
+    };
+    let mut line = Vec::new();
+    let formatter = HeaderFormatter {
+        header: &mut header,
+        depth: 0,
+        in_key: false,
+        key: String::new(),
+    };
+    message.serialize(&mut serde_json::Serializer::with_formatter(
+        &mut line, formatter,
+    ))?;
+    Ok((String::from_utf8(line)?, header))
+}
+
+// compact output, watching the fields of the body, the only object nested
+// in the envelope.
+struct HeaderFormatter<'a> {
+    header: &'a mut BodyHeader,
+    depth: usize,
+    in_key: bool,
+    // key of the body field being written.
+    key: String,
+}
+
+const BODY_DEPTH: usize = 2;
+
+impl Formatter for HeaderFormatter<'_> {
+    fn begin_object<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.depth += 1;
+        CompactFormatter.begin_object(writer)
+    }
+
+    fn end_object<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.depth -= 1;
+        CompactFormatter.end_object(writer)
+    }
+
+    fn begin_array<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.depth += 1;
+        CompactFormatter.begin_array(writer)
+    }
+
+    fn end_array<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.depth -= 1;
+        CompactFormatter.end_array(writer)
+    }
+
+    fn begin_object_key<W: ?Sized + io::Write>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> io::Result<()> {
+        if self.depth == BODY_DEPTH {
+            self.in_key = true;
+            self.key.clear();
+        }
+        CompactFormatter.begin_object_key(writer, first)
+    }
+
+    fn end_object_key<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.in_key = false;
+        CompactFormatter.end_object_key(writer)
+    }
+
+    fn write_string_fragment<W: ?Sized + io::Write>(
+        &mut self,
+        writer: &mut W,
+        fragment: &str,
+    ) -> io::Result<()> {
+        if self.depth == BODY_DEPTH {
+            match (self.in_key, self.key.as_str()) {
+                (true, _) => self.key.push_str(fragment),
+                (false, "type") => self.header.kind.push_str(fragment),
+                _ => {}
+            }
+        }
+        CompactFormatter.write_string_fragment(writer, fragment)
+    }
+
+    fn write_u64<W: ?Sized + io::Write>(&mut self, writer: &mut W, value: u64) -> io::Result<()> {
+        if self.depth == BODY_DEPTH && self.key == "in_reply_to" {
+            self.header.in_reply_to = Some(value as usize);
+        }
+        CompactFormatter.write_u64(writer, value)
+    }
+}
+
+#[derive(Deserialize)]
+struct Envelope {
+    src: String,
+    #[serde(rename = "dest")]
+    dst: String,
+    body: EnvelopeBody,
+}
+
+#[derive(Deserialize)]
+struct EnvelopeBody {
+    #[serde(rename = "type")]
+    kind: String,
+    msg_id: Option<usize>,
+}
+
+/// Body `type`s a payload is read from, which tells a body of some other
+/// type from a broken one before the body itself is deserialized. Untagged
+/// payloads list the types of every payload they wrap.
+pub trait BodyTypes {
+    const TYPES: &'static [&'static str];
+}
+
+impl BodyTypes for Init {
+    const TYPES: &'static [&'static str] = &["init", "init_ok"];
+}
+
+/// Tells a line whose body `type` is not one of `T` from a line that is
+/// broken, before deserializing the body itself.
+pub fn parse_incoming<T>(line: &str) -> Incoming<T>
+where
+    T: DeserializeOwned + BodyTypes,
+{
+    let envelope = match serde_json::from_str::<Envelope>(line) {
+        Ok(envelope) => envelope,
+        Err(error) => return Incoming::Malformed(error.into()),
+    };
+    if !T::TYPES.contains(&envelope.body.kind.as_str()) {
+        return Incoming::Unsupported {
+            src: envelope.src,
+            dst: envelope.dst,
+            kind: envelope.body.kind,
+            msg_id: envelope.body.msg_id,
+        };
+    }
+    match line.parse::<Message<T>>() {
+        Ok(message) => Incoming::Message(message),
+        Err(error) => Incoming::Malformed(error),
+    }
+}
+
+/// Reads messages line by line until `input` is exhausted and pushes them to `queue`.
+/// Malformed lines are logged and skipped, unsupported requests carrying a `msg_id`
+/// are answered with a `not-supported` error through `outbox`, whose clock
+/// also takes in the stamp of every message read. Retried requests the
+/// outbox has seen before never reach `queue`, unless the overflow policy
+/// dropped the original, nor do `admin` requests, which are answered right
+/// away.
+pub fn read_messages<T, E>(
+    input: impl BufRead,
+    queue: &EventQueue<E>,
+    wrap: impl Fn(Message<T>) -> E,
+    outbox: &Outbox,
+    trace: Option<&Trace>,
+    admin: Option<AdminHandler>,
+) -> Result<()>
+where
+    T: DeserializeOwned + BodyTypes,
+    E: Serialize,
+{
+    for line in input.lines() {
+        let line = line?;
+        if let Some(trace) = trace {
+            trace.record_in(&line);
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Ok(header) = body_header(&line) {
+            outbox.stats().record_in(&header.kind);
+        }
+        let incoming = parse_incoming::<T>(&line);
+        // workload bodies come first, untagged payloads fail on admin types.
+        if let (Some(admin), Incoming::Unsupported { .. } | Incoming::Malformed(_)) =
+            (admin, &incoming)
+        {
+            if let Ok(message) = line.parse::<Message<Admin>>() {
+                admin(outbox, message)?;
+                continue;
+            }
+        }
+        match incoming {
+            Incoming::Message(message) => {
+                outbox.receive(message.body.clock.as_ref());
+                if !outbox.admit(&message.src, message.body.msg_id)? {
+                    continue;
+                }
+                let request = message
+                    .body
+                    .msg_id
+                    .map(|msg_id| (message.src.clone(), msg_id));
+                let dropped = queue
+                    .push_request(wrap(message), request)
+                    .map_err(|_| anyhow::anyhow!("node stopped receiving messages"))?;
+                // a dropped request was never handled, its retry must get through.
+                if let Some((src, msg_id)) = dropped {
+                    outbox.forget(&src, msg_id);
+                }
+            }
+            Incoming::Unsupported {
+                src,
+                dst,
+                kind,
+                msg_id,
+            } => {
+                eprintln!("Unsupported message type {} from {}", kind, src);
+                if let Some(in_reply_to) = msg_id {
+                    let error = Message::new(
+                        dst,
+                        src,
+                        Payload::new(
+                            ErrorReply::Error {
+                                in_reply_to,
+                                code: ErrorCode::NotSupported,
+                                text: format!("message type {} is not supported", kind),
+                            },
+                            None,
+                        ),
+                    );
+                    outbox.answer(&error)?;
+                }
+            }
+            Incoming::Malformed(error) => {
+                eprintln!("Skipping malformed input {:?}: {}", line, error);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads lines until a valid `init` message arrives, `None` when input ends first.
+pub fn read_init(input: &mut impl BufRead) -> Result<Option<Message<Init>>> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        match parse_incoming::<Init>(&line) {
+            Incoming::Message(message) => return Ok(Some(message)),
+            _ => eprintln!("Skipping {:?} while waiting for init", line.trim_end()),
+        }
+    }
+}
+
 impl<T> FromStr for Message<T>
 where
     T: DeserializeOwned,
@@ -81,7 +495,10 @@ where
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
     use super::*;
+    use crate::event_queue::OverflowPolicy;
 
     #[test]
     fn test_init_message_serialization() {
@@ -105,8 +522,203 @@ mod tests {
         let serde_message_with_body = serde_json::to_string(&message).unwrap();
         println!("{}", serde_message_with_body);
         assert_eq!(
-            r#"{"src":"n1","dest":"c2","body":{"type":"init","node_id":"n3","node_ids":["n1","n2","n3"],"msg_id":null}}"#,
+            r#"{"src":"n1","dest":"c2","body":{"type":"init","node_id":"n3","node_ids":["n1","n2","n3"]}}"#,
             serde_message_with_body
         );
     }
+
+    const VALID_LINES: [&str; 3] = [
+        r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2"]}}"#,
+        r#"{"src":"n2","dest":"n1","body":{"type":"init_ok","in_reply_to":7,"msg_id":null}}"#,
+        r#"{"src":"c1","dest":"n1","body":{"type":"init","node_id":"n1","node_ids":[]}}"#,
+    ];
+
+    fn collect_replies(input: &str) -> (Vec<Message<Init>>, String) {
+        // room for every line, nothing pops while reading.
+        let queue = EventQueue::new(input.lines().count(), OverflowPolicy::Block);
+        let (outbox, replies) = Outbox::channel();
+        read_messages::<Init, _>(input.as_bytes(), &queue, |m| m, &outbox, None, None).unwrap();
+        queue.close();
+        drop(outbox);
+        (
+            std::iter::from_fn(|| queue.pop()).collect(),
+            replies.iter().collect(),
+        )
+    }
+
+    #[test]
+    fn test_truncated_lines_are_skipped() {
+        for line in VALID_LINES {
+            assert!(matches!(parse_incoming::<Init>(line), Incoming::Message(_)));
+            for end in (0..line.len()).filter(|end| line.is_char_boundary(*end)) {
+                let truncated = &line[..end];
+                assert!(
+                    !matches!(parse_incoming::<Init>(truncated), Incoming::Message(_)),
+                    "{}",
+                    truncated
+                );
+                let (messages, replies) = collect_replies(truncated);
+                assert!(messages.is_empty());
+                assert!(replies.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn test_fuzzed_lines_never_stop_reader() {
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        let mut input = String::new();
+        for _ in 0..2000 {
+            let mut bytes = VALID_LINES[next() as usize % VALID_LINES.len()]
+                .as_bytes()
+                .to_vec();
+            for _ in 0..(next() % 4) + 1 {
+                let at = next() as usize % bytes.len();
+                match next() % 3 {
+                    0 => bytes[at] = (next() % 128) as u8,
+                    1 => {
+                        bytes.remove(at);
+                    }
+                    _ => bytes.insert(at, b"{}[]\":,0"[next() as usize % 8]),
+                }
+            }
+            let line = String::from_utf8_lossy(&bytes).replace('\n', " ");
+            let _ = parse_incoming::<Init>(&line);
+            input.push_str(&line);
+            input.push('\n');
+        }
+        // a valid message after all the garbage still reaches the node.
+        input.push_str(VALID_LINES[0]);
+        input.push('\n');
+        let (messages, replies) = collect_replies(&input);
+        assert!(!messages.is_empty());
+        for reply in replies.lines() {
+            let reply = reply.parse::<Message<ErrorReply>>().unwrap();
+            assert!(matches!(
+                reply.body.data,
+                ErrorReply::Error {
+                    code: ErrorCode::NotSupported,
+                    ..
+                }
+            ));
+        }
+    }
+
+    #[test]
+    fn test_unknown_type_gets_not_supported() {
+        let input = concat!(
+            r#"{"src":"c1","dest":"n1","body":{"type":"frobnicate","msg_id":4}}"#,
+            "\n",
+            r#"{"src":"c1","dest":"n1","body":{"type":"frobnicate"}}"#,
+            "\n",
+            r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":5}}"#,
+            "\n",
+        );
+        assert!(matches!(
+            parse_incoming::<crate::counter::CounterPayload>(input.lines().next().unwrap()),
+            Incoming::Unsupported {
+                msg_id: Some(4),
+                ..
+            }
+        ));
+        let (messages, replies) = collect_replies(input);
+        assert!(messages.is_empty());
+        assert_eq!(
+            r#"{"src":"n1","dest":"c1","body":{"type":"error","in_reply_to":4,"code":10,"text":"message type frobnicate is not supported"}}"#,
+            replies.trim_end()
+        );
+    }
+
+    #[test]
+    fn test_to_line_matches_serde_and_parsed_header() {
+        let mut stamped = Payload::new(
+            ErrorReply::Error {
+                in_reply_to: 12,
+                code: ErrorCode::Abort,
+                text: "no \"type\": 3".to_string(),
+            },
+            Some(4),
+        );
+        stamped.clock = Some(Clock {
+            lamport: 9,
+            vector: [("n1", 3)].into(),
+        });
+        let error = Message::new("n1".to_string(), "c1".to_string(), stamped);
+        let init = Message::new(
+            "c1".to_string(),
+            "n1".to_string(),
+            Payload::new(
+                Init::Init {
+                    node_id: "n1".to_string(),
+                    node_ids: vec!["n1".to_string()],
+                },
+                None,
+            ),
+        );
+        for (line, header) in [to_line(&error).unwrap(), to_line(&init).unwrap()] {
+            let parsed = body_header(&line).unwrap();
+            assert_eq!(parsed.kind, header.kind);
+            assert_eq!(parsed.in_reply_to, header.in_reply_to);
+        }
+        assert_eq!(
+            serde_json::to_string(&error).unwrap(),
+            to_line(&error).unwrap().0
+        );
+        assert_eq!(Some(12), to_line(&error).unwrap().1.in_reply_to);
+    }
+
+    #[test]
+    fn test_retry_of_dropped_request_gets_through() {
+        let clock = Arc::new(crate::scheduler::VirtualTime::<()>::default().scheduler("n1"));
+        let (outbox, replies) = Outbox::channel();
+        let outbox = outbox.with_replies(crate::dedup::ReplyCache::new(
+            16,
+            std::time::Duration::from_secs(60),
+            &["n1".to_string()],
+            clock,
+        ));
+        let queue = EventQueue::new(1, OverflowPolicy::DropNewest);
+        let init = |msg_id: usize| {
+            format!(
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"init","msg_id":{},"node_id":"n1","node_ids":["n1"]}}}}"#,
+                msg_id
+            )
+        };
+        let read = |input: String| {
+            read_messages::<Init, _>(input.as_bytes(), &queue, |m| m, &outbox, None, None).unwrap();
+            while !queue.is_empty() {
+                let message = queue.pop().unwrap();
+                message.body.data.handle(&outbox, message.clone()).unwrap();
+            }
+        };
+
+        // the second request finds the queue full and is dropped.
+        read(format!("{}\n{}\n", init(1), init(2)));
+        read(format!("{}\n", init(2)));
+        let answered = replies
+            .try_iter()
+            .map(|line| body_header(&line).unwrap().in_reply_to)
+            .collect::<Vec<_>>();
+        assert_eq!(vec![Some(1), Some(2)], answered);
+    }
+
+    #[test]
+    fn test_known_type_with_broken_body_is_malformed() {
+        let line = r#"{"src":"c1","dest":"n1","body":{"type":"add","delta":"one","msg_id":1}}"#;
+        assert!(matches!(
+            parse_incoming::<crate::counter::CounterPayload>(line),
+            Incoming::Malformed(_)
+        ));
+        let line = r#"{"src":"c1","dest":"n1","body":{"type":"subtract","delta":1,"msg_id":1}}"#;
+        assert!(matches!(
+            parse_incoming::<crate::counter::CounterPayload>(line),
+            Incoming::Unsupported { .. }
+        ));
+    }
 }
@@ -0,0 +1,284 @@
+use std::{
+    io::{self, BufWriter, Write},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use anyhow::anyhow;
+use serde::Serialize;
+
+use crate::{
+    admin::Stats,
+    clock::{Clock, NodeClock},
+    dedup::{ReplyCache, Seen},
+    message::{self, Message, Payload},
+    trace::Trace,
+};
+
+/// Maximum number of lines written between two flushes.
+const MAX_BATCH: usize = 64;
+
+/// Cloneable handle used by handlers and timer threads to emit messages.
+/// Every message is serialized into a complete line before it is queued, so
+/// lines from concurrent producers can never interleave.
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    tx: Sender<String>,
+    // shared by all clones, so every producer ticks the same node clock.
+    clock: Option<Arc<Mutex<NodeClock>>>,
+    replies: Option<Arc<Mutex<ReplyCache>>>,
+    stats: Arc<Stats>,
+    trace: Option<Trace>,
+}
+
+impl Outbox {
+    /// Outbox whose lines are delivered to the returned receiver instead of a writer.
+    pub fn channel() -> (Outbox, Receiver<String>) {
+        let (tx, rx) = channel();
+        let outbox = Outbox {
+            tx,
+            clock: None,
+            replies: None,
+            stats: Arc::default(),
+            trace: None,
+        };
+        (outbox, rx)
+    }
+
+    /// Stamps messages to other nodes with logical clocks from now on.
+    pub fn with_clock(mut self, node_id: &str, node_ids: &[String]) -> Outbox {
+        self.clock = Some(Arc::new(Mutex::new(NodeClock::new(node_id, node_ids))));
+        self
+    }
+
+    /// Keeps replies to clients in `replies` from now on, see `admit`.
+    pub fn with_replies(mut self, replies: ReplyCache) -> Outbox {
+        self.replies = Some(Arc::new(Mutex::new(replies)));
+        self
+    }
+
+    /// Whether a request read from `src` should reach the node. Retries of
+    /// an answered request get the cached reply again, retries of one still
+    /// being handled are dropped.
+    pub fn admit(&self, src: &str, msg_id: Option<usize>) -> message::Result<bool> {
+        let Some(replies) = &self.replies else {
+            return Ok(true);
+        };
+        let seen = replies
+            .lock()
+            .expect("reply cache lock poisoned")
+            .admit(src, msg_id);
+        match seen {
+            Seen::New => Ok(true),
+            Seen::InProgress => Ok(false),
+            Seen::Replied(line) => {
+                self.record_answered(&line);
+                self.send_line(line).map(|_| false)
+            }
+        }
+    }
+
+    /// Undoes `admit` for a request that was dropped before the node saw it.
+    pub fn forget(&self, src: &str, msg_id: usize) {
+        if let Some(replies) = &self.replies {
+            replies
+                .lock()
+                .expect("reply cache lock poisoned")
+                .forget(src, msg_id);
+        }
+    }
+
+    pub fn send<T: Serialize>(&self, message: &Message<T>) -> message::Result<()> {
+        self.send_marked(message, false)
+    }
+
+    /// Sends a reply the reader answers with on its own, no event leads to
+    /// it. Traces mark it so that replays do not expect it.
+    pub fn answer<T: Serialize>(&self, message: &Message<T>) -> message::Result<()> {
+        self.send_marked(message, true)
+    }
+
+    fn send_marked<T: Serialize>(
+        &self,
+        message: &Message<T>,
+        answered: bool,
+    ) -> message::Result<()> {
+        // held until the line is queued, so stamps leave in increasing order.
+        let mut clock = self.node_clock();
+        let stamp = clock.as_mut().and_then(|clock| clock.send(&message.dst));
+        let (mut line, header) = match stamp {
+            Some(stamp) => message::to_line(&Message::new(
+                message.src.clone(),
+                message.dst.clone(),
+                Payload {
+                    data: &message.body.data,
+                    msg_id: message.body.msg_id,
+                    clock: Some(stamp),
+                },
+            ))?,
+            None => message::to_line(message)?,
+        };
+        line.push('\n');
+        self.stats.record_out(&header.kind);
+        if let (Some(replies), Some(in_reply_to)) = (&self.replies, header.in_reply_to) {
+            let mut replies = replies.lock().expect("reply cache lock poisoned");
+            if replies.tracks(&message.dst) {
+                replies.reply(&message.dst, in_reply_to, &line);
+            }
+        }
+        if answered {
+            self.record_answered(&line);
+        }
+        self.send_line(line)
+    }
+
+    /// Message and queue counters of the node.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Shared handle on the counters, for producers of events.
+    pub fn stats_ref(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    /// Merges the stamp of a received message into the node clock.
+    pub fn receive(&self, stamp: Option<&Clock>) {
+        if let (Some(mut clock), Some(stamp)) = (self.node_clock(), stamp) {
+            clock.receive(stamp);
+        }
+    }
+
+    /// Current node clock, `None` unless enabled with `with_clock`.
+    pub fn clock(&self) -> Option<Clock> {
+        self.node_clock().map(|clock| clock.clock().clone())
+    }
+
+    /// Restores the node clock, replays start every event from the recorded one.
+    pub fn set_clock(&self, stamp: Clock) {
+        if let Some(mut clock) = self.node_clock() {
+            clock.set(stamp);
+        }
+    }
+
+    fn node_clock(&self) -> Option<std::sync::MutexGuard<'_, NodeClock>> {
+        self.clock
+            .as_ref()
+            .map(|clock| clock.lock().expect("clock lock poisoned"))
+    }
+
+    fn record_answered(&self, line: &str) {
+        if let Some(trace) = &self.trace {
+            trace.record_answered(line);
+        }
+    }
+
+    fn send_line(&self, line: String) -> message::Result<()> {
+        self.tx
+            .send(line)
+            .map_err(|_| anyhow!("output writer has stopped"))
+    }
+}
+
+/// Thread draining an `Outbox` into a buffered writer. The buffer is flushed
+/// once the queue runs empty or after `MAX_BATCH` lines. Written lines are
+/// also recorded when a trace is given.
+#[derive(Debug)]
+pub struct OutputWriter {
+    handler: JoinHandle<io::Result<()>>,
+}
+
+impl OutputWriter {
+    pub fn spawn<W>(writer: W, trace: Option<Trace>) -> (Outbox, OutputWriter)
+    where
+        W: Write + Send + 'static,
+    {
+        let (mut outbox, rx) = Outbox::channel();
+        outbox.trace = trace.clone();
+        let handler = thread::spawn(move || {
+            let mut writer = BufWriter::new(writer);
+            let write_line = |writer: &mut BufWriter<W>, line: String| {
+                if let Some(trace) = &trace {
+                    trace.record_out(&line);
+                }
+                writer.write_all(line.as_bytes())
+            };
+            while let Ok(line) = rx.recv() {
+                write_line(&mut writer, line)?;
+                for line in rx.try_iter().take(MAX_BATCH - 1) {
+                    write_line(&mut writer, line)?;
+                }
+                writer.flush()?;
+            }
+            writer.flush()
+        });
+        (outbox, OutputWriter { handler })
+    }
+
+    /// Waits until every `Outbox` is dropped and all queued lines are written.
+    pub fn join(self) -> message::Result<()> {
+        self.handler
+            .join()
+            .map_err(|_| anyhow!("output writer panicked"))??;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::message::{Init, Payload};
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        // tiny writes make any interleaving of partial lines visible.
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let written = buf.len().min(3);
+            self.0.lock().unwrap().extend_from_slice(&buf[..written]);
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_concurrent_producers_write_whole_lines() {
+        let buffer = SharedBuffer::default();
+        let (outbox, writer) = OutputWriter::spawn(buffer.clone(), None);
+        let producers = (0..8)
+            .map(|producer| {
+                let outbox = outbox.clone();
+                thread::spawn(move || {
+                    for count in 0..200 {
+                        let message = Message::new(
+                            format!("n{}", producer),
+                            "c1".to_string(),
+                            Payload::new(Init::InitOk { in_reply_to: count }, Some(count)),
+                        );
+                        outbox.send(&message).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(outbox);
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        writer.join().unwrap();
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(8 * 200, output.lines().count());
+        for line in output.lines() {
+            serde_json::from_str::<serde_json::Value>(line).unwrap();
+        }
+    }
+}
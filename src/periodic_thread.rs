@@ -28,8 +28,8 @@ impl PeriodicThread {
             if terminate_clone.load(std::sync::atomic::Ordering::Acquire) {
                 break;
             }
-            if let Err(_) = task() {
-                println!("Terminating Periodic thread with error");
+            if task().is_err() {
+                eprintln!("Terminating Periodic thread with error");
                 break;
             }
             thread::park_timeout(period);
@@ -48,8 +48,8 @@ impl Drop for PeriodicThread {
             .store(true, std::sync::atomic::Ordering::Release);
         if let Some(handler) = self.handler.take() {
             handler.thread().unpark();
-            if let Err(_) = handler.join() {
-                println!("Periodic thread join ended with error");
+            if handler.join().is_err() {
+                eprintln!("Periodic thread join ended with error");
             }
         }
     }
@@ -0,0 +1,244 @@
+#![allow(dead_code)]
+//! Relays client requests a node does not serve to the node that does, such
+//! as the leader or the owner of a key. The request goes out with a fresh
+//! `msg_id` and the reply comes back to the client with its own `msg_id` as
+//! `in_reply_to`. Without a reply by `timeout` the client gets a `timeout`
+//! error, the request may or may not have taken effect.
+use std::{collections::HashMap, time::Duration};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    message::{self, ErrorCode, ErrorReply, Message, Payload},
+    output::Outbox,
+    scheduler::{SchedulerRef, Timer},
+};
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProxyMessage {
+    // timer event a node sends itself.
+    ForwardTimeout { forwarded: usize },
+}
+
+/// Bodies answering a request, the proxy rewrites their `in_reply_to`.
+pub trait Reply: Sized {
+    fn in_reply_to(&self) -> Option<usize>;
+
+    fn with_in_reply_to(self, in_reply_to: usize) -> Self;
+}
+
+/// Request waiting for the reply of the node it went to.
+#[derive(Debug)]
+struct Forwarded {
+    client: String,
+    msg_id: usize,
+    timeout: Timer,
+}
+
+/// Forwarded requests of one node by the `msg_id` they went out with.
+/// Timeouts are scheduled as messages of type `E` the node sends itself and
+/// must hand back to `handle`.
+pub struct Proxy<E> {
+    node_id: String,
+    timeout: Duration,
+    scheduler: SchedulerRef<E>,
+    forwarded: HashMap<usize, Forwarded>,
+    next_msg_id: usize,
+}
+
+impl<E> Proxy<E>
+where
+    E: From<Message<ProxyMessage>> + Send + 'static,
+{
+    pub fn new(node_id: String, timeout: Duration, scheduler: SchedulerRef<E>) -> Self {
+        Proxy {
+            node_id,
+            timeout,
+            scheduler,
+            forwarded: HashMap::new(),
+            next_msg_id: 0,
+        }
+    }
+
+    /// Sends `request` to `to` on behalf of the request `msg_id` of `client`.
+    pub fn forward<T: Serialize>(
+        &mut self,
+        outbox: &Outbox,
+        client: String,
+        msg_id: usize,
+        to: String,
+        request: T,
+    ) -> message::Result<()> {
+        self.next_msg_id += 1;
+        let forwarded = self.next_msg_id;
+        let timeout = self.scheduler.after(
+            self.timeout,
+            E::from(Message::new(
+                self.node_id.clone(),
+                self.node_id.clone(),
+                Payload::new(ProxyMessage::ForwardTimeout { forwarded }, None),
+            )),
+        );
+        self.forwarded.insert(
+            forwarded,
+            Forwarded {
+                client,
+                msg_id,
+                timeout,
+            },
+        );
+        outbox.send(&Message::new(
+            self.node_id.clone(),
+            to,
+            Payload::new(request, Some(forwarded)),
+        ))
+    }
+
+    /// Passes a reply on to the client that asked. Replies to requests that
+    /// timed out or were never forwarded are dropped.
+    pub fn relay<T: Reply + Serialize>(
+        &mut self,
+        outbox: &Outbox,
+        reply: T,
+    ) -> message::Result<()> {
+        let forwarded = reply
+            .in_reply_to()
+            .and_then(|in_reply_to| self.forwarded.remove(&in_reply_to));
+        let Some(Forwarded { client, msg_id, .. }) = forwarded else {
+            return Ok(());
+        };
+        outbox.send(&Message::new(
+            self.node_id.clone(),
+            client,
+            Payload::new(reply.with_in_reply_to(msg_id), None),
+        ))
+    }
+
+    pub fn handle(
+        &mut self,
+        outbox: &Outbox,
+        message: Message<ProxyMessage>,
+    ) -> message::Result<()> {
+        let ProxyMessage::ForwardTimeout { forwarded } = message.body.data;
+        let Some(Forwarded { client, msg_id, .. }) = self.forwarded.remove(&forwarded) else {
+            return Ok(());
+        };
+        let error = ErrorReply::Error {
+            in_reply_to: msg_id,
+            code: ErrorCode::Timeout,
+            text: format!(
+                "no reply to the forwarded request within {:?}",
+                self.timeout
+            ),
+        };
+        outbox.send(&Message::new(
+            self.node_id.clone(),
+            client,
+            Payload::new(error, None),
+        ))
+    }
+
+    /// Requests still waiting for a reply.
+    pub fn len(&self) -> usize {
+        self.forwarded.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.forwarded.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc::Receiver, Arc};
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{lin_kv::LinKv, scheduler::VirtualTime};
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    fn proxy() -> (
+        VirtualTime<Message<ProxyMessage>>,
+        Proxy<Message<ProxyMessage>>,
+    ) {
+        let time = VirtualTime::default();
+        let proxy = Proxy::new("n1".to_string(), TIMEOUT, Arc::new(time.scheduler("n1")));
+        (time, proxy)
+    }
+
+    fn sent(lines: &Receiver<String>) -> Vec<Message<Value>> {
+        lines
+            .try_iter()
+            .map(|line| line.parse::<Message<Value>>().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_forwarded_request_gets_fresh_id_and_reply_goes_back() {
+        let (_time, mut proxy) = proxy();
+        let (outbox, lines) = Outbox::channel();
+        for client in ["c1", "c2"] {
+            let read = LinKv::Read { key: json!(1) };
+            proxy
+                .forward(&outbox, client.to_string(), 7, "n2".to_string(), read)
+                .unwrap();
+        }
+        let forwarded = sent(&lines);
+        assert_eq!(2, forwarded.len());
+        assert!(forwarded.iter().all(|message| message.dst == "n2"));
+        // the clients both used 7, the leader must tell the two apart.
+        let ids = forwarded
+            .iter()
+            .map(|message| message.body.msg_id.unwrap())
+            .collect::<Vec<_>>();
+        assert_ne!(ids[0], ids[1]);
+        assert!(!ids.contains(&7));
+
+        let reply = LinKv::ReadOk {
+            value: json!(3),
+            in_reply_to: ids[1],
+        };
+        proxy.relay(&outbox, reply).unwrap();
+        let relayed = sent(&lines);
+        assert_eq!(1, relayed.len());
+        assert_eq!(("n1", "c2"), (&*relayed[0].src, &*relayed[0].dst));
+        assert_eq!(json!(7), relayed[0].body.data["in_reply_to"]);
+        assert_eq!(json!(3), relayed[0].body.data["value"]);
+        assert_eq!(1, proxy.len());
+    }
+
+    #[test]
+    fn test_timeout_answers_client_and_drops_late_reply() {
+        let (time, mut proxy) = proxy();
+        let (outbox, lines) = Outbox::channel();
+        let read = LinKv::Read { key: json!(1) };
+        proxy
+            .forward(&outbox, "c1".to_string(), 7, "n2".to_string(), read)
+            .unwrap();
+        let forwarded = sent(&lines)[0].body.msg_id.unwrap();
+
+        time.advance_to(TIMEOUT);
+        let (_, timeout) = time.pop_due().unwrap();
+        proxy.handle(&outbox, timeout).unwrap();
+        let answered = sent(&lines);
+        assert_eq!(1, answered.len());
+        assert_eq!("c1", answered[0].dst);
+        let error = &answered[0].body.data;
+        assert_eq!(
+            (&json!("error"), &json!(0), &json!(7)),
+            (&error["type"], &error["code"], &error["in_reply_to"])
+        );
+
+        let late = LinKv::ReadOk {
+            value: json!(3),
+            in_reply_to: forwarded,
+        };
+        proxy.relay(&outbox, late).unwrap();
+        assert!(sent(&lines).is_empty());
+        assert!(proxy.is_empty());
+    }
+}
@@ -0,0 +1,813 @@
+#![allow(dead_code)]
+//! Raft consensus over Maelstrom messages: leader election with randomized
+//! timeouts, log replication with `append_entries` and commit advancement.
+//! A node embeds a `Raft`, feeds it every `RaftMessage` it receives and gets
+//! back the entries committed and applied to its `StateMachine`.
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Debug,
+    time::Duration,
+};
+
+use schemars::JsonSchema;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    config::Config,
+    message::{self, Message, Payload},
+    output::Outbox,
+    scheduler::{SchedulerRef, Timer},
+};
+
+/// Application state replicated by Raft, every node applies the committed
+/// commands in log order.
+pub trait StateMachine: Send + 'static {
+    type Command: Serialize + DeserializeOwned + Clone + Debug + Send + 'static;
+    type Output;
+
+    fn apply(&mut self, command: &Self::Command) -> Self::Output;
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq)]
+pub struct Entry<C> {
+    pub term: u64,
+    /// `None` is the no-op a new leader appends to commit entries of earlier terms.
+    pub command: Option<C>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RaftMessage<C> {
+    RequestVote {
+        term: u64,
+        candidate_id: String,
+        last_log_index: usize,
+        last_log_term: u64,
+    },
+    RequestVoteOk {
+        term: u64,
+        vote_granted: bool,
+        in_reply_to: usize,
+    },
+    AppendEntries {
+        term: u64,
+        leader_id: String,
+        prev_log_index: usize,
+        prev_log_term: u64,
+        entries: Vec<Entry<C>>,
+        leader_commit: usize,
+    },
+    /// `match_index` is the last replicated index on success and a hint
+    /// where to retry from otherwise.
+    AppendEntriesOk {
+        term: u64,
+        success: bool,
+        match_index: usize,
+        in_reply_to: usize,
+    },
+    // timer events a node sends itself.
+    ElectionTimeout {
+        epoch: u64,
+    },
+    Heartbeat,
+}
+
+impl<C> RaftMessage<C> {
+    fn term(&self) -> Option<u64> {
+        match self {
+            RaftMessage::RequestVote { term, .. }
+            | RaftMessage::RequestVoteOk { term, .. }
+            | RaftMessage::AppendEntries { term, .. }
+            | RaftMessage::AppendEntriesOk { term, .. } => Some(*term),
+            RaftMessage::ElectionTimeout { .. } | RaftMessage::Heartbeat => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RaftConfig {
+    /// Followers wait between one and two times this for a leader.
+    pub election_timeout: Duration,
+    pub heartbeat_interval: Duration,
+    /// Most entries sent in one `append_entries`.
+    pub max_entries: usize,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        Config::default().raft()
+    }
+}
+
+/// Entry committed and applied, `output` is `None` for no-ops.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Applied<O> {
+    pub index: usize,
+    pub term: u64,
+    pub output: Option<O>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Proposal {
+    /// Appended to the leader log, committed once `Applied` with the same
+    /// index and term comes back. Another term at that index means it was lost.
+    Accepted {
+        index: usize,
+        term: u64,
+    },
+    NotLeader {
+        leader: Option<String>,
+    },
+}
+
+/// Raft state of one node. Timer events are scheduled as messages of type
+/// `E` the node sends itself and must hand back to `handle`.
+pub struct Raft<S: StateMachine, E> {
+    node_id: String,
+    peers: Vec<String>,
+    config: RaftConfig,
+    scheduler: SchedulerRef<E>,
+    role: Role,
+    current_term: u64,
+    voted_for: Option<String>,
+    leader_id: Option<String>,
+    // entry of index i is at i - 1.
+    log: Vec<Entry<S::Command>>,
+    commit_index: usize,
+    last_applied: usize,
+    state_machine: S,
+    votes: BTreeSet<String>,
+    next_index: HashMap<String, usize>,
+    match_index: HashMap<String, usize>,
+    // timeouts of an earlier epoch were cancelled but may already be queued.
+    election_epoch: u64,
+    election_timer: Option<Timer>,
+    heartbeat_timer: Option<Timer>,
+    next_msg_id: usize,
+}
+
+impl<S, E> Raft<S, E>
+where
+    S: StateMachine,
+    E: From<Message<RaftMessage<S::Command>>> + Send + 'static,
+{
+    pub fn new(
+        node_id: String,
+        node_ids: Vec<String>,
+        state_machine: S,
+        config: RaftConfig,
+        scheduler: SchedulerRef<E>,
+    ) -> Self {
+        let peers = node_ids.into_iter().filter(|id| *id != node_id).collect();
+        let mut raft = Raft {
+            node_id,
+            peers,
+            config,
+            scheduler,
+            role: Role::Follower,
+            current_term: 0,
+            voted_for: None,
+            leader_id: None,
+            log: Vec::new(),
+            commit_index: 0,
+            last_applied: 0,
+            state_machine,
+            votes: BTreeSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            election_epoch: 0,
+            election_timer: None,
+            heartbeat_timer: None,
+            next_msg_id: 0,
+        };
+        raft.reset_election_timer();
+        raft
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.role == Role::Leader
+    }
+
+    pub fn term(&self) -> u64 {
+        self.current_term
+    }
+
+    pub fn leader_id(&self) -> Option<&str> {
+        self.leader_id.as_deref()
+    }
+
+    pub fn commit_index(&self) -> usize {
+        self.commit_index
+    }
+
+    pub fn log(&self) -> &[Entry<S::Command>] {
+        &self.log
+    }
+
+    pub fn state_machine(&self) -> &S {
+        &self.state_machine
+    }
+
+    /// Cancels the timers, the node stops taking part in elections.
+    pub fn stop(&mut self) {
+        self.election_timer = None;
+        self.heartbeat_timer = None;
+    }
+
+    /// Appends `command` to the log when this node leads.
+    pub fn propose(&mut self, outbox: &Outbox, command: S::Command) -> message::Result<Proposal> {
+        if self.role != Role::Leader {
+            return Ok(Proposal::NotLeader {
+                leader: self.leader_id.clone(),
+            });
+        }
+        self.log.push(Entry {
+            term: self.current_term,
+            command: Some(command),
+        });
+        let index = self.last_log_index();
+        // followers get the entry right away instead of on the next heartbeat.
+        self.replicate(outbox)?;
+        Ok(Proposal::Accepted {
+            index,
+            term: self.current_term,
+        })
+    }
+
+    /// Handles one Raft message or timer event and returns what got applied.
+    pub fn handle(
+        &mut self,
+        outbox: &Outbox,
+        message: Message<RaftMessage<S::Command>>,
+    ) -> message::Result<Vec<Applied<S::Output>>> {
+        if let Some(term) = message.body.data.term() {
+            if term > self.current_term {
+                self.step_down(term);
+            }
+        }
+        let in_reply_to = message.body.msg_id.unwrap_or(0);
+        match message.body.data {
+            RaftMessage::ElectionTimeout { epoch } => {
+                if epoch == self.election_epoch && self.role != Role::Leader {
+                    self.start_election(outbox)?;
+                }
+            }
+            RaftMessage::Heartbeat => {
+                if self.role == Role::Leader {
+                    self.replicate(outbox)?;
+                }
+            }
+            RaftMessage::RequestVote {
+                term,
+                candidate_id,
+                last_log_index,
+                last_log_term,
+            } => {
+                let up_to_date = (last_log_term, last_log_index)
+                    >= (self.term_at(self.last_log_index()), self.last_log_index());
+                let vote_granted = term == self.current_term
+                    && up_to_date
+                    && self
+                        .voted_for
+                        .as_ref()
+                        .is_none_or(|voted_for| *voted_for == candidate_id);
+                if vote_granted {
+                    self.voted_for = Some(candidate_id);
+                    self.reset_election_timer();
+                }
+                let vote = RaftMessage::RequestVoteOk {
+                    term: self.current_term,
+                    vote_granted,
+                    in_reply_to,
+                };
+                self.send(outbox, &message.src, vote)?;
+            }
+            RaftMessage::RequestVoteOk {
+                term, vote_granted, ..
+            } => {
+                if self.role == Role::Candidate && term == self.current_term && vote_granted {
+                    self.votes.insert(message.src);
+                    if self.votes.len() >= self.quorum() {
+                        self.become_leader(outbox)?;
+                    }
+                }
+            }
+            RaftMessage::AppendEntries {
+                term,
+                leader_id,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => {
+                let reply = if term < self.current_term {
+                    RaftMessage::AppendEntriesOk {
+                        term: self.current_term,
+                        success: false,
+                        match_index: 0,
+                        in_reply_to,
+                    }
+                } else {
+                    self.follow(leader_id);
+                    let (success, match_index) =
+                        self.append(prev_log_index, prev_log_term, entries, leader_commit);
+                    RaftMessage::AppendEntriesOk {
+                        term: self.current_term,
+                        success,
+                        match_index,
+                        in_reply_to,
+                    }
+                };
+                self.send(outbox, &message.src, reply)?;
+            }
+            RaftMessage::AppendEntriesOk {
+                term,
+                success,
+                match_index,
+                ..
+            } => {
+                if self.role == Role::Leader && term == self.current_term {
+                    let peer = message.src;
+                    let matched = self.match_index.entry(peer.clone()).or_default();
+                    if success {
+                        *matched = (*matched).max(match_index);
+                        self.advance_commit();
+                    } else {
+                        // rewind, but never behind what the peer already confirmed.
+                        let next = match_index.max(*matched) + 1;
+                        self.next_index.insert(peer.clone(), next);
+                        self.send_append_entries(outbox, &peer)?;
+                    }
+                }
+            }
+        }
+        Ok(self.apply())
+    }
+
+    fn last_log_index(&self) -> usize {
+        self.log.len()
+    }
+
+    fn term_at(&self, index: usize) -> u64 {
+        index
+            .checked_sub(1)
+            .and_then(|position| self.log.get(position))
+            .map_or(0, |entry| entry.term)
+    }
+
+    fn quorum(&self) -> usize {
+        let cluster = self.peers.len() + 1;
+        cluster / 2 + 1
+    }
+
+    fn timer_event(node_id: &str, data: RaftMessage<S::Command>) -> E {
+        E::from(Message::new(
+            node_id.to_string(),
+            node_id.to_string(),
+            Payload::new(data, None),
+        ))
+    }
+
+    fn send(
+        &mut self,
+        outbox: &Outbox,
+        dest: &str,
+        data: RaftMessage<S::Command>,
+    ) -> message::Result<()> {
+        self.next_msg_id += 1;
+        outbox.send(&Message::new(
+            self.node_id.clone(),
+            dest.to_string(),
+            Payload::new(data, Some(self.next_msg_id)),
+        ))
+    }
+
+    fn reset_election_timer(&mut self) {
+        self.election_epoch += 1;
+        let timeout = self.config.election_timeout.as_micros().max(1) as u64;
+        let delay = Duration::from_micros(timeout + self.scheduler.random() % timeout);
+        let event = Self::timer_event(
+            &self.node_id,
+            RaftMessage::ElectionTimeout {
+                epoch: self.election_epoch,
+            },
+        );
+        // replacing the timer cancels the previous one.
+        self.election_timer = Some(self.scheduler.after(delay, event));
+    }
+
+    fn step_down(&mut self, term: u64) {
+        let was_leader = self.role == Role::Leader;
+        self.current_term = term;
+        self.voted_for = None;
+        self.leader_id = None;
+        self.role = Role::Follower;
+        self.votes.clear();
+        self.heartbeat_timer = None;
+        if was_leader {
+            self.reset_election_timer();
+        }
+    }
+
+    /// Accepts `leader_id` as the leader of the current term.
+    fn follow(&mut self, leader_id: String) {
+        if self.role != Role::Follower {
+            self.role = Role::Follower;
+            self.votes.clear();
+            self.heartbeat_timer = None;
+        }
+        self.leader_id = Some(leader_id);
+        self.reset_election_timer();
+    }
+
+    fn start_election(&mut self, outbox: &Outbox) -> message::Result<()> {
+        self.role = Role::Candidate;
+        self.current_term += 1;
+        self.voted_for = Some(self.node_id.clone());
+        self.leader_id = None;
+        self.votes = BTreeSet::from([self.node_id.clone()]);
+        self.reset_election_timer();
+        if self.votes.len() >= self.quorum() {
+            return self.become_leader(outbox);
+        }
+        for peer in self.peers.clone() {
+            let request = RaftMessage::RequestVote {
+                term: self.current_term,
+                candidate_id: self.node_id.clone(),
+                last_log_index: self.last_log_index(),
+                last_log_term: self.term_at(self.last_log_index()),
+            };
+            self.send(outbox, &peer, request)?;
+        }
+        Ok(())
+    }
+
+    fn become_leader(&mut self, outbox: &Outbox) -> message::Result<()> {
+        self.role = Role::Leader;
+        self.leader_id = Some(self.node_id.clone());
+        self.election_timer = None;
+        self.votes.clear();
+        let next = self.last_log_index() + 1;
+        self.next_index = self.peers.iter().map(|peer| (peer.clone(), next)).collect();
+        self.match_index = self.peers.iter().map(|peer| (peer.clone(), 0)).collect();
+        self.log.push(Entry {
+            term: self.current_term,
+            command: None,
+        });
+        let node_id = self.node_id.clone();
+        self.heartbeat_timer = Some(self.scheduler.every(
+            self.config.heartbeat_interval,
+            Box::new(move || Self::timer_event(&node_id, RaftMessage::Heartbeat)),
+        ));
+        self.replicate(outbox)
+    }
+
+    fn replicate(&mut self, outbox: &Outbox) -> message::Result<()> {
+        for peer in self.peers.clone() {
+            self.send_append_entries(outbox, &peer)?;
+        }
+        // without peers the leader alone is a majority.
+        self.advance_commit();
+        Ok(())
+    }
+
+    fn send_append_entries(&mut self, outbox: &Outbox, peer: &str) -> message::Result<()> {
+        let last = self.last_log_index();
+        let next = self.next_index.get(peer).copied().unwrap_or(last + 1);
+        let prev_log_index = next.clamp(1, last + 1) - 1;
+        let entries = self.log[prev_log_index..]
+            .iter()
+            .take(self.config.max_entries)
+            .cloned()
+            .collect::<Vec<_>>();
+        // assume delivery, a failed reply rewinds next_index again.
+        self.next_index
+            .insert(peer.to_string(), prev_log_index + entries.len() + 1);
+        let request = RaftMessage::AppendEntries {
+            term: self.current_term,
+            leader_id: self.node_id.clone(),
+            prev_log_index,
+            prev_log_term: self.term_at(prev_log_index),
+            entries,
+            leader_commit: self.commit_index,
+        };
+        self.send(outbox, peer, request)
+    }
+
+    /// Follower side of `append_entries`, returns success and match index.
+    fn append(
+        &mut self,
+        prev_log_index: usize,
+        prev_log_term: u64,
+        entries: Vec<Entry<S::Command>>,
+        leader_commit: usize,
+    ) -> (bool, usize) {
+        if prev_log_index > self.last_log_index() || self.term_at(prev_log_index) != prev_log_term {
+            let hint = prev_log_index.saturating_sub(1).min(self.last_log_index());
+            return (false, hint);
+        }
+        let matched = prev_log_index + entries.len();
+        for (index, entry) in (prev_log_index + 1..).zip(entries) {
+            if index <= self.last_log_index() {
+                if self.term_at(index) == entry.term {
+                    continue;
+                }
+                // a conflicting suffix was never committed, it can go.
+                self.log.truncate(index - 1);
+            }
+            self.log.push(entry);
+        }
+        self.commit_index = self.commit_index.max(leader_commit.min(matched));
+        (true, matched)
+    }
+
+    fn advance_commit(&mut self) {
+        // terms only grow along the log, so only entries of the current
+        // term at the end can be committed by counting replicas.
+        for index in (self.commit_index + 1..=self.last_log_index()).rev() {
+            if self.term_at(index) != self.current_term {
+                break;
+            }
+            let replicas = 1 + self
+                .match_index
+                .values()
+                .filter(|matched| **matched >= index)
+                .count();
+            if replicas >= self.quorum() {
+                self.commit_index = index;
+                break;
+            }
+        }
+    }
+
+    fn apply(&mut self) -> Vec<Applied<S::Output>> {
+        let mut applied = Vec::new();
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+            let entry = &self.log[self.last_applied - 1];
+            applied.push(Applied {
+                index: self.last_applied,
+                term: entry.term,
+                output: entry
+                    .command
+                    .as_ref()
+                    .map(|command| self.state_machine.apply(command)),
+            });
+        }
+        applied
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Mutex, time::Duration};
+
+    use super::*;
+    use crate::{
+        config::Config,
+        message::{BodyTypes, Handler},
+        runtime::Node,
+        sim::{Rng, Simulation},
+    };
+
+    /// Remembers every applied command in order.
+    #[derive(Debug, Default)]
+    struct Appender(Vec<i64>);
+
+    impl StateMachine for Appender {
+        type Command = i64;
+        type Output = ();
+
+        fn apply(&mut self, command: &i64) {
+            self.0.push(*command);
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Client {
+        Propose { value: i64 },
+    }
+
+    #[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+    #[serde(untagged)]
+    enum TestPayload {
+        Raft(RaftMessage<i64>),
+        Client(Client),
+    }
+
+    impl BodyTypes for TestPayload {
+        const TYPES: &'static [&'static str] = &[
+            "request_vote",
+            "request_vote_ok",
+            "append_entries",
+            "append_entries_ok",
+            "election_timeout",
+            "heartbeat",
+            "propose",
+        ];
+    }
+
+    impl From<Message<RaftMessage<i64>>> for Message<TestPayload> {
+        fn from(message: Message<RaftMessage<i64>>) -> Self {
+            message.map(TestPayload::Raft)
+        }
+    }
+
+    struct RaftNode {
+        raft: Mutex<Raft<Appender, Message<TestPayload>>>,
+    }
+
+    impl Node for RaftNode {
+        type Payload = TestPayload;
+        type Event = Message<TestPayload>;
+
+        fn from_init(
+            node_id: String,
+            node_ids: Vec<String>,
+            scheduler: SchedulerRef<Self::Event>,
+            _config: &Config,
+        ) -> Self {
+            let raft = Raft::new(
+                node_id,
+                node_ids,
+                Appender::default(),
+                RaftConfig::default(),
+                scheduler,
+            );
+            RaftNode {
+                raft: Mutex::new(raft),
+            }
+        }
+
+        fn ordering_key(_event: &Self::Event) -> Option<&str> {
+            None
+        }
+    }
+
+    impl Handler<Message<TestPayload>> for RaftNode {
+        fn handle(&self, outbox: &Outbox, message: Message<TestPayload>) -> message::Result<()> {
+            let mut raft = self.raft.lock().unwrap();
+            let Message { src, dst, body } = message;
+            match body.data {
+                TestPayload::Raft(data) => {
+                    raft.handle(
+                        outbox,
+                        Message::new(src, dst, Payload::new(data, body.msg_id)),
+                    )?;
+                }
+                // followers drop proposals, like a client timing out.
+                TestPayload::Client(Client::Propose { value }) => {
+                    raft.propose(outbox, value)?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    const NODES: [&str; 5] = ["n1", "n2", "n3", "n4", "n5"];
+
+    fn cluster(seed: u64) -> Simulation<RaftNode> {
+        Simulation::new(&NODES, seed)
+    }
+
+    fn leader(sim: &Simulation<RaftNode>) -> Option<(String, u64)> {
+        NODES
+            .iter()
+            .filter_map(|node| {
+                let raft = sim.node(node)?.raft.lock().unwrap();
+                raft.is_leader().then(|| (node.to_string(), raft.term()))
+            })
+            .max_by_key(|(_, term)| *term)
+    }
+
+    fn applied(sim: &Simulation<RaftNode>, node: &str) -> Vec<i64> {
+        sim.node(node)
+            .unwrap()
+            .raft
+            .lock()
+            .unwrap()
+            .state_machine()
+            .0
+            .clone()
+    }
+
+    fn propose(sim: &mut Simulation<RaftNode>, node: &str, value: i64) {
+        sim.client_send("c1", node, TestPayload::Client(Client::Propose { value }))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_elects_one_leader_everyone_follows() {
+        let mut sim = cluster(1);
+        sim.run_for(Duration::from_secs(2)).unwrap();
+        let (leader, term) = leader(&sim).unwrap();
+        for node in NODES {
+            let raft = sim.node(node).unwrap().raft.lock().unwrap();
+            assert_eq!(term, raft.term(), "{}", node);
+            assert_eq!(Some(leader.as_str()), raft.leader_id(), "{}", node);
+            assert_eq!(node == leader, raft.is_leader());
+        }
+    }
+
+    #[test]
+    fn test_committed_entries_are_applied_everywhere_in_order() {
+        let mut sim = cluster(2);
+        sim.run_for(Duration::from_secs(2)).unwrap();
+        let (leader, _) = leader(&sim).unwrap();
+        for value in 1..=20 {
+            propose(&mut sim, &leader, value);
+        }
+        sim.run_for(Duration::from_secs(1)).unwrap();
+        // proposals reach the leader in random order, the log fixes one.
+        let expected = applied(&sim, &leader);
+        let mut values = expected.clone();
+        values.sort();
+        assert_eq!((1..=20).collect::<Vec<_>>(), values);
+        for node in NODES {
+            assert_eq!(expected, applied(&sim, node), "{}", node);
+        }
+    }
+
+    #[test]
+    fn test_partitioned_leader_is_replaced_and_loses_uncommitted_entries() {
+        let mut sim = cluster(3);
+        sim.run_for(Duration::from_secs(2)).unwrap();
+        let (old_leader, old_term) = leader(&sim).unwrap();
+        let minority = [
+            old_leader.as_str(),
+            NODES.iter().find(|n| **n != old_leader).unwrap(),
+        ];
+        let majority = NODES
+            .iter()
+            .copied()
+            .filter(|node| !minority.contains(node))
+            .collect::<Vec<_>>();
+        sim.partition(&[&minority, &majority]);
+        propose(&mut sim, &old_leader, 100);
+        sim.run_for(Duration::from_secs(2)).unwrap();
+
+        let (new_leader, new_term) = leader(&sim).unwrap();
+        assert!(majority.contains(&new_leader.as_str()));
+        assert!(new_term > old_term);
+        propose(&mut sim, &new_leader, 200);
+        sim.run_for(Duration::from_secs(1)).unwrap();
+        assert!(applied(&sim, &old_leader).is_empty());
+
+        sim.heal();
+        sim.run_for(Duration::from_secs(2)).unwrap();
+        let old = sim.node(&old_leader).unwrap().raft.lock().unwrap();
+        assert_eq!(Role::Follower, old.role());
+        assert_eq!(Some(new_leader.as_str()), old.leader_id());
+        drop(old);
+        for node in NODES {
+            assert_eq!(vec![200], applied(&sim, node), "{}", node);
+        }
+    }
+
+    #[test]
+    fn test_random_partitions_keep_logs_consistent() {
+        for seed in 0..8 {
+            let mut sim = cluster(seed).with_max_latency(Duration::from_millis(40));
+            let mut rng = Rng::new(seed);
+            let mut leaders = HashMap::new();
+            for round in 0..20 {
+                let mut groups = [Vec::new(), Vec::new()];
+                for node in NODES {
+                    groups[rng.below(2) as usize].push(node);
+                }
+                sim.partition(&[&groups[0], &groups[1]]);
+                for value in 0..3 {
+                    let node = NODES[rng.below(NODES.len() as u64) as usize];
+                    propose(&mut sim, node, round * 10 + value);
+                }
+                sim.run_for(Duration::from_millis(400)).unwrap();
+                for node in NODES {
+                    let raft = sim.node(node).unwrap().raft.lock().unwrap();
+                    if raft.is_leader() {
+                        let elected = leaders.entry(raft.term()).or_insert(node);
+                        assert_eq!(*elected, node, "two leaders in term {}", raft.term());
+                    }
+                }
+            }
+            sim.heal();
+            sim.run_for(Duration::from_secs(3)).unwrap();
+            let expected = applied(&sim, "n1");
+            assert!(!expected.is_empty(), "seed {} committed nothing", seed);
+            for node in NODES {
+                assert_eq!(expected, applied(&sim, node), "seed {} {}", seed, node);
+            }
+        }
+    }
+}
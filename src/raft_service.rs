@@ -0,0 +1,254 @@
+#![allow(dead_code)]
+//! Serves a `StateMachine` replicated with Raft to Maelstrom clients. The
+//! leader turns every request into a log entry and answers it once applied,
+//! other nodes relay requests to the leader through a `Proxy`. A workload
+//! only brings its store and how its client bodies map to commands and
+//! replies.
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    sync::{Mutex, MutexGuard},
+    time::Duration,
+};
+
+use schemars::JsonSchema;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    config::Config,
+    message::{self, BodyTypes, ErrorCode, Handler, Message, Payload},
+    output::Outbox,
+    proxy::{Proxy, ProxyMessage, Reply},
+    raft::{Applied, Proposal, Raft, RaftConfig, RaftMessage, StateMachine},
+    runtime::Node,
+    scheduler::SchedulerRef,
+};
+
+/// Store served by a `RaftService`, with the client bodies carrying its
+/// commands and results.
+pub trait Replicated: StateMachine + Default {
+    type Body: Reply + Serialize + DeserializeOwned + Clone + Debug + Send + 'static;
+
+    /// Error answering a request whose log slot another leader filled.
+    const LOST_COMMIT: ErrorCode;
+
+    /// Command of a client request, answers of the leader come back as they are.
+    fn command(body: Self::Body) -> Result<Self::Command, Self::Body>;
+
+    /// Request carrying `command`, forwarded to the leader.
+    fn request(command: Self::Command) -> Self::Body;
+
+    fn reply(output: Self::Output, in_reply_to: usize) -> Self::Body;
+
+    fn error(code: ErrorCode, text: &str, in_reply_to: usize) -> Self::Body;
+}
+
+/// Client requests and Raft traffic share the node input.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ServicePayload<B, C> {
+    Client(B),
+    Raft(RaftMessage<C>),
+    Proxy(ProxyMessage),
+}
+
+impl<B, C> From<Message<RaftMessage<C>>> for Message<ServicePayload<B, C>> {
+    fn from(message: Message<RaftMessage<C>>) -> Self {
+        message.map(ServicePayload::Raft)
+    }
+}
+
+impl<B, C> From<Message<ProxyMessage>> for Message<ServicePayload<B, C>> {
+    fn from(message: Message<ProxyMessage>) -> Self {
+        message.map(ServicePayload::Proxy)
+    }
+}
+
+type Event<S> = Message<ServicePayload<<S as Replicated>::Body, <S as StateMachine>::Command>>;
+
+/// Request waiting for its log entry to be applied.
+#[derive(Debug)]
+struct Pending {
+    term: u64,
+    client: String,
+    msg_id: usize,
+}
+
+struct ServiceState<S: Replicated> {
+    raft: Raft<S, Event<S>>,
+    // by log index.
+    pending: HashMap<usize, Pending>,
+    // requests relayed to the leader.
+    proxy: Proxy<Event<S>>,
+}
+
+pub struct RaftService<S: Replicated> {
+    node_id: String,
+    state: Mutex<ServiceState<S>>,
+}
+
+impl<S: Replicated> RaftService<S> {
+    pub fn new(
+        node_id: String,
+        node_ids: Vec<String>,
+        config: RaftConfig,
+        forward_timeout: Duration,
+        scheduler: SchedulerRef<Event<S>>,
+    ) -> Self {
+        let proxy = Proxy::new(node_id.clone(), forward_timeout, scheduler.clone());
+        let raft = Raft::new(node_id.clone(), node_ids, S::default(), config, scheduler);
+        RaftService {
+            node_id,
+            state: Mutex::new(ServiceState {
+                raft,
+                pending: HashMap::new(),
+                proxy,
+            }),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, ServiceState<S>> {
+        self.state.lock().expect("raft service state lock poisoned")
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.state().raft.is_leader()
+    }
+
+    /// Reads the store as applied on this node.
+    pub fn read<T>(&self, read: impl FnOnce(&S) -> T) -> T {
+        read(self.state().raft.state_machine())
+    }
+
+    /// Requests relayed to the leader and not answered yet.
+    pub fn forwarding(&self) -> usize {
+        self.state().proxy.len()
+    }
+
+    fn reply(&self, outbox: &Outbox, client: &str, body: S::Body) -> message::Result<()> {
+        outbox.send(&Message::new(
+            self.node_id.clone(),
+            client.to_string(),
+            Payload::new(body, None),
+        ))
+    }
+
+    fn request(
+        &self,
+        outbox: &Outbox,
+        state: &mut ServiceState<S>,
+        client: String,
+        msg_id: usize,
+        command: S::Command,
+    ) -> message::Result<()> {
+        match state.raft.propose(outbox, command.clone())? {
+            Proposal::Accepted { index, term } => {
+                let pending = Pending {
+                    term,
+                    client,
+                    msg_id,
+                };
+                state.pending.insert(index, pending);
+                Ok(())
+            }
+            Proposal::NotLeader {
+                leader: Some(leader),
+            } => state
+                .proxy
+                .forward(outbox, client, msg_id, leader, S::request(command)),
+            Proposal::NotLeader { leader: None } => {
+                let error = S::error(
+                    ErrorCode::TemporarilyUnavailable,
+                    "no leader elected yet",
+                    msg_id,
+                );
+                self.reply(outbox, &client, error)
+            }
+        }
+    }
+
+    fn applied(
+        &self,
+        outbox: &Outbox,
+        state: &mut ServiceState<S>,
+        applied: Vec<Applied<S::Output>>,
+    ) -> message::Result<()> {
+        for Applied {
+            index,
+            term,
+            output,
+        } in applied
+        {
+            let Some(pending) = state.pending.remove(&index) else {
+                continue;
+            };
+            let reply = match output {
+                Some(output) if term == pending.term => S::reply(output, pending.msg_id),
+                // another leader filled the slot, the request never took effect.
+                _ => S::error(
+                    S::LOST_COMMIT,
+                    "leadership changed before commit",
+                    pending.msg_id,
+                ),
+            };
+            self.reply(outbox, &pending.client, reply)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S> Node for RaftService<S>
+where
+    S: Replicated + Sync,
+    ServicePayload<S::Body, S::Command>: BodyTypes + JsonSchema,
+{
+    type Payload = ServicePayload<S::Body, S::Command>;
+    type Event = Event<S>;
+
+    fn from_init(
+        node_id: String,
+        node_ids: Vec<String>,
+        scheduler: SchedulerRef<Self::Event>,
+        config: &Config,
+    ) -> Self {
+        RaftService::new(
+            node_id,
+            node_ids,
+            config.raft(),
+            config.forward_timeout,
+            scheduler,
+        )
+    }
+
+    fn ordering_key(_event: &Self::Event) -> Option<&str> {
+        // everything is serialized by the state lock anyway.
+        None
+    }
+}
+
+impl<S: Replicated> Handler<Event<S>> for RaftService<S> {
+    fn handle(&self, outbox: &Outbox, message: Event<S>) -> message::Result<()> {
+        let mut state = self.state();
+        let Message { src, dst, body } = message;
+        match body.data {
+            ServicePayload::Raft(data) => {
+                let message = Message::new(src, dst, Payload::new(data, body.msg_id));
+                let applied = state.raft.handle(outbox, message)?;
+                self.applied(outbox, &mut state, applied)?;
+            }
+            ServicePayload::Client(data) => match S::command(data) {
+                Ok(command) => {
+                    let msg_id = body.msg_id.unwrap_or(1);
+                    self.request(outbox, &mut state, src, msg_id, command)?;
+                }
+                // the leader answered a proxied request, pass it on.
+                Err(reply) => state.proxy.relay(outbox, reply)?,
+            },
+            ServicePayload::Proxy(data) => {
+                let message = Message::new(src, dst, Payload::new(data, body.msg_id));
+                state.proxy.handle(outbox, message)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,418 @@
+//! At-least-once delivery between nodes. Every message to a peer takes the
+//! next sequence number of that peer and is sent again with exponential
+//! backoff until the peer acks it. Receivers drop duplicates and hold back
+//! messages that overtook a missing one, so a node sees what a peer sent
+//! exactly once and in send order, even across partitions that heal.
+//!
+//! Sequences live in memory only, a node that restarts would start over and
+//! have its messages taken for duplicates.
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Config,
+    message::{self, Message, Payload},
+    output::Outbox,
+    scheduler::{SchedulerRef, Timer},
+};
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Reliable<T> {
+    /// `seq` counts from 1 for every sender and receiver pair.
+    Deliver {
+        seq: u64,
+        message: T,
+    },
+    /// `seq` arrived, and so did everything up to `delivered`.
+    Ack {
+        seq: u64,
+        delivered: u64,
+    },
+    // timer event a node sends itself.
+    Retransmit,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReliableConfig {
+    /// How often unacked messages are checked for being due again.
+    pub retransmit_interval: Duration,
+    /// Wait before the first retransmit, doubled on every further one.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for ReliableConfig {
+    fn default() -> Self {
+        Config::default().reliable()
+    }
+}
+
+impl ReliableConfig {
+    /// Wait after the `attempts`th send, between half and all of the
+    /// doubled backoff so peers cut off together do not retry in lockstep.
+    fn backoff(&self, attempts: u32, random: u64) -> Duration {
+        let doubled = self
+            .initial_backoff
+            .saturating_mul(1 << attempts.saturating_sub(1).min(16))
+            .min(self.max_backoff);
+        let half = doubled.as_micros() as u64 / 2;
+        Duration::from_micros(half + random % (half + 1))
+    }
+}
+
+struct Pending<T> {
+    message: T,
+    attempts: u32,
+    due: Duration,
+}
+
+struct Outgoing<T> {
+    last_seq: u64,
+    unacked: BTreeMap<u64, Pending<T>>,
+}
+
+struct Incoming<T> {
+    delivered: u64,
+    // arrived ahead of a missing message.
+    early: BTreeMap<u64, T>,
+}
+
+/// Reliable channels of one node to all its peers. Retransmits are
+/// scheduled as messages of type `E` the node sends itself and must hand
+/// back to `handle`.
+pub struct ReliableChannel<T, E> {
+    node_id: String,
+    config: ReliableConfig,
+    scheduler: SchedulerRef<E>,
+    // ordered, so retransmits draw their backoffs the same way every run.
+    outgoing: BTreeMap<String, Outgoing<T>>,
+    incoming: HashMap<String, Incoming<T>>,
+    timer: Option<Timer>,
+}
+
+impl<T, E> ReliableChannel<T, E>
+where
+    T: Serialize + Clone,
+    E: From<Message<Reliable<T>>> + Send + 'static,
+{
+    pub fn new(node_id: String, config: ReliableConfig, scheduler: SchedulerRef<E>) -> Self {
+        let tick_node = node_id.clone();
+        let timer = scheduler.every(
+            config.retransmit_interval,
+            Box::new(move || {
+                E::from(Message::new(
+                    tick_node.clone(),
+                    tick_node.clone(),
+                    Payload::new(Reliable::Retransmit, None),
+                ))
+            }),
+        );
+        ReliableChannel {
+            node_id,
+            config,
+            scheduler,
+            outgoing: BTreeMap::new(),
+            incoming: HashMap::new(),
+            timer: Some(timer),
+        }
+    }
+
+    /// Sends `message` to `dest` until it is acked, returns its sequence number.
+    pub fn send(&mut self, outbox: &Outbox, dest: &str, message: T) -> message::Result<u64> {
+        let due = self.scheduler.now() + self.config.backoff(1, self.scheduler.random());
+        let outgoing = self
+            .outgoing
+            .entry(dest.to_string())
+            .or_insert_with(|| Outgoing {
+                last_seq: 0,
+                unacked: BTreeMap::new(),
+            });
+        outgoing.last_seq += 1;
+        let seq = outgoing.last_seq;
+        outgoing.unacked.insert(
+            seq,
+            Pending {
+                message: message.clone(),
+                attempts: 1,
+                due,
+            },
+        );
+        self.transmit(outbox, dest, seq, message)?;
+        Ok(seq)
+    }
+
+    /// Messages sent but not acked yet, over all peers.
+    pub fn unacked(&self) -> usize {
+        self.outgoing
+            .values()
+            .map(|outgoing| outgoing.unacked.len())
+            .sum()
+    }
+
+    /// Cancels the retransmits, unacked messages are given up.
+    pub fn stop(&mut self) {
+        self.timer = None;
+    }
+
+    /// Handles a delivery, ack or retransmit tick and returns the messages
+    /// that are now in order, oldest first.
+    pub fn handle(
+        &mut self,
+        outbox: &Outbox,
+        message: Message<Reliable<T>>,
+    ) -> message::Result<Vec<Message<T>>> {
+        match message.body.data {
+            Reliable::Deliver { seq, message: data } => {
+                let incoming =
+                    self.incoming
+                        .entry(message.src.clone())
+                        .or_insert_with(|| Incoming {
+                            delivered: 0,
+                            early: BTreeMap::new(),
+                        });
+                // duplicates are acked again, the first ack may have been lost.
+                if seq > incoming.delivered {
+                    incoming.early.entry(seq).or_insert(data);
+                }
+                let mut ready = Vec::new();
+                while let Some(data) = incoming.early.remove(&(incoming.delivered + 1)) {
+                    incoming.delivered += 1;
+                    ready.push(Message::new(
+                        message.src.clone(),
+                        self.node_id.clone(),
+                        Payload::new(data, None),
+                    ));
+                }
+                let ack = Reliable::<T>::Ack {
+                    seq,
+                    delivered: incoming.delivered,
+                };
+                outbox.send(&Message::new(
+                    self.node_id.clone(),
+                    message.src,
+                    Payload::new(ack, None),
+                ))?;
+                Ok(ready)
+            }
+            Reliable::Ack { seq, delivered } => {
+                if let Some(outgoing) = self.outgoing.get_mut(&message.src) {
+                    outgoing.unacked.remove(&seq);
+                    outgoing.unacked = outgoing.unacked.split_off(&(delivered + 1));
+                }
+                Ok(Vec::new())
+            }
+            Reliable::Retransmit => {
+                let now = self.scheduler.now();
+                let mut due = Vec::new();
+                for (peer, outgoing) in self.outgoing.iter_mut() {
+                    for (seq, pending) in outgoing.unacked.iter_mut() {
+                        if pending.due > now {
+                            continue;
+                        }
+                        pending.attempts += 1;
+                        pending.due = now
+                            + self
+                                .config
+                                .backoff(pending.attempts, self.scheduler.random());
+                        due.push((peer.clone(), *seq, pending.message.clone()));
+                    }
+                }
+                for (peer, seq, data) in due {
+                    self.transmit(outbox, &peer, seq, data)?;
+                }
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    fn transmit(&self, outbox: &Outbox, dest: &str, seq: u64, message: T) -> message::Result<()> {
+        outbox.send(&Message::new(
+            self.node_id.clone(),
+            dest.to_string(),
+            Payload::new(Reliable::Deliver { seq, message }, None),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{
+        message::{BodyTypes, Handler},
+        runtime::Node,
+        sim::Simulation,
+    };
+
+    #[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Command {
+        /// Sends `value` once to every peer.
+        Spread { value: usize },
+    }
+
+    #[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq)]
+    #[serde(untagged)]
+    enum SpreadPayload {
+        Client(Command),
+        Peer(Reliable<usize>),
+    }
+
+    impl BodyTypes for SpreadPayload {
+        const TYPES: &'static [&'static str] = &["spread", "deliver", "ack", "retransmit"];
+    }
+
+    impl From<Message<Reliable<usize>>> for Message<SpreadPayload> {
+        fn from(message: Message<Reliable<usize>>) -> Self {
+            message.map(SpreadPayload::Peer)
+        }
+    }
+
+    struct SpreadNode {
+        peers: Vec<String>,
+        channel: Mutex<ReliableChannel<usize, Message<SpreadPayload>>>,
+        // clients race too, so this is the send order peers must see.
+        spread: Mutex<Vec<usize>>,
+        received: Mutex<Vec<(String, usize)>>,
+    }
+
+    impl Node for SpreadNode {
+        type Payload = SpreadPayload;
+        type Event = Message<SpreadPayload>;
+
+        fn from_init(
+            node_id: String,
+            node_ids: Vec<String>,
+            scheduler: SchedulerRef<Self::Event>,
+            _config: &Config,
+        ) -> Self {
+            let peers = node_ids.into_iter().filter(|id| *id != node_id).collect();
+            let channel = ReliableChannel::new(node_id, ReliableConfig::default(), scheduler);
+            SpreadNode {
+                peers,
+                channel: Mutex::new(channel),
+                spread: Mutex::new(Vec::new()),
+                received: Mutex::new(Vec::new()),
+            }
+        }
+
+        fn ordering_key(_event: &Self::Event) -> Option<&str> {
+            None
+        }
+    }
+
+    impl Handler<Message<SpreadPayload>> for SpreadNode {
+        fn handle(&self, outbox: &Outbox, message: Message<SpreadPayload>) -> message::Result<()> {
+            let mut channel = self.channel.lock().unwrap();
+            match message.body.data {
+                SpreadPayload::Client(Command::Spread { value }) => {
+                    self.spread.lock().unwrap().push(value);
+                    for peer in &self.peers {
+                        channel.send(outbox, peer, value)?;
+                    }
+                }
+                SpreadPayload::Peer(reliable) => {
+                    let message =
+                        Message::new(message.src, message.dst, Payload::new(reliable, None));
+                    let ready = channel.handle(outbox, message)?;
+                    self.received.lock().unwrap().extend(
+                        ready
+                            .into_iter()
+                            .map(|message| (message.src, message.body.data)),
+                    );
+                }
+            }
+            Ok(())
+        }
+    }
+
+    const NODES: [&str; 3] = ["n1", "n2", "n3"];
+
+    fn received_from(sim: &Simulation<SpreadNode>, node: &str, src: &str) -> Vec<usize> {
+        let received = sim.node(node).unwrap().received.lock().unwrap();
+        received
+            .iter()
+            .filter(|(from, _)| from == src)
+            .map(|(_, value)| *value)
+            .collect()
+    }
+
+    fn spread(sim: &Simulation<SpreadNode>, node: &str) -> Vec<usize> {
+        sim.node(node).unwrap().spread.lock().unwrap().clone()
+    }
+
+    fn unacked(sim: &Simulation<SpreadNode>, node: &str) -> usize {
+        sim.node(node).unwrap().channel.lock().unwrap().unacked()
+    }
+
+    #[test]
+    fn test_reordered_messages_arrive_once_in_order() {
+        let mut sim =
+            Simulation::<SpreadNode>::new(&NODES, 4).with_max_latency(Duration::from_millis(30));
+        for value in 0..50 {
+            sim.client_send("c1", "n1", SpreadPayload::Client(Command::Spread { value }))
+                .unwrap();
+        }
+        sim.run_for(Duration::from_secs(2)).unwrap();
+        let sent = spread(&sim, "n1");
+        assert_eq!(50, sent.len());
+        for node in ["n2", "n3"] {
+            assert_eq!(sent, received_from(&sim, node, "n1"));
+        }
+        assert_eq!(0, unacked(&sim, "n1"));
+    }
+
+    #[test]
+    fn test_messages_sent_during_a_partition_arrive_after_heal() {
+        let mut sim = Simulation::<SpreadNode>::new(&NODES, 5);
+        sim.partition(&[&["n1"], &["n2", "n3"]]);
+        for value in 0..10 {
+            sim.client_send("c1", "n1", SpreadPayload::Client(Command::Spread { value }))
+                .unwrap();
+            sim.client_send("c1", "n2", SpreadPayload::Client(Command::Spread { value }))
+                .unwrap();
+        }
+        sim.run_for(Duration::from_secs(10)).unwrap();
+        assert!(received_from(&sim, "n2", "n1").is_empty());
+        assert_eq!(spread(&sim, "n2"), received_from(&sim, "n3", "n2"));
+        assert_eq!(20, unacked(&sim, "n1"));
+
+        sim.heal();
+        sim.run_for(Duration::from_secs(10)).unwrap();
+        for (node, src) in [("n2", "n1"), ("n3", "n1"), ("n1", "n2")] {
+            assert_eq!(
+                spread(&sim, src),
+                received_from(&sim, node, src),
+                "{} from {}",
+                node,
+                src
+            );
+        }
+        for node in NODES {
+            assert_eq!(0, unacked(&sim, node), "{}", node);
+        }
+    }
+
+    #[test]
+    fn test_backoff_doubles_with_jitter_up_to_max() {
+        let config = ReliableConfig::default();
+        for attempts in 1..8 {
+            let full = config
+                .initial_backoff
+                .saturating_mul(1 << (attempts - 1))
+                .min(config.max_backoff);
+            assert_eq!(full / 2, config.backoff(attempts, 0));
+            assert_eq!(full, config.backoff(attempts, full.as_micros() as u64 / 2));
+        }
+        let half = config.max_backoff.as_micros() as u64 / 2;
+        assert_eq!(config.max_backoff, config.backoff(u32::MAX, half));
+        assert!(config.backoff(u32::MAX, 7) >= config.max_backoff / 2);
+    }
+}
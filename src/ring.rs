@@ -0,0 +1,192 @@
+#![allow(dead_code)]
+//! Consistent-hash ring telling which nodes own a key. Every node takes
+//! `virtual_nodes` points on the ring and a key belongs to the nodes of the
+//! first points clockwise from its hash. A node joining or leaving only
+//! moves the keys next to its own points, about one in `nodes` keys, and
+//! every node built from the same `node_ids` agrees on the owners.
+//!
+//! A standalone building block: lin-kv and txn-list-append replicate every
+//! key through one Raft group, so no workload shards keys yet. One that does
+//! would look up `owner` and hand requests for other nodes to `proxy::Proxy`.
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeSet},
+    hash::{Hash, Hasher},
+};
+
+/// Points per node, enough to keep shares within a few percent.
+pub const DEFAULT_VIRTUAL_NODES: usize = 128;
+
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    virtual_nodes: usize,
+    replicas: usize,
+    // node ids next to the hash so that colliding points both stay.
+    points: BTreeSet<(u64, String)>,
+    nodes: BTreeSet<String>,
+}
+
+fn hash(value: &(impl Hash + ?Sized)) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl HashRing {
+    /// Ring over `node_ids` handing out replica sets of `replicas` nodes.
+    pub fn new(node_ids: &[String], virtual_nodes: usize, replicas: usize) -> Self {
+        let mut ring = HashRing {
+            virtual_nodes: virtual_nodes.max(1),
+            replicas: replicas.max(1),
+            points: BTreeSet::new(),
+            nodes: BTreeSet::new(),
+        };
+        for node_id in node_ids {
+            ring.add(node_id);
+        }
+        ring
+    }
+
+    /// Whether `node_id` was new.
+    pub fn add(&mut self, node_id: &str) -> bool {
+        if !self.nodes.insert(node_id.to_string()) {
+            return false;
+        }
+        for index in 0..self.virtual_nodes {
+            self.points
+                .insert((hash(&(node_id, index)), node_id.to_string()));
+        }
+        true
+    }
+
+    /// Whether `node_id` was on the ring.
+    pub fn remove(&mut self, node_id: &str) -> bool {
+        if !self.nodes.remove(node_id) {
+            return false;
+        }
+        self.points.retain(|(_, owner)| owner != node_id);
+        true
+    }
+
+    pub fn contains(&self, node_id: &str) -> bool {
+        self.nodes.contains(node_id)
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &str> {
+        self.nodes.iter().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// First node of the replica set, `None` on an empty ring.
+    pub fn owner(&self, key: &(impl Hash + ?Sized)) -> Option<&str> {
+        self.walk(hash(key)).next()
+    }
+
+    /// Up to `replicas` distinct nodes for `key`, the owner first. Fewer
+    /// when the ring has fewer nodes.
+    pub fn replicas(&self, key: &(impl Hash + ?Sized)) -> Vec<&str> {
+        let mut replicas = Vec::with_capacity(self.replicas.min(self.nodes.len()));
+        for node_id in self.walk(hash(key)) {
+            if replicas.len() == self.replicas.min(self.nodes.len()) {
+                break;
+            }
+            if !replicas.contains(&node_id) {
+                replicas.push(node_id);
+            }
+        }
+        replicas
+    }
+
+    // nodes of the points clockwise from `from`, wrapping around once.
+    fn walk(&self, from: u64) -> impl Iterator<Item = &str> {
+        let start = (from, String::new());
+        self.points
+            .range(&start..)
+            .chain(self.points.range(..&start))
+            .map(|(_, node_id)| node_id.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn node_ids(count: usize) -> Vec<String> {
+        (1..=count).map(|node| format!("n{}", node)).collect()
+    }
+
+    fn owners(ring: &HashRing, keys: usize) -> Vec<String> {
+        (0..keys)
+            .map(|key| ring.owner(&key).unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_keys_spread_evenly() {
+        let ring = HashRing::new(&node_ids(5), DEFAULT_VIRTUAL_NODES, 1);
+        let mut shares = HashMap::<String, usize>::new();
+        for owner in owners(&ring, 10_000) {
+            *shares.entry(owner).or_default() += 1;
+        }
+        assert_eq!(5, shares.len());
+        // 2000 each when perfectly even.
+        for (node_id, share) in shares {
+            assert!((1600..=2400).contains(&share), "{} owns {}", node_id, share);
+        }
+    }
+
+    #[test]
+    fn test_membership_changes_move_few_keys() {
+        let mut ring = HashRing::new(&node_ids(5), DEFAULT_VIRTUAL_NODES, 1);
+        let before = owners(&ring, 10_000);
+
+        assert!(ring.add("n6"));
+        let joined = owners(&ring, 10_000);
+        let moved = before
+            .iter()
+            .zip(&joined)
+            .filter(|(before, now)| before != now)
+            .collect::<Vec<_>>();
+        // keys only move to the new node, about a sixth of them.
+        assert!(moved.iter().all(|(_, owner)| *owner == "n6"));
+        assert!((1200..=2200).contains(&moved.len()));
+
+        assert!(ring.remove("n3"));
+        assert!(!ring.remove("n3"));
+        let left = owners(&ring, 10_000);
+        for ((owner, now), key) in joined.iter().zip(&left).zip(0..) {
+            assert!(
+                owner == now || owner == "n3",
+                "key {} moved off {}",
+                key,
+                owner
+            );
+        }
+        // coming back restores the old owners.
+        ring.add("n3");
+        assert_eq!(joined, owners(&ring, 10_000));
+    }
+
+    #[test]
+    fn test_replica_sets_are_distinct_and_start_at_owner() {
+        let ring = HashRing::new(&node_ids(5), 16, 3);
+        for key in 0..1000 {
+            let replicas = ring.replicas(&key);
+            assert_eq!(3, replicas.len());
+            assert_eq!(ring.owner(&key), Some(replicas[0]));
+            assert!(replicas[1..].iter().all(|node| *node != replicas[0]));
+            assert_ne!(replicas[1], replicas[2]);
+        }
+        let small = HashRing::new(&node_ids(2), 16, 3);
+        assert_eq!(2, small.replicas("key").len());
+        assert!(HashRing::new(&[], 16, 3).owner("key").is_none());
+    }
+}
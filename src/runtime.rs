@@ -0,0 +1,353 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io,
+    path::PathBuf,
+    sync::{
+        mpsc::{sync_channel, SyncSender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+use anyhow::{anyhow, bail};
+use schemars::JsonSchema;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::{
+    admin::Introspection,
+    config::Config,
+    dedup::ReplyCache,
+    event_queue::EventQueue,
+    message::{self, BodyTypes, Handler, Init, Message},
+    output::{Outbox, OutputWriter},
+    scheduler::{Clock, SchedulerRef, ThreadScheduler},
+    trace::Trace,
+};
+
+/// A node that can be driven by the runtime: it is built from the `init`
+/// message and the run's `Config` and then handles every event, either read
+/// from stdin or produced by its own timers through the scheduler it got at
+/// construction. Events are serializable so they can be recorded and
+/// replayed.
+pub trait Node: Handler<Self::Event> + Send + Sync + Sized + 'static {
+    type Payload: DeserializeOwned + BodyTypes + JsonSchema + Send + 'static;
+    type Event: From<Message<Self::Payload>> + Serialize + DeserializeOwned + Send + 'static;
+
+    /// Config keys the node takes up while running, `set_config` refuses
+    /// the others since they only change on restart.
+    const LIVE_KEYS: &'static [&'static str] = &[];
+
+    fn from_init(
+        node_id: String,
+        node_ids: Vec<String>,
+        scheduler: SchedulerRef<Self::Event>,
+        config: &Config,
+    ) -> Self;
+
+    /// Event handled once stdin is closed so the node can stop its timers.
+    fn shutdown_event() -> Option<Self::Event> {
+        None
+    }
+
+    /// Events sharing a key are handled one after another in arrival order,
+    /// events without key may be handled by any worker.
+    fn ordering_key(event: &Self::Event) -> Option<&str>;
+
+    /// Workload figures for `stats`, such as gossip rounds or state sizes.
+    fn stats(&self) -> Value {
+        Value::Null
+    }
+
+    /// Internal state for `dump_state`.
+    fn dump_state(&self) -> Value {
+        Value::Null
+    }
+
+    /// Takes up a config changed by `set_config`, which only ever changes
+    /// `LIVE_KEYS`.
+    fn set_config(&self, _config: &Config) {}
+}
+
+#[derive(Debug, Clone)]
+pub struct RuntimeOptions {
+    /// Number of threads handling events, 1 handles everything on the main thread.
+    pub workers: usize,
+    /// Directory receiving a `<node_id>.jsonl` trace of the run.
+    pub trace_dir: Option<PathBuf>,
+    /// Stamps messages between nodes with Lamport and vector clocks.
+    pub clocks: bool,
+    pub config: Config,
+}
+
+impl Default for RuntimeOptions {
+    fn default() -> Self {
+        RuntimeOptions {
+            workers: 1,
+            trace_dir: None,
+            clocks: false,
+            config: Config::default(),
+        }
+    }
+}
+
+impl RuntimeOptions {
+    pub fn from_env() -> message::Result<Self> {
+        let mut options = RuntimeOptions::default();
+        if let Ok(workers) = std::env::var("FLY_DIS_WORKERS") {
+            options.workers = workers.parse()?;
+        }
+        options.trace_dir = std::env::var_os("FLY_DIS_TRACE").map(PathBuf::from);
+        options.clocks = std::env::var("FLY_DIS_CLOCKS").is_ok_and(|clocks| clocks == "1");
+        if options.workers == 0 {
+            bail!("FLY_DIS_WORKERS must be at least 1");
+        }
+        Ok(options)
+    }
+}
+
+/// Runs `N` over stdin and stdout until stdin is closed.
+pub fn run<N: Node>(options: RuntimeOptions) -> message::Result<()> {
+    let mut stdin = io::stdin().lock();
+    let Some(init_message) = message::read_init(&mut stdin)? else {
+        return Ok(());
+    };
+    drop(stdin);
+    let (node_id, node_ids) = match init_message.body.data.clone() {
+        Init::Init { node_id, node_ids } => (node_id, node_ids),
+        _ => bail!("First message should be of type init"),
+    };
+    let trace = match &options.trace_dir {
+        Some(dir) => {
+            let trace = Trace::create(dir.join(format!("{}.jsonl", node_id)))?;
+            trace.record_in(&serde_json::to_string(&init_message)?);
+            Some(trace)
+        }
+        None => None,
+    };
+    let (outbox, output_writer) = OutputWriter::spawn(io::stdout(), trace.clone());
+    let outbox = match options.clocks {
+        true => outbox.with_clock(&node_id, &node_ids),
+        false => outbox,
+    };
+    init_message
+        .body
+        .data
+        .handle(&outbox, init_message.clone())?;
+
+    let queue = EventQueue::with_stats(
+        options.config.queue_capacity,
+        options.config.overflow_policy,
+        outbox.stats_ref(),
+    );
+    let queue_cloned = queue.clone();
+    let scheduler = Arc::new(ThreadScheduler::new(queue.clone()));
+    if let Some(trace) = &trace {
+        trace.record_seed(scheduler.seed());
+    }
+    let outbox = with_reply_cache(outbox, &node_ids, &options.config, scheduler.clone());
+    let node = Arc::new(N::from_init(node_id, node_ids, scheduler, &options.config));
+    let introspection = Introspection::new(node.clone(), &options.config);
+    let outbox_cloned = outbox.clone();
+    let trace_cloned = trace.clone();
+    let join_handler = thread::spawn(move || {
+        let stdin = io::stdin().lock();
+        let read = message::read_messages(
+            stdin,
+            &queue_cloned,
+            N::Event::from,
+            &outbox_cloned,
+            trace_cloned.as_ref(),
+            Some(&|outbox: &Outbox, message| introspection.handle(outbox, message)),
+        );
+        let stop =
+            N::shutdown_event().map_or(Ok(()), |shutdown| queue_cloned.push_internal(shutdown));
+        queue_cloned.close();
+        stop.map_err(|_| anyhow!("node stopped before shutdown"))?;
+        read
+    });
+
+    let dispatched = dispatch(
+        node.clone(),
+        &outbox,
+        queue.clone(),
+        options.workers,
+        trace.as_ref(),
+    );
+    // a failed node leaves no one to pop, the reader must not wait for room.
+    queue.close();
+    dispatched?;
+    // dropping the node cancels its timers.
+    drop(node);
+
+    join_handler.join().expect("Main panicked")?;
+    drop(outbox);
+    output_writer.join()
+}
+
+/// Adds the cache answering retried client requests, unless
+/// `dedup_capacity` turns it off.
+pub fn with_reply_cache(
+    outbox: Outbox,
+    node_ids: &[String],
+    config: &Config,
+    clock: Arc<dyn Clock>,
+) -> Outbox {
+    match config.dedup_capacity {
+        0 => outbox,
+        capacity => {
+            outbox.with_replies(ReplyCache::new(capacity, config.dedup_ttl, node_ids, clock))
+        }
+    }
+}
+
+/// Handles events from `queue` until it is closed and drained.
+pub fn dispatch<N: Node>(
+    node: Arc<N>,
+    outbox: &Outbox,
+    queue: EventQueue<N::Event>,
+    workers: usize,
+    trace: Option<&Trace>,
+) -> message::Result<()> {
+    let record = |event: &N::Event| {
+        if let Some(trace) = trace {
+            trace.record_event(event, outbox.clock());
+        }
+    };
+    if workers <= 1 {
+        while let Some(event) = queue.pop() {
+            record(&event);
+            node.handle(outbox, event)?;
+        }
+        return Ok(());
+    }
+    let mut pool = WorkerPool::new(workers, node, outbox.clone());
+    while let Some(event) = queue.pop() {
+        record(&event);
+        let key = N::ordering_key(&event).map(|key| {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            hasher.finish()
+        });
+        pool.dispatch(key, event)?;
+    }
+    pool.join()
+}
+
+const WORKER_BACKLOG: usize = 16;
+
+/// Fixed set of threads sharing one node. Events with the same key always go
+/// to the same worker, which keeps them in order.
+#[derive(Debug)]
+pub struct WorkerPool<E> {
+    senders: Vec<SyncSender<E>>,
+    handlers: Vec<JoinHandle<message::Result<()>>>,
+    next: usize,
+}
+
+impl<E: Send + 'static> WorkerPool<E> {
+    pub fn new<N>(size: usize, node: Arc<N>, outbox: Outbox) -> Self
+    where
+        N: Handler<E> + Send + Sync + 'static,
+    {
+        let (senders, handlers) = (0..size.max(1))
+            .map(|_| {
+                // a short backlog per worker, the event queue holds the rest.
+                let (tx, rx) = sync_channel::<E>(WORKER_BACKLOG);
+                let node = node.clone();
+                let outbox = outbox.clone();
+                let handler = thread::spawn(move || {
+                    for event in rx {
+                        node.handle(&outbox, event)?;
+                    }
+                    Ok(())
+                });
+                (tx, handler)
+            })
+            .unzip();
+        WorkerPool {
+            senders,
+            handlers,
+            next: 0,
+        }
+    }
+
+    pub fn dispatch(&mut self, key: Option<u64>, event: E) -> message::Result<()> {
+        let worker = match key {
+            Some(key) => (key % self.senders.len() as u64) as usize,
+            None => {
+                self.next = (self.next + 1) % self.senders.len();
+                self.next
+            }
+        };
+        if self.senders[worker].send(event).is_err() {
+            // the worker only stops on a handler error, report that one.
+            return Err(self
+                .join()
+                .err()
+                .unwrap_or_else(|| anyhow!("worker stopped")));
+        }
+        Ok(())
+    }
+
+    /// Lets the workers drain their queues and waits for them.
+    pub fn join(&mut self) -> message::Result<()> {
+        self.senders.clear();
+        let mut result = Ok(());
+        for handler in self.handlers.drain(..) {
+            let joined = handler.join().map_err(|_| anyhow!("worker panicked"))?;
+            if result.is_ok() {
+                result = joined;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Recorder {
+        seen: Mutex<Vec<(String, usize)>>,
+    }
+
+    impl Handler<(String, usize)> for Recorder {
+        fn handle(&self, _outbox: &Outbox, event: (String, usize)) -> message::Result<()> {
+            // uneven handling time shuffles events across workers.
+            thread::sleep(Duration::from_micros((event.1 % 3) as u64 * 50));
+            self.seen.lock().unwrap().push(event);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_worker_pool_keeps_per_source_order() {
+        let recorder = Arc::new(Recorder::default());
+        let (outbox, _lines) = Outbox::channel();
+        let mut pool = WorkerPool::new(4, recorder.clone(), outbox);
+        for count in 0..100 {
+            for source in 0..6 {
+                let source = format!("c{}", source);
+                let mut hasher = DefaultHasher::new();
+                source.hash(&mut hasher);
+                pool.dispatch(Some(hasher.finish()), (source, count))
+                    .unwrap();
+            }
+        }
+        pool.join().unwrap();
+
+        let seen = recorder.seen.lock().unwrap();
+        assert_eq!(600, seen.len());
+        let mut last = HashMap::new();
+        for (source, count) in seen.iter() {
+            if let Some(previous) = last.insert(source.clone(), *count) {
+                assert!(previous < *count, "{} out of order", source);
+            }
+        }
+    }
+}
@@ -0,0 +1,452 @@
+#![allow(dead_code)]
+use std::{
+    any::Any,
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::Serialize;
+
+use crate::{event_queue::EventQueue, sim::Rng};
+
+/// Source of time for a node. Only durations since the node started are
+/// exposed so real and virtual time look the same.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Duration;
+}
+
+/// Lets a node ask for events to be delivered to itself later.
+pub trait Scheduler<E>: Clock {
+    /// Delivers `event()` right away and then once every `period`.
+    fn every(&self, period: Duration, event: Box<dyn Fn() -> E + Send + Sync>) -> Timer;
+
+    /// Delivers `event` once after `delay`.
+    fn after(&self, delay: Duration, event: E) -> Timer;
+
+    /// Random number for jittering timeouts, seeded by the simulation under
+    /// virtual time so a seed still replays the same run.
+    fn random(&self) -> u64;
+}
+
+pub type SchedulerRef<E> = Arc<dyn Scheduler<E>>;
+
+/// Handle of a scheduled event, dropping it cancels the event.
+pub struct Timer {
+    _guard: Box<dyn Any + Send + Sync>,
+}
+
+impl fmt::Debug for Timer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Timer").finish()
+    }
+}
+
+impl Timer {
+    fn new(guard: impl Any + Send + Sync) -> Self {
+        Timer {
+            _guard: Box::new(guard),
+        }
+    }
+}
+
+/// Sets the flag once the owning `Timer` is dropped.
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+enum Firing<E> {
+    Once(E),
+    Every(Duration, Box<dyn Fn() -> E + Send + Sync>),
+}
+
+struct TimerState<E> {
+    next_id: u64,
+    // deadlines of cancelled timers stay until due and are skipped then.
+    due: BinaryHeap<Reverse<(Instant, u64)>>,
+    timers: HashMap<u64, Firing<E>>,
+    stopped: bool,
+}
+
+struct Timers<E> {
+    state: Mutex<TimerState<E>>,
+    wake: Condvar,
+}
+
+impl<E> Timers<E> {
+    fn lock(&self) -> MutexGuard<'_, TimerState<E>> {
+        self.state.lock().expect("timer lock poisoned")
+    }
+
+    fn add(&self, deadline: Instant, firing: Firing<E>) -> u64 {
+        let mut state = self.lock();
+        state.next_id += 1;
+        let id = state.next_id;
+        state.due.push(Reverse((deadline, id)));
+        state.timers.insert(id, firing);
+        self.wake.notify_one();
+        id
+    }
+}
+
+/// Takes the timer out of the deadline heap once the owning `Timer` is dropped.
+struct TimerGuard<E> {
+    timers: Arc<Timers<E>>,
+    id: u64,
+}
+
+impl<E> Drop for TimerGuard<E> {
+    fn drop(&mut self) {
+        self.timers.lock().timers.remove(&self.id);
+    }
+}
+
+/// Wall clock scheduler, one thread per node waits for the earliest
+/// deadline of all its timers and pushes their events to the queue of the
+/// node as internal events.
+pub struct ThreadScheduler<E> {
+    timers: Arc<Timers<E>>,
+    start: Instant,
+    seed: u64,
+    rng: Mutex<Rng>,
+}
+
+impl<E> fmt::Debug for ThreadScheduler<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ThreadScheduler")
+            .field("start", &self.start)
+            .finish()
+    }
+}
+
+impl<E: Serialize + Send + 'static> ThreadScheduler<E> {
+    pub fn new(queue: EventQueue<E>) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as u64);
+        let timers = Arc::new(Timers {
+            state: Mutex::new(TimerState {
+                next_id: 0,
+                due: BinaryHeap::new(),
+                timers: HashMap::new(),
+                stopped: false,
+            }),
+            wake: Condvar::new(),
+        });
+        let fire = timers.clone();
+        thread::spawn(move || ThreadScheduler::fire(&fire, &queue));
+        ThreadScheduler {
+            timers,
+            start: Instant::now(),
+            seed,
+            rng: Mutex::new(Rng::new(seed)),
+        }
+    }
+
+    /// Seed of `random`, a replay seeded with it draws the same numbers.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    fn fire(timers: &Timers<E>, queue: &EventQueue<E>) {
+        let mut state = timers.lock();
+        while !state.stopped {
+            let now = Instant::now();
+            let mut fired = Vec::new();
+            while let Some(&Reverse((deadline, id))) = state.due.peek() {
+                if deadline > now {
+                    break;
+                }
+                state.due.pop();
+                match state.timers.remove(&id) {
+                    Some(Firing::Once(event)) => fired.push(event),
+                    Some(Firing::Every(period, event)) => {
+                        fired.push(event());
+                        state.due.push(Reverse((now + period, id)));
+                        state.timers.insert(id, Firing::Every(period, event));
+                    }
+                    None => {}
+                }
+            }
+            if !fired.is_empty() {
+                // pushed unlocked, so handlers dropping timers never wait on it.
+                drop(state);
+                for event in fired {
+                    let _ = queue.push_internal(event);
+                }
+                state = timers.lock();
+                continue;
+            }
+            state = match state.due.peek() {
+                Some(&Reverse((deadline, _))) => {
+                    let timeout = deadline - now;
+                    timers
+                        .wake
+                        .wait_timeout(state, timeout)
+                        .expect("timer lock poisoned")
+                        .0
+                }
+                None => timers.wake.wait(state).expect("timer lock poisoned"),
+            };
+        }
+    }
+
+    fn schedule(&self, deadline: Instant, firing: Firing<E>) -> Timer {
+        let id = self.timers.add(deadline, firing);
+        Timer::new(TimerGuard {
+            timers: self.timers.clone(),
+            id,
+        })
+    }
+}
+
+impl<E> Drop for ThreadScheduler<E> {
+    fn drop(&mut self) {
+        self.timers.lock().stopped = true;
+        self.timers.wake.notify_one();
+    }
+}
+
+impl<E: Send> Clock for ThreadScheduler<E> {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+impl<E: Serialize + Send + 'static> Scheduler<E> for ThreadScheduler<E> {
+    fn every(&self, period: Duration, event: Box<dyn Fn() -> E + Send + Sync>) -> Timer {
+        self.schedule(Instant::now(), Firing::Every(period, event))
+    }
+
+    fn after(&self, delay: Duration, event: E) -> Timer {
+        self.schedule(Instant::now() + delay, Firing::Once(event))
+    }
+
+    fn random(&self) -> u64 {
+        self.rng.lock().expect("rng lock poisoned").next_u64()
+    }
+}
+
+struct VirtualTimer<E> {
+    node: String,
+    period: Option<Duration>,
+    event: Box<dyn Fn() -> E + Send + Sync>,
+    cancelled: Arc<AtomicBool>,
+}
+
+struct VirtualState<E> {
+    now: Duration,
+    next_id: u64,
+    // ordered by due time, then by creation so equal deadlines stay deterministic.
+    due: BinaryHeap<Reverse<(Duration, u64)>>,
+    timers: Vec<(u64, VirtualTimer<E>)>,
+    rng: Rng,
+}
+
+/// Virtual time shared by every node of a simulation. Time only moves when
+/// the owner calls `advance_to`, timers never fire on their own.
+pub struct VirtualTime<E> {
+    state: Arc<Mutex<VirtualState<E>>>,
+}
+
+impl<E> Clone for VirtualTime<E> {
+    fn clone(&self) -> Self {
+        VirtualTime {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<E> Default for VirtualTime<E> {
+    fn default() -> Self {
+        VirtualTime::seeded(0)
+    }
+}
+
+impl<E> VirtualTime<E> {
+    /// `seed` drives the `random` of every scheduler handed out.
+    pub fn seeded(seed: u64) -> Self {
+        VirtualTime {
+            state: Arc::new(Mutex::new(VirtualState {
+                now: Duration::ZERO,
+                next_id: 0,
+                due: BinaryHeap::new(),
+                timers: Vec::new(),
+                rng: Rng::new(seed),
+            })),
+        }
+    }
+}
+
+impl<E: 'static> VirtualTime<E> {
+    pub fn now(&self) -> Duration {
+        self.lock().now
+    }
+
+    /// Scheduler for one node, fired events are tagged with `node`.
+    pub fn scheduler(&self, node: &str) -> VirtualScheduler<E> {
+        VirtualScheduler {
+            node: node.to_string(),
+            time: self.clone(),
+        }
+    }
+
+    /// Due time of the next live timer.
+    pub fn next_due(&self) -> Option<Duration> {
+        let mut state = self.lock();
+        state.drop_cancelled();
+        state.due.peek().map(|Reverse((at, _))| *at)
+    }
+
+    pub fn advance_to(&self, at: Duration) {
+        let mut state = self.lock();
+        state.now = state.now.max(at);
+    }
+
+    /// Pops the earliest timer due at or before now, periodic timers are
+    /// scheduled again.
+    pub fn pop_due(&self) -> Option<(String, E)> {
+        let mut state = self.lock();
+        state.drop_cancelled();
+        let Reverse((at, id)) = *state.due.peek()?;
+        if at > state.now {
+            return None;
+        }
+        state.due.pop();
+        let index = state
+            .timers
+            .iter()
+            .position(|(timer_id, _)| *timer_id == id)?;
+        let timer = &state.timers[index].1;
+        let fired = (timer.node.clone(), (timer.event)());
+        match timer.period {
+            Some(period) => {
+                let period = period.max(Duration::from_millis(1));
+                state.due.push(Reverse((at + period, id)));
+            }
+            None => {
+                state.timers.remove(index);
+            }
+        }
+        Some(fired)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, VirtualState<E>> {
+        self.state.lock().expect("virtual time lock poisoned")
+    }
+
+    fn schedule(
+        &self,
+        node: &str,
+        delay: Duration,
+        period: Option<Duration>,
+        event: Box<dyn Fn() -> E + Send + Sync>,
+    ) -> Timer {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let mut state = self.lock();
+        let id = state.next_id;
+        state.next_id += 1;
+        let at = state.now + delay;
+        state.due.push(Reverse((at, id)));
+        state.timers.push((
+            id,
+            VirtualTimer {
+                node: node.to_string(),
+                period,
+                event,
+                cancelled: cancelled.clone(),
+            },
+        ));
+        Timer::new(CancelOnDrop(cancelled))
+    }
+}
+
+impl<E> VirtualState<E> {
+    fn drop_cancelled(&mut self) {
+        self.timers
+            .retain(|(_, timer)| !timer.cancelled.load(Ordering::Acquire));
+        while let Some(Reverse((_, id))) = self.due.peek() {
+            if self.timers.iter().any(|(timer_id, _)| timer_id == id) {
+                break;
+            }
+            self.due.pop();
+        }
+    }
+}
+
+pub struct VirtualScheduler<E> {
+    node: String,
+    time: VirtualTime<E>,
+}
+
+impl<E: 'static> Clock for VirtualScheduler<E> {
+    fn now(&self) -> Duration {
+        self.time.now()
+    }
+}
+
+impl<E: Send + 'static> Scheduler<E> for VirtualScheduler<E> {
+    fn every(&self, period: Duration, event: Box<dyn Fn() -> E + Send + Sync>) -> Timer {
+        self.time
+            .schedule(&self.node, Duration::ZERO, Some(period), event)
+    }
+
+    fn after(&self, delay: Duration, event: E) -> Timer {
+        // a one shot timer fires once, the event is taken out on that call.
+        let event = Mutex::new(Some(event));
+        self.time.schedule(
+            &self.node,
+            delay,
+            None,
+            Box::new(move || {
+                event
+                    .lock()
+                    .expect("timer event lock poisoned")
+                    .take()
+                    .expect("one shot timer fired twice")
+            }),
+        )
+    }
+
+    fn random(&self) -> u64 {
+        self.time.lock().rng.next_u64()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_queue::OverflowPolicy;
+
+    #[test]
+    fn test_thread_timers_fire_by_deadline_until_dropped() {
+        let queue = EventQueue::new(16, OverflowPolicy::Block);
+        let scheduler = ThreadScheduler::new(queue.clone());
+        let _late = scheduler.after(Duration::from_millis(60), 3);
+        let cancelled = scheduler.after(Duration::from_millis(20), 2);
+        let _early = scheduler.after(Duration::from_millis(10), 1);
+        drop(cancelled);
+        assert_eq!(Some(1), queue.pop());
+        assert_eq!(Some(3), queue.pop());
+
+        let ticks = scheduler.every(Duration::from_millis(5), Box::new(|| 4));
+        for _ in 0..3 {
+            assert_eq!(Some(4), queue.pop());
+        }
+        drop(ticks);
+        let _after_ticks = scheduler.after(Duration::from_millis(20), 5);
+        // a tick may still have been waiting, none comes after the drop.
+        let next = queue.pop();
+        assert!(next == Some(5) || (next == Some(4) && queue.pop() == Some(5)));
+    }
+}
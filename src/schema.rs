@@ -0,0 +1,493 @@
+#![allow(dead_code)]
+//! JSON Schemas of the traffic of every workload, generated from the body
+//! types, and a check of recorded traffic against them. A node reads and
+//! writes messages of its workload, `init`, `error` and admin messages, any
+//! line of a trace has to be one of those.
+//!
+//! The validator only knows the keywords the generated schemas use.
+use std::{fmt, fs::File, io::BufReader, path::Path};
+
+use schemars::{schema_for, JsonSchema};
+use serde_json::{Map, Value};
+
+use crate::{
+    admin::Admin,
+    message::{self, ErrorReply, Init, Message},
+    runtime::Node,
+    trace::{read_trace, TraceEntry},
+};
+
+/// Everything a node of workload `T` sends or receives.
+#[derive(JsonSchema)]
+#[serde(untagged)]
+enum Traffic<T> {
+    Workload(Message<T>),
+    Init(Message<Init>),
+    Error(Message<ErrorReply>),
+    Admin(Message<Admin>),
+}
+
+/// Schema of any message a node of `N` reads or writes.
+pub fn traffic_schema<N: Node>() -> Value {
+    serde_json::to_value(schema_for!(Traffic<N::Payload>)).expect("schemas serialize")
+}
+
+/// Ways `value` breaks `schema`, each led by the JSON pointer of the
+/// offending part. Empty when it conforms.
+pub fn validate(schema: &Value, value: &Value) -> Vec<String> {
+    let mut errors = Vec::new();
+    Validator { root: schema }.check(schema, value, "", &mut errors);
+    errors
+}
+
+struct Validator<'a> {
+    // holds the `definitions` that `$ref`s point into.
+    root: &'a Value,
+}
+
+impl Validator<'_> {
+    fn check(&self, schema: &Value, value: &Value, path: &str, errors: &mut Vec<String>) {
+        let schema = match schema {
+            Value::Bool(true) => return,
+            Value::Bool(false) => {
+                errors.push(format!("{}: not allowed", at(path)));
+                return;
+            }
+            Value::Object(schema) => schema,
+            _ => return,
+        };
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            match self.resolve(reference) {
+                Some(target) => self.check(target, value, path, errors),
+                None => errors.push(format!("{}: unknown reference {}", at(path), reference)),
+            }
+        }
+        if let Some(types) = schema.get("type") {
+            let types = match types {
+                Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+                types => types.as_str().into_iter().collect::<Vec<_>>(),
+            };
+            if !types.iter().any(|kind| has_type(value, kind)) {
+                errors.push(format!(
+                    "{}: {} is not {}",
+                    at(path),
+                    value,
+                    types.join(" or ")
+                ));
+                return;
+            }
+        }
+        if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+            if !allowed.contains(value) {
+                errors.push(format!(
+                    "{}: {} is not one of {}",
+                    at(path),
+                    value,
+                    Value::from(allowed.clone())
+                ));
+            }
+        }
+        if let Some(constant) = schema.get("const") {
+            if constant != value {
+                errors.push(format!("{}: {} is not {}", at(path), value, constant));
+            }
+        }
+        if let Some(minimum) = schema.get("minimum").and_then(Value::as_f64) {
+            if value.as_f64().is_some_and(|number| number < minimum) {
+                errors.push(format!("{}: {} is below {}", at(path), value, minimum));
+            }
+        }
+        if let Value::Object(fields) = value {
+            self.check_object(schema, fields, path, errors);
+        }
+        if let Value::Array(items) = value {
+            self.check_array(schema, items, path, errors);
+        }
+        if let Some(all) = schema.get("allOf").and_then(Value::as_array) {
+            for branch in all {
+                self.check(branch, value, path, errors);
+            }
+        }
+        if let Some(branches) = schema.get("anyOf").and_then(Value::as_array) {
+            self.check_branches(branches, false, value, path, errors);
+        }
+        if let Some(branches) = schema.get("oneOf").and_then(Value::as_array) {
+            self.check_branches(branches, true, value, path, errors);
+        }
+    }
+
+    fn check_object(
+        &self,
+        schema: &Map<String, Value>,
+        fields: &Map<String, Value>,
+        path: &str,
+        errors: &mut Vec<String>,
+    ) {
+        let empty = Map::new();
+        let properties = schema
+            .get("properties")
+            .and_then(Value::as_object)
+            .unwrap_or(&empty);
+        for required in schema
+            .get("required")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            if let Some(required) = required.as_str().filter(|key| !fields.contains_key(*key)) {
+                errors.push(format!("{}: missing {}", at(path), required));
+            }
+        }
+        for (key, field) in fields {
+            let field_path = format!("{}/{}", path, key);
+            match (properties.get(key), schema.get("additionalProperties")) {
+                (Some(property), _) => self.check(property, field, &field_path, errors),
+                (None, Some(additional)) => self.check(additional, field, &field_path, errors),
+                (None, None) => {}
+            }
+        }
+    }
+
+    fn check_array(
+        &self,
+        schema: &Map<String, Value>,
+        items: &[Value],
+        path: &str,
+        errors: &mut Vec<String>,
+    ) {
+        let bounds = (
+            schema.get("minItems").and_then(Value::as_u64),
+            schema.get("maxItems").and_then(Value::as_u64),
+        );
+        if bounds.0.is_some_and(|min| (items.len() as u64) < min)
+            || bounds.1.is_some_and(|max| items.len() as u64 > max)
+        {
+            errors.push(format!("{}: {} items out of bounds", at(path), items.len()));
+        }
+        for (index, item) in items.iter().enumerate() {
+            let item_schema = match schema.get("items") {
+                // a tuple, one schema per position.
+                Some(Value::Array(positions)) => positions.get(index),
+                item_schema => item_schema,
+            };
+            if let Some(item_schema) = item_schema {
+                self.check(item_schema, item, &format!("{}/{}", path, index), errors);
+            }
+        }
+    }
+
+    /// Passes when a branch matches, when exactly one does if `exclusive`.
+    /// Otherwise reports the branch that came closest, preferring those
+    /// whose body `type` matched.
+    fn check_branches(
+        &self,
+        branches: &[Value],
+        exclusive: bool,
+        value: &Value,
+        path: &str,
+        errors: &mut Vec<String>,
+    ) {
+        let rank = |errors: &[String]| {
+            let wrong_type = errors.iter().any(|error| {
+                error
+                    .split(':')
+                    .next()
+                    .is_some_and(|at| at.ends_with("/type"))
+            });
+            (wrong_type, errors.len())
+        };
+        let mut matched = 0;
+        let mut closest: Option<Vec<String>> = None;
+        for branch in branches {
+            let mut branch_errors = Vec::new();
+            self.check(branch, value, path, &mut branch_errors);
+            if branch_errors.is_empty() {
+                matched += 1;
+                if !exclusive {
+                    return;
+                }
+                continue;
+            }
+            if closest
+                .as_ref()
+                .is_none_or(|closest| rank(&branch_errors) < rank(closest))
+            {
+                closest = Some(branch_errors);
+            }
+        }
+        match matched {
+            0 => errors.extend(closest.unwrap_or_default()),
+            1 => {}
+            matched => errors.push(format!(
+                "{}: matches {} branches of oneOf instead of one",
+                at(path),
+                matched
+            )),
+        }
+    }
+
+    fn resolve(&self, reference: &str) -> Option<&Value> {
+        let pointer = reference.strip_prefix('#')?;
+        self.root.pointer(pointer)
+    }
+}
+
+// the JSON pointer of the whole message is empty.
+fn at(path: &str) -> &str {
+    match path {
+        "" => "/",
+        path => path,
+    }
+}
+
+fn has_type(value: &Value, kind: &str) -> bool {
+    match kind {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "string" => value.is_string(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        _ => true,
+    }
+}
+
+/// Lines of a trace that broke the schema, with what was wrong with them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SchemaReport {
+    pub checked: usize,
+    pub violations: Vec<(String, Vec<String>)>,
+}
+
+impl SchemaReport {
+    pub fn passed(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+impl fmt::Display for SchemaReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} lines checked, {} violate the schema",
+            self.checked,
+            self.violations.len()
+        )?;
+        for (line, errors) in self.violations.iter() {
+            writeln!(f, "  {}", line)?;
+            for error in errors {
+                writeln!(f, "    {}", error)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn validate_file<N: Node>(path: impl AsRef<Path>) -> message::Result<SchemaReport> {
+    Ok(validate_trace::<N>(&read_trace(BufReader::new(
+        File::open(path)?,
+    ))?))
+}
+
+/// Checks every line read or written in a trace of a node of `N`.
+pub fn validate_trace<N: Node>(entries: &[TraceEntry]) -> SchemaReport {
+    let schema = traffic_schema::<N>();
+    let mut report = SchemaReport::default();
+    for entry in entries {
+        let line = match entry {
+            TraceEntry::In { line, .. } | TraceEntry::Out { line, .. } => line,
+            _ => continue,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        report.checked += 1;
+        let errors = match serde_json::from_str(line) {
+            Ok(value) => validate(&schema, &value),
+            Err(error) => vec![format!("not JSON: {}", error)],
+        };
+        if !errors.is_empty() {
+            report.violations.push((line.clone(), errors));
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeSet, sync::Arc};
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        broadcase_handler::BroadcastNode,
+        config::Config,
+        counter::{Counter, CounterNode},
+        echo_handler::EchoNode,
+        lin_kv::LinKvNode,
+        message::{BodyTypes, Handler, Payload},
+        output::Outbox,
+        scheduler::VirtualTime,
+        txn_list_append::TxnNode,
+        unique_id_handler::UniqueIdNode,
+    };
+
+    fn entries(lines: &[&str]) -> Vec<TraceEntry> {
+        lines
+            .iter()
+            .map(|line| TraceEntry::In {
+                at_ms: 0,
+                line: line.to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_recorded_counter_traffic_conforms() {
+        let time = VirtualTime::default();
+        let node = CounterNode::from_init(
+            "n1".to_string(),
+            vec!["n1".to_string(), "n2".to_string()],
+            Arc::new(time.scheduler("n1")),
+            &Config::default(),
+        );
+        let (outbox, lines) = Outbox::channel();
+        let mut trace = Vec::new();
+        let requests = [
+            ("c1", Counter::Add { delta: 3 }, Some(1)),
+            ("n2", Counter::Current { value: 4 }, None),
+            ("n2", Counter::Poll, Some(7)),
+            ("c1", Counter::Read, Some(2)),
+        ];
+        for (src, data, msg_id) in requests {
+            let message = Message::new(
+                src.to_string(),
+                "n1".to_string(),
+                Payload::new(data, msg_id),
+            );
+            trace.push(TraceEntry::In {
+                at_ms: 0,
+                line: serde_json::to_string(&message).unwrap(),
+            });
+            node.handle(&outbox, message.into()).unwrap();
+        }
+        trace.extend(
+            lines
+                .try_iter()
+                .map(|line| TraceEntry::Out { at_ms: 0, line }),
+        );
+
+        // the add also sends its delta on to n2.
+        let report = validate_trace::<CounterNode>(&trace);
+        assert_eq!(8, report.checked);
+        assert!(report.passed(), "{}", report);
+    }
+
+    #[test]
+    fn test_violations_name_the_offending_field() {
+        let report = validate_trace::<CounterNode>(&entries(&[
+            r#"{"src":"n1","dest":"c1","body":{"type":"add_ok","in_reply_to":1,"msg_id":null}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"add","delta":"3","msg_id":2}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"subtract","delta":3}}"#,
+            r#"{"src":"n1","dest":"c1","body":{"type":"error","in_reply_to":1,"code":99,"text":""}}"#,
+            r#"{"src":"c1","body":{"type":"read"}}"#,
+            r#"{"src":"n1","dest":"c1","body":{"type":"add_ok","in_reply_to":1}}"#,
+        ]));
+        assert_eq!(6, report.checked);
+        let errors = report
+            .violations
+            .iter()
+            .map(|(_, errors)| errors[0].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "/body/msg_id: null is not integer",
+                "/body/delta: \"3\" is not integer",
+                "/body/type: \"subtract\" is not one of [\"add\"]",
+                "/body/code: 99 is not one of [0,10,11,12,13,14,20,21,22,30]",
+                "/: missing dest",
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn test_one_of_takes_exactly_one_branch() {
+        let schema = json!({"oneOf": [{"type": "integer"}, {"minimum": 0}]});
+        assert!(validate(&schema, &json!(-1)).is_empty());
+        assert!(validate(&schema, &json!("x")).is_empty());
+        assert_eq!(
+            vec!["/: matches 2 branches of oneOf instead of one".to_string()],
+            validate(&schema, &json!(3))
+        );
+        let any = json!({"anyOf": [{"type": "integer"}, {"minimum": 0}]});
+        assert!(validate(&any, &json!(3)).is_empty());
+    }
+
+    #[test]
+    fn test_every_workload_accepts_init_and_errors() {
+        let lines = [
+            r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#,
+            r#"{"src":"n1","dest":"c0","body":{"type":"init_ok","in_reply_to":1}}"#,
+            r#"{"src":"n1","dest":"c1","body":{"type":"error","in_reply_to":2,"code":10,"text":"no"}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"stats","msg_id":3}}"#,
+        ];
+        let reports = [
+            validate_trace::<EchoNode>(&entries(&lines)),
+            validate_trace::<UniqueIdNode>(&entries(&lines)),
+            validate_trace::<BroadcastNode>(&entries(&lines)),
+            validate_trace::<CounterNode>(&entries(&lines)),
+            validate_trace::<LinKvNode>(&entries(&lines)),
+            validate_trace::<TxnNode>(&entries(&lines)),
+        ];
+        for report in reports {
+            assert!(report.passed(), "{}", report);
+        }
+    }
+
+    // tags of the variants `schema` is made of, following `$ref`s and
+    // branches but not fields.
+    fn collect_tags(root: &Value, schema: &Value, tags: &mut BTreeSet<String>) {
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            let name = reference.trim_start_matches("#/definitions/");
+            collect_tags(root, &root["definitions"][name], tags);
+        }
+        for keyword in ["anyOf", "oneOf", "allOf"] {
+            for branch in schema[keyword].as_array().into_iter().flatten() {
+                collect_tags(root, branch, tags);
+            }
+        }
+        let tag = &schema["properties"]["type"];
+        let values = tag["enum"].as_array().into_iter().flatten();
+        tags.extend(
+            values
+                .chain([&tag["const"]])
+                .filter_map(Value::as_str)
+                .map(str::to_string),
+        );
+    }
+
+    fn assert_body_types_match<N: Node>() {
+        let schema = serde_json::to_value(schema_for!(N::Payload)).unwrap();
+        let mut exported = BTreeSet::new();
+        collect_tags(&schema, &schema, &mut exported);
+        let listed = N::Payload::TYPES
+            .iter()
+            .map(|kind| kind.to_string())
+            .collect::<BTreeSet<_>>();
+        assert_eq!(exported, listed, "{}", std::any::type_name::<N>());
+        assert_eq!(listed.len(), N::Payload::TYPES.len());
+    }
+
+    #[test]
+    fn test_body_types_match_schema() {
+        assert_body_types_match::<EchoNode>();
+        assert_body_types_match::<UniqueIdNode>();
+        assert_body_types_match::<BroadcastNode>();
+        assert_body_types_match::<CounterNode>();
+        assert_body_types_match::<LinKvNode>();
+        assert_body_types_match::<TxnNode>();
+    }
+}
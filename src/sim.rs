@@ -0,0 +1,591 @@
+#![allow(dead_code)]
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap},
+    sync::{mpsc::Receiver, Arc},
+    time::Duration,
+};
+
+use anyhow::bail;
+use serde_json::Value;
+
+use crate::{
+    config::Config,
+    message::{self, Handler, Incoming, Message, Payload},
+    output::Outbox,
+    runtime::{self, Node},
+    scheduler::VirtualTime,
+};
+
+/// Small seeded generator (SplitMix64), good enough to pick interleavings.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform value in `0..bound`, `bound` must not be 0.
+    pub fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+}
+
+struct SimNode<N> {
+    node: N,
+    outbox: Outbox,
+    lines: Receiver<String>,
+}
+
+/// Runs a cluster of `N` on one thread in virtual time. Every message gets a
+/// latency drawn from the seeded rng and timers fire from virtual time, so a
+/// seed always replays the exact same interleaving.
+pub struct Simulation<N: Node> {
+    time: VirtualTime<N::Event>,
+    nodes: BTreeMap<String, SimNode<N>>,
+    // in flight messages ordered by delivery time, then by send order.
+    network: BinaryHeap<Reverse<(Duration, u64)>>,
+    in_flight: BTreeMap<u64, (String, String, String)>,
+    // group of every node while the network is partitioned.
+    partition: Option<BTreeMap<String, usize>>,
+    next_seq: u64,
+    next_msg_id: usize,
+    rng: Rng,
+    max_latency: Duration,
+    client_messages: Vec<Message<Value>>,
+    log: Vec<String>,
+}
+
+impl<N: Node> Simulation<N> {
+    pub fn new(node_ids: &[&str], seed: u64) -> Self {
+        Simulation::with_config(node_ids, seed, &Config::default())
+    }
+
+    pub fn with_config(node_ids: &[&str], seed: u64, config: &Config) -> Self {
+        // timers draw from their own stream so latencies do not shift them.
+        let time = VirtualTime::seeded(seed.rotate_left(32));
+        let all_node_ids = node_ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+        let nodes = all_node_ids
+            .iter()
+            .map(|node_id| {
+                let (outbox, lines) = Outbox::channel();
+                let outbox = runtime::with_reply_cache(
+                    outbox,
+                    &all_node_ids,
+                    config,
+                    Arc::new(time.scheduler(node_id)),
+                );
+                let node = N::from_init(
+                    node_id.clone(),
+                    all_node_ids.clone(),
+                    Arc::new(time.scheduler(node_id)),
+                    config,
+                );
+                (
+                    node_id.clone(),
+                    SimNode {
+                        node,
+                        outbox,
+                        lines,
+                    },
+                )
+            })
+            .collect();
+        Simulation {
+            time,
+            nodes,
+            network: BinaryHeap::new(),
+            in_flight: BTreeMap::new(),
+            partition: None,
+            next_seq: 0,
+            next_msg_id: 0,
+            rng: Rng::new(seed),
+            max_latency: Duration::from_millis(10),
+            client_messages: Vec::new(),
+            log: Vec::new(),
+        }
+    }
+
+    /// Messages take up to `max_latency`, 0 delivers in send order.
+    pub fn with_max_latency(mut self, max_latency: Duration) -> Self {
+        self.max_latency = max_latency;
+        self
+    }
+
+    /// Stamps messages between nodes with logical clocks, like `FLY_DIS_CLOCKS=1`.
+    pub fn with_clocks(mut self) -> Self {
+        let node_ids = self.nodes.keys().cloned().collect::<Vec<_>>();
+        for (node_id, sim_node) in self.nodes.iter_mut() {
+            sim_node.outbox = sim_node.outbox.clone().with_clock(node_id, &node_ids);
+        }
+        self
+    }
+
+    pub fn now(&self) -> Duration {
+        self.time.now()
+    }
+
+    pub fn node(&self, node_id: &str) -> Option<&N> {
+        self.nodes.get(node_id).map(|sim_node| &sim_node.node)
+    }
+
+    /// Sends a request from client `src` to `dest`, returns its msg_id.
+    pub fn client_send(
+        &mut self,
+        src: &str,
+        dest: &str,
+        data: impl Into<N::Payload>,
+    ) -> message::Result<usize>
+    where
+        N::Payload: serde::Serialize,
+    {
+        self.next_msg_id += 1;
+        self.client_retry(src, dest, data, self.next_msg_id)?;
+        Ok(self.next_msg_id)
+    }
+
+    /// Sends a request again under the msg_id of an earlier one.
+    pub fn client_retry(
+        &mut self,
+        src: &str,
+        dest: &str,
+        data: impl Into<N::Payload>,
+        msg_id: usize,
+    ) -> message::Result<()>
+    where
+        N::Payload: serde::Serialize,
+    {
+        let message = Message::new(
+            src.to_string(),
+            dest.to_string(),
+            Payload::new(data.into(), Some(msg_id)),
+        );
+        self.enqueue(src, dest, serde_json::to_string(&message)?);
+        Ok(())
+    }
+
+    /// Splits the nodes into `groups`, messages between groups are dropped
+    /// when they are delivered. Nodes in no group are cut off from all
+    /// others, clients always reach every node.
+    pub fn partition(&mut self, groups: &[&[&str]]) {
+        let mut partition = BTreeMap::new();
+        for (group, nodes) in groups.iter().enumerate() {
+            for node in nodes.iter() {
+                partition.insert(node.to_string(), group);
+            }
+        }
+        let mut isolated = groups.len();
+        for node in self.nodes.keys() {
+            partition.entry(node.clone()).or_insert_with(|| {
+                isolated += 1;
+                isolated
+            });
+        }
+        self.partition = Some(partition);
+    }
+
+    pub fn heal(&mut self) {
+        self.partition = None;
+    }
+
+    fn cut(&self, src: &str, dest: &str) -> bool {
+        match &self.partition {
+            Some(partition) => match (partition.get(src), partition.get(dest)) {
+                (Some(src_group), Some(dest_group)) => src_group != dest_group,
+                _ => false,
+            },
+            None => false,
+        }
+    }
+
+    /// Everything nodes sent to clients so far.
+    pub fn client_messages(&self) -> &[Message<Value>] {
+        &self.client_messages
+    }
+
+    /// Reply to the request `msg_id` sent by client `client`.
+    pub fn reply_to(&self, client: &str, msg_id: usize) -> Option<&Message<Value>> {
+        self.client_messages.iter().find(|message| {
+            message.dst == client
+                && message.body.data.get("in_reply_to").and_then(Value::as_u64)
+                    == Some(msg_id as u64)
+        })
+    }
+
+    /// One line per delivered event, equal logs mean equal interleavings.
+    pub fn log(&self) -> &[String] {
+        &self.log
+    }
+
+    /// Delivers the next message or timer, `false` once nothing is pending.
+    pub fn step(&mut self) -> message::Result<bool> {
+        let next_message = self.network.peek().map(|Reverse((at, _))| *at);
+        let next_timer = self.time.next_due();
+        // a message and a timer due at the same time, the message goes first.
+        let message_first = match (next_message, next_timer) {
+            (None, None) => return Ok(false),
+            (Some(message_at), Some(timer_at)) => message_at <= timer_at,
+            (message_at, _) => message_at.is_some(),
+        };
+        if message_first {
+            let Reverse((at, seq)) = self.network.pop().expect("peeked message");
+            self.time.advance_to(at);
+            let (src, dest, line) = self.in_flight.remove(&seq).expect("in flight message");
+            if self.cut(&src, &dest) {
+                self.log
+                    .push(format!("{} drop {} -> {}", at.as_micros(), src, dest));
+            } else {
+                self.deliver(&dest, &line)?;
+            }
+        } else if let Some(timer_at) = next_timer {
+            self.time.advance_to(timer_at);
+            while let Some((node_id, event)) = self.time.pop_due() {
+                self.handle(&node_id, event)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Time of the next pending message or timer.
+    pub fn next_due(&self) -> Option<Duration> {
+        let next_message = self.network.peek().map(|Reverse((at, _))| *at);
+        match (next_message, self.time.next_due()) {
+            (Some(message_at), Some(timer_at)) => Some(message_at.min(timer_at)),
+            (message_at, timer_at) => message_at.or(timer_at),
+        }
+    }
+
+    /// Runs every event due in the next `duration` of virtual time.
+    pub fn run_for(&mut self, duration: Duration) -> message::Result<()> {
+        let until = self.now() + duration;
+        while self.next_due().is_some_and(|next| next <= until) {
+            self.step()?;
+        }
+        self.time.advance_to(until);
+        Ok(())
+    }
+
+    fn enqueue(&mut self, src: &str, dest: &str, line: String) {
+        let latency = match self.max_latency.as_micros() as u64 {
+            0 => Duration::ZERO,
+            max => Duration::from_micros(self.rng.below(max + 1)),
+        };
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.network.push(Reverse((self.now() + latency, seq)));
+        self.in_flight
+            .insert(seq, (src.to_string(), dest.to_string(), line));
+    }
+
+    fn deliver(&mut self, dest: &str, line: &str) -> message::Result<()> {
+        match message::parse_incoming::<N::Payload>(line) {
+            Incoming::Message(message) => {
+                if let Some(sim_node) = self.nodes.get(dest) {
+                    sim_node.outbox.receive(message.body.clock.as_ref());
+                    if !sim_node.outbox.admit(&message.src, message.body.msg_id)? {
+                        // a retry, the node may have answered it from the cache.
+                        return self.route_output(dest);
+                    }
+                }
+                self.handle(dest, N::Event::from(message))
+            }
+            // nodes drop what they cannot parse, so does the simulated stdin.
+            _ => Ok(()),
+        }
+    }
+
+    fn handle(&mut self, node_id: &str, event: N::Event) -> message::Result<()> {
+        let Some(sim_node) = self.nodes.get(node_id) else {
+            bail!("no node {} in simulation", node_id);
+        };
+        self.log.push(format!(
+            "{} {} {}",
+            self.time.now().as_micros(),
+            node_id,
+            serde_json::to_string(&event)?
+        ));
+        sim_node.node.handle(&sim_node.outbox, event)?;
+        self.route_output(node_id)
+    }
+
+    /// Sends what `node_id` wrote on to nodes and clients.
+    fn route_output(&mut self, node_id: &str) -> message::Result<()> {
+        let Some(sim_node) = self.nodes.get(node_id) else {
+            return Ok(());
+        };
+        let lines = sim_node.lines.try_iter().collect::<Vec<_>>();
+        for line in lines {
+            let message = serde_json::from_str::<Message<Value>>(&line)?;
+            if self.nodes.contains_key(&message.dst) {
+                self.enqueue(&message.src, &message.dst, line);
+            } else {
+                self.client_messages.push(message);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        broadcase_handler::{Broadcast, BroadcastNode},
+        config::ReadConsistency,
+        counter::{Counter, CounterNode},
+    };
+
+    fn counter_run(seed: u64) -> Simulation<CounterNode> {
+        let mut sim = Simulation::<CounterNode>::new(&["n1", "n2", "n3"], seed)
+            .with_max_latency(Duration::from_millis(300));
+        for (index, node) in ["n1", "n2", "n3", "n1"].iter().enumerate() {
+            sim.client_send("c1", node, Counter::Add { delta: index + 1 })
+                .unwrap();
+        }
+        sim.run_for(Duration::from_secs(3)).unwrap();
+        sim
+    }
+
+    #[test]
+    fn test_counter_nodes_converge_in_virtual_time() {
+        let mut sim = counter_run(7);
+        let reads = ["n1", "n2", "n3"]
+            .iter()
+            .map(|node| sim.client_send("c2", node, Counter::Read).unwrap())
+            .collect::<Vec<_>>();
+        sim.run_for(Duration::from_secs(1)).unwrap();
+        for msg_id in reads {
+            let reply = sim.reply_to("c2", msg_id).unwrap();
+            assert_eq!(Some(10), reply.body.data["value"].as_u64());
+        }
+        assert_eq!(Duration::from_secs(4), sim.now());
+    }
+
+    #[test]
+    fn test_same_seed_replays_same_interleaving() {
+        assert_eq!(counter_run(42).log(), counter_run(42).log());
+        assert!((0..5).any(|seed| counter_run(seed).log() != counter_run(42).log()));
+    }
+
+    #[test]
+    fn test_clocks_keep_reordered_counts_from_going_back() {
+        let mut sim = Simulation::<CounterNode>::new(&["n1", "n2", "n3"], 5)
+            .with_max_latency(Duration::from_secs(2))
+            .with_clocks();
+        for _ in 0..20 {
+            sim.client_send("c1", "n1", Counter::Add { delta: 1 })
+                .unwrap();
+            for _ in 0..3 {
+                sim.client_send("c2", "n2", Counter::Read).unwrap();
+                sim.run_for(Duration::from_millis(100)).unwrap();
+            }
+        }
+        sim.run_for(Duration::from_secs(5)).unwrap();
+        sim.client_send("c2", "n2", Counter::Read).unwrap();
+        sim.run_for(Duration::from_secs(3)).unwrap();
+
+        assert!(sim.log().iter().any(|line| line.contains("\"lamport\"")));
+        let reads = sim
+            .client_messages()
+            .iter()
+            .filter(|message| message.dst == "c2")
+            .map(|message| message.body.data["value"].as_u64().unwrap())
+            .collect::<Vec<_>>();
+        assert!(
+            reads.windows(2).all(|pair| pair[0] <= pair[1]),
+            "{:?}",
+            reads
+        );
+        assert_eq!(Some(&20), reads.last());
+    }
+
+    #[test]
+    fn test_retried_add_is_applied_once() {
+        let mut sim = Simulation::<CounterNode>::new(&["n1", "n2"], 6);
+        let add = sim
+            .client_send("c1", "n1", Counter::Add { delta: 5 })
+            .unwrap();
+        // one retry right behind the original, one long after it.
+        sim.client_retry("c1", "n1", Counter::Add { delta: 5 }, add)
+            .unwrap();
+        sim.run_for(Duration::from_secs(2)).unwrap();
+        sim.client_retry("c1", "n1", Counter::Add { delta: 5 }, add)
+            .unwrap();
+        sim.run_for(Duration::from_secs(2)).unwrap();
+        let read = sim.client_send("c2", "n2", Counter::Read).unwrap();
+        sim.run_for(Duration::from_millis(100)).unwrap();
+
+        assert_eq!(
+            Some(5),
+            sim.reply_to("c2", read).unwrap().body.data["value"].as_u64()
+        );
+        let replies = sim
+            .client_messages()
+            .iter()
+            .filter(|message| message.dst == "c1")
+            .collect::<Vec<_>>();
+        assert_eq!(3, replies.len());
+        assert!(replies.iter().all(|reply| *reply == replies[0]));
+    }
+
+    #[test]
+    fn test_quorum_read_sees_adds_not_yet_gossiped() {
+        let config = Config {
+            read_consistency: ReadConsistency::Quorum,
+            ..Config::default()
+        };
+        let mut sim = Simulation::<CounterNode>::with_config(&["n1", "n2", "n3"], 8, &config);
+        // n3 is cut off before the delta of the add reaches it.
+        sim.partition(&[&["n1", "n2"], &["n3"]]);
+        sim.client_send("c1", "n1", Counter::Add { delta: 4 })
+            .unwrap();
+        sim.run_for(Duration::from_millis(50)).unwrap();
+        // well before the first counter round.
+        let majority = sim.client_send("c2", "n2", Counter::Read).unwrap();
+        let isolated = sim.client_send("c2", "n3", Counter::Read).unwrap();
+        sim.run_for(Duration::from_millis(100)).unwrap();
+        assert_eq!(
+            Some(4),
+            sim.reply_to("c2", majority).unwrap().body.data["value"].as_u64()
+        );
+        assert!(sim.reply_to("c2", isolated).is_none());
+
+        // no peer answers, the deadline falls back to what n3 knows.
+        sim.run_for(config.read_timeout).unwrap();
+        assert_eq!(
+            Some(0),
+            sim.reply_to("c2", isolated).unwrap().body.data["value"].as_u64()
+        );
+        assert_eq!(1, sim.node("n3").unwrap().stats()["read_fallbacks"]);
+    }
+
+    #[test]
+    fn test_counter_deltas_cross_a_healed_partition() {
+        // no counter rounds, only the reliable channel carries the adds.
+        let config = Config {
+            counter_interval: Duration::from_secs(3600),
+            ..Config::default()
+        };
+        let mut sim = Simulation::<CounterNode>::with_config(&["n1", "n2", "n3"], 11, &config);
+        sim.run_for(Duration::from_millis(10)).unwrap();
+        sim.partition(&[&["n1", "n2"], &["n3"]]);
+        sim.client_send("c1", "n1", Counter::Add { delta: 2 })
+            .unwrap();
+        sim.client_send("c1", "n3", Counter::Add { delta: 5 })
+            .unwrap();
+        sim.run_for(Duration::from_secs(2)).unwrap();
+        let cut_off = sim.client_send("c2", "n3", Counter::Read).unwrap();
+        sim.run_for(Duration::from_millis(10)).unwrap();
+        assert_eq!(
+            Some(5),
+            sim.reply_to("c2", cut_off).unwrap().body.data["value"].as_u64()
+        );
+
+        sim.heal();
+        sim.run_for(Duration::from_secs(6)).unwrap();
+        for node in ["n1", "n2", "n3"] {
+            let read = sim.client_send("c2", node, Counter::Read).unwrap();
+            sim.run_for(Duration::from_millis(10)).unwrap();
+            assert_eq!(
+                Some(7),
+                sim.reply_to("c2", read).unwrap().body.data["value"].as_u64()
+            );
+            assert_eq!(0, sim.node(node).unwrap().stats()["unacked_deltas"]);
+        }
+        // the one round at start went out before any add.
+        let currents = sim.log().iter().filter(|line| line.contains("\"current\""));
+        assert!(currents
+            .into_iter()
+            .all(|line| line.contains("\"value\":0")));
+    }
+
+    fn line_broadcast(seed: u64) -> Simulation<BroadcastNode> {
+        let mut sim = Simulation::<BroadcastNode>::new(&["n1", "n2", "n3"], seed);
+        let topology = [
+            ("n1", vec!["n2"]),
+            ("n2", vec!["n1", "n3"]),
+            ("n3", vec!["n2"]),
+        ]
+        .into_iter()
+        .map(|(node, neighbours)| {
+            (
+                node.to_string(),
+                neighbours.into_iter().map(String::from).collect(),
+            )
+        })
+        .collect::<std::collections::HashMap<_, _>>();
+        for node in ["n1", "n2", "n3"] {
+            sim.client_send(
+                "c1",
+                node,
+                Broadcast::Topology {
+                    topology: topology.clone(),
+                },
+            )
+            .unwrap();
+        }
+        sim
+    }
+
+    fn read(sim: &mut Simulation<BroadcastNode>, node: &str) -> Value {
+        let msg_id = sim.client_send("c2", node, Broadcast::Read).unwrap();
+        sim.run_for(Duration::from_millis(100)).unwrap();
+        sim.reply_to("c2", msg_id).unwrap().body.data["messages"].clone()
+    }
+
+    #[test]
+    fn test_broadcast_spreads_through_topology() {
+        let mut sim = line_broadcast(3);
+        sim.client_send("c1", "n1", Broadcast::Broadcast { message: 11 })
+            .unwrap();
+        sim.client_send("c1", "n3", Broadcast::Broadcast { message: 33 })
+            .unwrap();
+        sim.run_for(Duration::from_secs(2)).unwrap();
+
+        for node in ["n1", "n2", "n3"] {
+            assert_eq!(
+                serde_json::json!([11, 33]),
+                read(&mut sim, node),
+                "{}",
+                node
+            );
+        }
+    }
+
+    #[test]
+    fn test_broadcast_heals_partition_with_digests() {
+        let mut sim = line_broadcast(4);
+        sim.run_for(Duration::from_millis(100)).unwrap();
+        sim.partition(&[&["n1"], &["n2", "n3"]]);
+        for message in 0..300 {
+            let node = ["n1", "n3"][message % 2];
+            sim.client_send("c1", node, Broadcast::Broadcast { message })
+                .unwrap();
+        }
+        sim.run_for(Duration::from_secs(3)).unwrap();
+        sim.heal();
+        sim.run_for(Duration::from_secs(2)).unwrap();
+        let all = serde_json::json!((0..300).collect::<Vec<_>>());
+        for node in ["n1", "n2", "n3"] {
+            assert_eq!(all, read(&mut sim, node), "{}", node);
+        }
+
+        // once everyone agrees only root hashes go around.
+        let settled = sim.log().len();
+        sim.run_for(Duration::from_secs(2)).unwrap();
+        let gossip = &sim.log()[settled..];
+        assert!(gossip.iter().any(|line| line.contains("\"digest\"")));
+        assert!(!gossip
+            .iter()
+            .any(|line| line.contains("digest_buckets") || line.contains("repair")));
+    }
+}
@@ -0,0 +1,463 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admin::Admin,
+    clock::Clock,
+    config::Config,
+    message::{self, Handler, Incoming, Init},
+    output::Outbox,
+    runtime::Node,
+    scheduler::VirtualTime,
+};
+
+/// One line of a trace file.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TraceEntry {
+    /// Raw line read from stdin, including lines the node skipped.
+    In { at_ms: u64, line: String },
+    /// Event handed to the node, either a parsed message or one of its own
+    /// ticks, along with the node clock at that point when clocks are on.
+    Event {
+        at_ms: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        clock: Option<Clock>,
+        event: serde_json::Value,
+    },
+    /// Line written to stdout.
+    Out { at_ms: u64, line: String },
+    /// Reply the reader sent by itself, a `not-supported` error or the
+    /// cached answer to a retry. Its `Out` follows, no event produces it.
+    Answered { at_ms: u64, line: String },
+    /// Seed of the random numbers the node drew, such as gossip targets.
+    Seed { seed: u64 },
+}
+
+/// Shared JSONL trace writer, flushed when the last clone is dropped. Tracing
+/// must never take the node down, so write failures are only logged.
+#[derive(Clone)]
+pub struct Trace {
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+    start: Instant,
+}
+
+impl fmt::Debug for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Trace").field("start", &self.start).finish()
+    }
+}
+
+impl Trace {
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        Trace {
+            writer: Arc::new(Mutex::new(Box::new(writer))),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn create(path: impl AsRef<Path>) -> message::Result<Self> {
+        Ok(Trace::new(BufWriter::new(File::create(path)?)))
+    }
+
+    pub fn record_in(&self, line: &str) {
+        self.record(TraceEntry::In {
+            at_ms: self.elapsed_ms(),
+            line: line.trim_end().to_string(),
+        });
+    }
+
+    pub fn record_event<E: Serialize>(&self, event: &E, clock: Option<Clock>) {
+        match serde_json::to_value(event) {
+            Ok(event) => self.record(TraceEntry::Event {
+                at_ms: self.elapsed_ms(),
+                clock,
+                event,
+            }),
+            Err(error) => eprintln!("Could not trace event: {}", error),
+        }
+    }
+
+    pub fn record_out(&self, line: &str) {
+        self.record(TraceEntry::Out {
+            at_ms: self.elapsed_ms(),
+            line: line.trim_end().to_string(),
+        });
+    }
+
+    pub fn record_answered(&self, line: &str) {
+        self.record(TraceEntry::Answered {
+            at_ms: self.elapsed_ms(),
+            line: line.trim_end().to_string(),
+        });
+    }
+
+    pub fn record_seed(&self, seed: u64) {
+        self.record(TraceEntry::Seed { seed });
+    }
+
+    fn elapsed_ms(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    fn record(&self, entry: TraceEntry) {
+        let mut writer = self.writer.lock().expect("trace lock poisoned");
+        let written = serde_json::to_writer(&mut *writer, &entry)
+            .map_err(io::Error::from)
+            .and_then(|_| writer.write_all(b"\n"));
+        if let Err(error) = written {
+            eprintln!("Could not write trace entry: {}", error);
+        }
+    }
+}
+
+pub fn read_trace(input: impl BufRead) -> message::Result<Vec<TraceEntry>> {
+    input
+        .lines()
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+/// Outcome of replaying a trace: recorded output next to what the node
+/// produced this time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayReport {
+    pub expected: Vec<String>,
+    pub actual: Vec<String>,
+}
+
+impl ReplayReport {
+    pub fn matches(&self) -> bool {
+        self.expected == self.actual
+    }
+}
+
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.matches() {
+            return writeln!(f, "replay matches trace ({} lines)", self.actual.len());
+        }
+        for index in 0..self.expected.len().max(self.actual.len()) {
+            let expected = self.expected.get(index);
+            let actual = self.actual.get(index);
+            if expected != actual {
+                writeln!(f, "line {}:", index + 1)?;
+                writeln!(f, "- {}", expected.map_or("<missing>", |line| line))?;
+                writeln!(f, "+ {}", actual.map_or("<missing>", |line| line))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Feeds the events of a recorded trace into a fresh `N` and compares its
+/// output with the recorded one. Timers of the node are cut off, ticks only
+/// come from the trace and random numbers from the recorded seed, so the
+/// run is deterministic. `config` has to match the recorded run.
+pub fn replay<N: Node>(entries: &[TraceEntry], config: &Config) -> message::Result<ReplayReport> {
+    let init_message = entries.iter().find_map(|entry| match entry {
+        TraceEntry::In { line, .. } => match message::parse_incoming::<Init>(line) {
+            Incoming::Message(message) => Some(message),
+            _ => None,
+        },
+        _ => None,
+    });
+    let Some(init_message) = init_message else {
+        bail!("trace has no init message");
+    };
+    let (outbox, lines) = Outbox::channel();
+    init_message
+        .body
+        .data
+        .handle(&outbox, init_message.clone())?;
+    let Init::Init { node_id, node_ids } = init_message.body.data else {
+        bail!("trace has no init message");
+    };
+    let clocks = entries
+        .iter()
+        .any(|entry| matches!(entry, TraceEntry::Event { clock: Some(_), .. }));
+    let outbox = match clocks {
+        true => outbox.with_clock(&node_id, &node_ids),
+        false => outbox,
+    };
+
+    let seed = entries.iter().find_map(|entry| match entry {
+        TraceEntry::Seed { seed } => Some(*seed),
+        _ => None,
+    });
+    // virtual time never advances here, so timers of the node never fire.
+    let time = VirtualTime::seeded(seed.unwrap_or_default());
+    let node = N::from_init(
+        node_id,
+        node_ids,
+        Arc::new(time.scheduler("replay")),
+        config,
+    );
+    for entry in entries {
+        if let TraceEntry::Event { event, clock, .. } = entry {
+            // the recorded clock already took in what was received meanwhile.
+            if let Some(clock) = clock {
+                outbox.set_clock(clock.clone());
+            }
+            node.handle(&outbox, serde_json::from_value::<N::Event>(event.clone())?)?;
+        }
+    }
+    drop(node);
+    drop(outbox);
+
+    // neither answers of the reader nor admin replies were produced by events.
+    let mut answered = Vec::new();
+    Ok(ReplayReport {
+        expected: entries
+            .iter()
+            .filter_map(|entry| match entry {
+                TraceEntry::Answered { line, .. } => {
+                    answered.push(line);
+                    None
+                }
+                TraceEntry::Out { line, .. } => {
+                    match answered.iter().position(|answer| *answer == line) {
+                        Some(answer) => {
+                            answered.swap_remove(answer);
+                            None
+                        }
+                        None if is_admin_reply(line) => None,
+                        None => Some(line.clone()),
+                    }
+                }
+                _ => None,
+            })
+            .collect(),
+        actual: lines
+            .iter()
+            .map(|line| line.trim_end().to_string())
+            .collect(),
+    })
+}
+
+fn is_admin_reply(line: &str) -> bool {
+    message::body_header(line).is_ok_and(|header| Admin::REPLIES.contains(&header.kind.as_str()))
+}
+
+pub fn replay_file<N: Node>(
+    path: impl AsRef<Path>,
+    config: &Config,
+) -> message::Result<ReplayReport> {
+    replay::<N>(&read_trace(BufReader::new(File::open(path)?))?, config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        counter::{Counter, CounterNode, CounterPayload, ExternalInternal, Internal},
+        event_queue::{EventQueue, OverflowPolicy},
+        message::{Message, Payload},
+        output::OutputWriter,
+        runtime,
+        scheduler::ThreadScheduler,
+    };
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn request(src: &str, counter: Counter, msg_id: usize) -> ExternalInternal {
+        ExternalInternal::External(Message::new(
+            src.to_string(),
+            "n1".to_string(),
+            Payload::new(counter, Some(msg_id)),
+        ))
+    }
+
+    fn record_run(
+        node_count: usize,
+        config: &Config,
+        events: Vec<ExternalInternal>,
+    ) -> Vec<TraceEntry> {
+        let buffer = SharedBuffer::default();
+        let trace = Trace::new(buffer.clone());
+        let node_ids = (1..=node_count)
+            .map(|node| format!("n{}", node))
+            .collect::<Vec<_>>();
+        let init = Message::new(
+            "c0".to_string(),
+            "n1".to_string(),
+            Payload::new(
+                Init::Init {
+                    node_id: "n1".to_string(),
+                    node_ids: node_ids.clone(),
+                },
+                Some(1),
+            ),
+        );
+        trace.record_in(&serde_json::to_string(&init).unwrap());
+        let (outbox, output_writer) = OutputWriter::spawn(io::sink(), Some(trace.clone()));
+        init.body.data.handle(&outbox, init.clone()).unwrap();
+
+        let queue = EventQueue::new(64, OverflowPolicy::Block);
+        let scheduler = Arc::new(ThreadScheduler::new(queue.clone()));
+        trace.record_seed(scheduler.seed());
+        let node = CounterNode::new("n1".to_string(), node_ids, config, scheduler);
+        for event in events {
+            queue.push(event).unwrap();
+        }
+        queue.close();
+        runtime::dispatch(Arc::new(node), &outbox, queue, 1, Some(&trace)).unwrap();
+        drop(outbox);
+        output_writer.join().unwrap();
+        drop(trace);
+
+        let recorded = buffer.0.lock().unwrap().clone();
+        read_trace(recorded.as_slice()).unwrap()
+    }
+
+    fn record_counter_run() -> Vec<TraceEntry> {
+        let events = vec![
+            request("c1", Counter::Add { delta: 3 }, 1),
+            request("c2", Counter::Add { delta: 4 }, 1),
+            ExternalInternal::Internal(Internal::TriggerDispatch),
+            request("c1", Counter::Read, 2),
+            ExternalInternal::Internal(Internal::TerminateDispatcher),
+        ];
+        record_run(2, &Config::default(), events)
+    }
+
+    #[test]
+    fn test_replay_reproduces_recorded_output() {
+        let entries = record_counter_run();
+        assert!(entries.iter().any(
+            |entry| matches!(entry, TraceEntry::Out { line, .. } if line.contains("read_ok"))
+        ));
+
+        let report = replay::<CounterNode>(&entries, &Config::default()).unwrap();
+        assert!(report.matches(), "{}", report);
+    }
+
+    #[test]
+    fn test_replay_skips_answers_of_the_reader() {
+        let buffer = SharedBuffer::default();
+        let trace = Trace::new(buffer.clone());
+        let init = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2"]}}"#;
+        trace.record_in(init);
+        let (outbox, output_writer) = OutputWriter::spawn(io::sink(), Some(trace.clone()));
+        let init_message = init.parse::<Message<Init>>().unwrap();
+        init_message
+            .body
+            .data
+            .handle(&outbox, init_message.clone())
+            .unwrap();
+        let node_ids = vec!["n1".to_string(), "n2".to_string()];
+        let scheduler = Arc::new(VirtualTime::default().scheduler("n1"));
+        let outbox =
+            runtime::with_reply_cache(outbox, &node_ids, &Config::default(), scheduler.clone());
+        let node = Arc::new(CounterNode::new(
+            "n1".to_string(),
+            node_ids,
+            &Config::default(),
+            scheduler,
+        ));
+
+        // the retry comes after the add was answered, the cache answers it.
+        let rounds = [
+            concat!(
+                r#"{"src":"c1","dest":"n1","body":{"type":"frob","msg_id":2}}"#,
+                "\n",
+                r#"{"src":"c1","dest":"n1","body":{"type":"add","delta":3,"msg_id":3}}"#,
+            ),
+            concat!(
+                r#"{"src":"c1","dest":"n1","body":{"type":"add","delta":3,"msg_id":3}}"#,
+                "\n",
+                r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":4}}"#,
+            ),
+        ];
+        for round in rounds {
+            let queue = EventQueue::new(16, OverflowPolicy::Block);
+            message::read_messages::<CounterPayload, _>(
+                round.as_bytes(),
+                &queue,
+                ExternalInternal::from,
+                &outbox,
+                Some(&trace),
+                None,
+            )
+            .unwrap();
+            queue.close();
+            runtime::dispatch(node.clone(), &outbox, queue, 1, Some(&trace)).unwrap();
+        }
+        drop(outbox);
+        output_writer.join().unwrap();
+        drop(trace);
+        let recorded = buffer.0.lock().unwrap().clone();
+        let entries = read_trace(recorded.as_slice()).unwrap();
+        let answered = entries
+            .iter()
+            .filter_map(|entry| match entry {
+                TraceEntry::Answered { line, .. } => Some(line.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(2, answered.len());
+        assert!(answered[0].contains("\"code\":10"));
+        assert!(answered[1].contains("add_ok"));
+
+        let report = replay::<CounterNode>(&entries, &Config::default()).unwrap();
+        assert!(report.matches(), "{}", report);
+        assert!(report.actual.iter().any(|line| line.contains("read_ok")));
+    }
+
+    #[test]
+    fn test_replay_reports_diverging_output() {
+        let mut entries = record_counter_run();
+        for entry in entries.iter_mut() {
+            if let TraceEntry::Out { line, .. } = entry {
+                *line = line.replace("\"value\":7", "\"value\":8");
+            }
+        }
+
+        let report = replay::<CounterNode>(&entries, &Config::default()).unwrap();
+        assert!(!report.matches());
+        assert!(report.to_string().contains("\"value\":7"));
+    }
+
+    #[test]
+    fn test_replay_draws_the_recorded_random_peers() {
+        let mut config = Config::default();
+        config.set("peer_selection", "random").unwrap();
+        config.set("fanout", "1").unwrap();
+        let mut events = vec![request("c1", Counter::Add { delta: 3 }, 1)];
+        events.extend((0..20).map(|_| ExternalInternal::Internal(Internal::TriggerDispatch)));
+        let entries = record_run(5, &config, events);
+
+        let report = replay::<CounterNode>(&entries, &config).unwrap();
+        assert!(report.matches(), "{}", report);
+        // one pick per round, and not always the same one.
+        let targets = report
+            .actual
+            .iter()
+            .filter(|line| line.contains("\"current\""))
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["dest"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(20, targets.len());
+        assert!(targets.iter().any(|target| *target != targets[0]));
+    }
+}
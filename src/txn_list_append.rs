@@ -0,0 +1,323 @@
+#![allow(dead_code)]
+//! Maelstrom `txn-list-append`: transactions of list reads and appends. Each
+//! transaction is one entry of the Raft log and is applied atomically, so
+//! the commit order is a serial order of all transactions. A transaction
+//! whose log slot is taken over by a new leader is aborted with `txn-conflict`.
+use std::collections::BTreeMap;
+
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{ArrayValidation, InstanceType, Schema, SchemaObject},
+    JsonSchema,
+};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+use crate::{
+    message::{BodyTypes, ErrorCode},
+    proxy::Reply,
+    raft::StateMachine,
+    raft_service::{RaftService, Replicated, ServicePayload},
+};
+
+/// Micro-operation, `["r", key, null]` or `["append", key, value]` on the
+/// wire. Reads carry the whole list once executed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MicroOp {
+    Read {
+        key: Value,
+        value: Option<Vec<Value>>,
+    },
+    Append {
+        key: Value,
+        value: Value,
+    },
+}
+
+impl Serialize for MicroOp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            MicroOp::Read { key, value } => ("r", key, value).serialize(serializer),
+            MicroOp::Append { key, value } => ("append", key, value).serialize(serializer),
+        }
+    }
+}
+
+/// `[kind, key, value]` with `kind` either `r` or `append`.
+impl JsonSchema for MicroOp {
+    fn schema_name() -> String {
+        "MicroOp".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        let kind = SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            enum_values: Some(vec!["r".into(), "append".into()]),
+            ..SchemaObject::default()
+        };
+        let any = gen.subschema_for::<Value>();
+        SchemaObject {
+            instance_type: Some(InstanceType::Array.into()),
+            array: Some(Box::new(ArrayValidation {
+                items: Some(vec![kind.into(), any.clone(), any].into()),
+                min_items: Some(3),
+                max_items: Some(3),
+                ..ArrayValidation::default()
+            })),
+            ..SchemaObject::default()
+        }
+        .into()
+    }
+}
+
+impl<'de> Deserialize<'de> for MicroOp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (kind, key, value) = <(String, Value, Value)>::deserialize(deserializer)?;
+        match kind.as_str() {
+            "r" => Ok(MicroOp::Read {
+                key,
+                value: serde_json::from_value(value).map_err(de::Error::custom)?,
+            }),
+            "append" => Ok(MicroOp::Append { key, value }),
+            other => Err(de::Error::unknown_variant(other, &["r", "append"])),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Txn {
+    Txn {
+        txn: Vec<MicroOp>,
+    },
+    TxnOk {
+        txn: Vec<MicroOp>,
+        in_reply_to: usize,
+    },
+    // only seen by proxies relaying the answer of the leader.
+    Error {
+        in_reply_to: usize,
+        code: ErrorCode,
+        text: String,
+    },
+}
+
+impl Reply for Txn {
+    fn in_reply_to(&self) -> Option<usize> {
+        match self {
+            Txn::TxnOk { in_reply_to, .. } | Txn::Error { in_reply_to, .. } => Some(*in_reply_to),
+            Txn::Txn { .. } => None,
+        }
+    }
+
+    fn with_in_reply_to(mut self, to: usize) -> Self {
+        if let Txn::TxnOk { in_reply_to, .. } | Txn::Error { in_reply_to, .. } = &mut self {
+            *in_reply_to = to;
+        }
+        self
+    }
+}
+
+impl Txn {
+    fn error(code: ErrorCode, text: &str, in_reply_to: usize) -> Self {
+        Txn::Error {
+            in_reply_to,
+            code,
+            text: text.to_string(),
+        }
+    }
+}
+
+pub type TxnPayload = ServicePayload<Txn, Vec<MicroOp>>;
+
+impl BodyTypes for TxnPayload {
+    const TYPES: &'static [&'static str] = &[
+        "txn",
+        "txn_ok",
+        "error",
+        "request_vote",
+        "request_vote_ok",
+        "append_entries",
+        "append_entries_ok",
+        "election_timeout",
+        "heartbeat",
+        "forward_timeout",
+    ];
+}
+
+/// Lists by the serialized form of their key.
+#[derive(Debug, Default)]
+pub struct ListStore {
+    lists: BTreeMap<String, Vec<Value>>,
+}
+
+impl ListStore {
+    pub fn get(&self, key: &Value) -> Option<&[Value]> {
+        self.lists.get(&key.to_string()).map(Vec::as_slice)
+    }
+}
+
+impl StateMachine for ListStore {
+    type Command = Vec<MicroOp>;
+    type Output = Vec<MicroOp>;
+
+    /// Runs the whole transaction, reads see the appends before them.
+    fn apply(&mut self, txn: &Vec<MicroOp>) -> Vec<MicroOp> {
+        txn.iter()
+            .map(|op| match op {
+                MicroOp::Read { key, .. } => MicroOp::Read {
+                    key: key.clone(),
+                    value: Some(self.get(key).unwrap_or_default().to_vec()),
+                },
+                MicroOp::Append { key, value } => {
+                    self.lists
+                        .entry(key.to_string())
+                        .or_default()
+                        .push(value.clone());
+                    op.clone()
+                }
+            })
+            .collect()
+    }
+}
+
+impl Replicated for ListStore {
+    type Body = Txn;
+
+    // the transaction never ran.
+    const LOST_COMMIT: ErrorCode = ErrorCode::TxnConflict;
+
+    fn command(body: Txn) -> Result<Vec<MicroOp>, Txn> {
+        match body {
+            Txn::Txn { txn } => Ok(txn),
+            reply => Err(reply),
+        }
+    }
+
+    fn request(txn: Vec<MicroOp>) -> Txn {
+        Txn::Txn { txn }
+    }
+
+    fn reply(txn: Vec<MicroOp>, in_reply_to: usize) -> Txn {
+        Txn::TxnOk { txn, in_reply_to }
+    }
+
+    fn error(code: ErrorCode, text: &str, in_reply_to: usize) -> Txn {
+        Txn::error(code, text, in_reply_to)
+    }
+}
+
+pub type TxnNode = RaftService<ListStore>;
+
+impl TxnNode {
+    /// Committed list of `key` as applied on this node.
+    pub fn get(&self, key: &Value) -> Vec<Value> {
+        self.read(|store| store.get(key).unwrap_or_default().to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::sim::Simulation;
+
+    const NODES: [&str; 3] = ["n1", "n2", "n3"];
+
+    fn txn(sim: &mut Simulation<TxnNode>, node: &str, txn: Value) -> Value {
+        let txn = serde_json::from_value(txn).unwrap();
+        let msg_id = sim
+            .client_send("c1", node, TxnPayload::Client(Txn::Txn { txn }))
+            .unwrap();
+        sim.run_for(Duration::from_millis(200)).unwrap();
+        sim.reply_to("c1", msg_id).unwrap().body.data.clone()
+    }
+
+    #[test]
+    fn test_micro_ops_use_maelstrom_arrays() {
+        let ops = json!([["r", 1, null], ["append", 1, 3], ["r", 2, [1, 2]]]);
+        let parsed = serde_json::from_value::<Vec<MicroOp>>(ops.clone()).unwrap();
+        assert_eq!(
+            MicroOp::Append {
+                key: json!(1),
+                value: json!(3)
+            },
+            parsed[1]
+        );
+        assert_eq!(ops, serde_json::to_value(&parsed).unwrap());
+        assert!(serde_json::from_value::<MicroOp>(json!(["w", 1, 2])).is_err());
+    }
+
+    #[test]
+    fn test_reads_see_earlier_appends_of_the_same_txn() {
+        let mut store = ListStore::default();
+        let ops = json!([
+            ["append", 1, 1],
+            ["r", 1, null],
+            ["append", 1, 2],
+            ["r", 2, null]
+        ]);
+        let done = store.apply(&serde_json::from_value(ops).unwrap());
+        assert_eq!(
+            json!([
+                ["append", 1, 1],
+                ["r", 1, [1]],
+                ["append", 1, 2],
+                ["r", 2, []]
+            ]),
+            serde_json::to_value(done).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_transactions_commit_through_any_node() {
+        let mut sim = Simulation::new(&NODES, 9);
+        sim.run_for(Duration::from_secs(2)).unwrap();
+        let first = txn(
+            &mut sim,
+            "n1",
+            json!([["append", "x", 1], ["r", "x", null]]),
+        );
+        assert_eq!(json!([["append", "x", 1], ["r", "x", [1]]]), first["txn"]);
+        for (node, value) in [("n2", 2), ("n3", 3)] {
+            let reply = txn(&mut sim, node, json!([["append", "x", value]]));
+            assert_eq!("txn_ok", reply["type"]);
+        }
+        let read = txn(&mut sim, "n2", json!([["r", "x", null]]));
+        assert_eq!(json!([["r", "x", [1, 2, 3]]]), read["txn"]);
+        for node in NODES {
+            let list = sim.node(node).unwrap().get(&json!("x"));
+            assert_eq!(vec![json!(1), json!(2), json!(3)], list, "{}", node);
+        }
+    }
+
+    #[test]
+    fn test_transaction_lost_with_its_leader_is_a_conflict() {
+        let mut sim = Simulation::<TxnNode>::new(&NODES, 9);
+        sim.run_for(Duration::from_secs(2)).unwrap();
+        let leader = NODES
+            .into_iter()
+            .find(|node| sim.node(node).unwrap().is_leader())
+            .unwrap();
+        let others = NODES
+            .into_iter()
+            .filter(|node| *node != leader)
+            .collect::<Vec<_>>();
+        sim.partition(&[&[leader], &others]);
+        let txn = serde_json::from_value(json!([["append", "x", 1]])).unwrap();
+        let msg_id = sim
+            .client_send("c1", leader, TxnPayload::Client(Txn::Txn { txn }))
+            .unwrap();
+        sim.run_for(Duration::from_secs(2)).unwrap();
+        sim.heal();
+        sim.run_for(Duration::from_secs(2)).unwrap();
+        let reply = &sim.reply_to("c1", msg_id).unwrap().body.data;
+        assert_eq!(json!(30), reply["code"]);
+        for node in NODES {
+            assert!(sim.node(node).unwrap().get(&json!("x")).is_empty());
+        }
+    }
+}
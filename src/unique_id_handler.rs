@@ -1,40 +1,67 @@
 #![allow(dead_code)]
 
-use std::{cell::Cell, io::Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use anyhow::Ok;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use crate::message::{self, Handler, Message, Payload};
+use crate::{
+    config::Config,
+    message::{self, BodyTypes, Handler, Message, Payload},
+    output::Outbox,
+    runtime::Node,
+    scheduler::SchedulerRef,
+};
 
-#[derive(Debug, Serialize, Clone, Deserialize, Hash, PartialEq, Eq)]
+#[derive(Debug, Serialize, Clone, Deserialize, JsonSchema, Hash, PartialEq, Eq)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum Generate {
     Generate,
     GenerateOk { id: String, in_reply_to: usize },
 }
 
-#[derive(Debug, Clone)]
+impl BodyTypes for Generate {
+    const TYPES: &'static [&'static str] = &["generate", "generate_ok"];
+}
+
+#[derive(Debug)]
 pub struct UniqueIdNode {
     node_id: String,
-    processed_id_count: Cell<usize>,
+    processed_id_count: AtomicUsize,
 }
 
 impl UniqueIdNode {
     pub fn new(node_id: String) -> Self {
         UniqueIdNode {
             node_id,
-            processed_id_count: Cell::new(0),
+            processed_id_count: AtomicUsize::new(0),
         }
     }
 }
 
+impl Node for UniqueIdNode {
+    type Payload = Generate;
+    type Event = Message<Generate>;
+
+    fn from_init(
+        node_id: String,
+        _node_ids: Vec<String>,
+        _scheduler: SchedulerRef<Self::Event>,
+        _config: &Config,
+    ) -> Self {
+        UniqueIdNode::new(node_id)
+    }
+
+    fn ordering_key(_event: &Self::Event) -> Option<&str> {
+        None
+    }
+}
+
 impl Handler<Message<Generate>> for UniqueIdNode {
-    fn handle(&self, writer: &mut dyn Write, message: Message<Generate>) -> message::Result<()> {
+    fn handle(&self, outbox: &Outbox, message: Message<Generate>) -> message::Result<()> {
         let generate_response = match message.body.data {
             Generate::Generate => {
-                let current_processed_id = self.processed_id_count.get();
-                self.processed_id_count.set(current_processed_id + 1);
+                let current_processed_id = self.processed_id_count.fetch_add(1, Ordering::SeqCst);
                 let gen_ok = Generate::GenerateOk {
                     id: format!("{}_{}", self.node_id, current_processed_id + 1),
                     in_reply_to: message.body.msg_id.unwrap_or(1),
@@ -48,8 +75,6 @@ impl Handler<Message<Generate>> for UniqueIdNode {
             _ => message,
         };
 
-        serde_json::to_writer(&mut *writer, &generate_response)?;
-        writer.write_all(b"\n")?;
-        Ok(())
+        outbox.send(&generate_response)
     }
 }
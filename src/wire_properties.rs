@@ -0,0 +1,207 @@
+//! Property tests for the wire format of every message body and for the
+//! handlers consuming them.
+use std::{fmt::Debug, sync::Arc};
+
+use proptest::{collection, option, prelude::*};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::{
+    broadcase_handler::{Broadcast, BroadcastNode},
+    config::Config,
+    counter::{Counter, CounterNode, CounterPayload},
+    echo_handler::{Echo, EchoNode},
+    interval_set::IntervalSet,
+    message::{Handler, Init, Message, Payload},
+    output::Outbox,
+    runtime::Node,
+    scheduler::VirtualTime,
+    unique_id_handler::{Generate, UniqueIdNode},
+};
+
+fn node_name() -> impl Strategy<Value = String> {
+    "[cn][0-9]{1,2}"
+}
+
+fn message<T: Debug>(body: impl Strategy<Value = T>) -> impl Strategy<Value = Message<T>> {
+    (node_name(), node_name(), body, option::of(any::<usize>()))
+        .prop_map(|(src, dst, data, msg_id)| Message::new(src, dst, Payload::new(data, msg_id)))
+}
+
+fn init() -> impl Strategy<Value = Init> {
+    prop_oneof![
+        (node_name(), collection::vec(node_name(), 0..5))
+            .prop_map(|(node_id, node_ids)| Init::Init { node_id, node_ids }),
+        any::<usize>().prop_map(|in_reply_to| Init::InitOk { in_reply_to }),
+    ]
+}
+
+fn echo() -> impl Strategy<Value = Echo> {
+    prop_oneof![
+        any::<String>().prop_map(|echo| Echo::Echo { echo }),
+        (any::<String>(), any::<usize>())
+            .prop_map(|(echo, in_reply_to)| Echo::EchoOk { echo, in_reply_to }),
+    ]
+}
+
+fn generate() -> impl Strategy<Value = Generate> {
+    prop_oneof![
+        Just(Generate::Generate),
+        (any::<String>(), any::<usize>())
+            .prop_map(|(id, in_reply_to)| Generate::GenerateOk { id, in_reply_to }),
+    ]
+}
+
+fn broadcast() -> impl Strategy<Value = Broadcast> {
+    let values = || collection::btree_set(any::<usize>(), 0..8);
+    prop_oneof![
+        any::<usize>().prop_map(|message| Broadcast::Broadcast { message }),
+        any::<usize>().prop_map(|in_reply_to| Broadcast::BroadcastOk { in_reply_to }),
+        Just(Broadcast::Read),
+        (values(), any::<usize>()).prop_map(|(messages, in_reply_to)| Broadcast::ReadOk {
+            messages,
+            in_reply_to
+        }),
+        collection::hash_map(node_name(), collection::vec(node_name(), 0..4), 0..4)
+            .prop_map(|topology| Broadcast::Topology { topology }),
+        any::<usize>().prop_map(|in_reply_to| Broadcast::TopologyOk { in_reply_to }),
+        Just(Broadcast::TriggerGossip),
+        any::<u64>().prop_map(|root| Broadcast::Digest { root }),
+        collection::btree_map(0usize..100, any::<u64>(), 0..8)
+            .prop_map(|buckets| Broadcast::DigestBuckets { buckets }),
+        (values(), collection::vec(0usize..100, 0..4)).prop_map(|(seen, buckets)| {
+            Broadcast::Repair {
+                seen: seen.into_iter().collect(),
+                buckets,
+            }
+        }),
+        Just(Broadcast::Quit),
+    ]
+}
+
+fn counter() -> impl Strategy<Value = Counter> {
+    prop_oneof![
+        any::<usize>().prop_map(|delta| Counter::Add { delta }),
+        any::<usize>().prop_map(|in_reply_to| Counter::AddOk { in_reply_to }),
+        Just(Counter::Read),
+        (any::<usize>(), any::<usize>())
+            .prop_map(|(value, in_reply_to)| Counter::ReadOk { value, in_reply_to }),
+        any::<usize>().prop_map(|value| Counter::Current { value }),
+        Just(Counter::Poll),
+        (any::<usize>(), any::<usize>())
+            .prop_map(|(value, in_reply_to)| Counter::PollOk { value, in_reply_to }),
+    ]
+}
+
+/// JSON round trip is exact and the envelope looks like the Maelstrom spec:
+/// `src`/`dest`/`body`, a snake_case `type` flattened into the body and an
+/// `in_reply_to` on every reply.
+fn check_wire<T>(message: &Message<T>) -> Result<(), TestCaseError>
+where
+    T: Serialize + DeserializeOwned + PartialEq + Debug,
+{
+    let json = serde_json::to_string(message).unwrap();
+    let parsed = serde_json::from_str::<Message<T>>(&json).unwrap();
+    prop_assert_eq!(&parsed, message);
+
+    let value = serde_json::from_str::<Value>(&json).unwrap();
+    prop_assert_eq!(Some(&Value::from(message.dst.clone())), value.get("dest"));
+    prop_assert_eq!(Some(&Value::from(message.src.clone())), value.get("src"));
+    prop_assert!(value.get("dst").is_none());
+    let body = &value["body"];
+    prop_assert!(body.get("data").is_none());
+    let kind = body["type"].as_str().unwrap();
+    prop_assert!(kind.chars().all(|c| c.is_ascii_lowercase() || c == '_'));
+    if kind.ends_with("_ok") {
+        prop_assert!(body["in_reply_to"].is_u64(), "{} lacks in_reply_to", kind);
+    }
+    prop_assert_eq!(
+        message.body.msg_id.map(|msg_id| msg_id as u64),
+        body["msg_id"].as_u64()
+    );
+    Ok(())
+}
+
+/// Feeds arbitrary messages to a fresh node, handlers may reject input but
+/// must never panic.
+fn check_handler<N: Node>(messages: Vec<Message<N::Payload>>) -> Result<(), TestCaseError> {
+    let time = VirtualTime::default();
+    let node = N::from_init(
+        "n1".to_string(),
+        vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
+        Arc::new(time.scheduler("n1")),
+        &Config::default(),
+    );
+    let (outbox, lines) = Outbox::channel();
+    for message in messages {
+        prop_assert!(node.handle(&outbox, N::Event::from(message)).is_ok());
+    }
+    for line in lines.try_iter() {
+        serde_json::from_str::<Message<Value>>(&line).unwrap();
+    }
+    Ok(())
+}
+
+proptest! {
+    #[test]
+    fn init_round_trips(message in message(init())) {
+        check_wire(&message)?;
+    }
+
+    #[test]
+    fn echo_round_trips(message in message(echo())) {
+        check_wire(&message)?;
+    }
+
+    #[test]
+    fn generate_round_trips(message in message(generate())) {
+        check_wire(&message)?;
+    }
+
+    #[test]
+    fn broadcast_round_trips(message in message(broadcast())) {
+        check_wire(&message)?;
+    }
+
+    #[test]
+    fn counter_round_trips(message in message(counter())) {
+        check_wire(&message)?;
+    }
+
+    #[test]
+    fn interval_set_matches_a_plain_set(
+        left in collection::btree_set(0usize..200, 0..60),
+        right in collection::btree_set(0usize..200, 0..60),
+    ) {
+        let mut set = left.iter().copied().collect::<IntervalSet>();
+        set.union(&right.iter().copied().collect());
+        let expected = left.union(&right).copied().collect::<Vec<_>>();
+        prop_assert_eq!(&expected, &set.iter().collect::<Vec<_>>());
+        prop_assert_eq!(expected.len(), set.len());
+        prop_assert!(set.ranges().windows(2).all(|pair| pair[0].1 + 1 < pair[1].0));
+        let json = serde_json::to_string(&set).unwrap();
+        prop_assert_eq!(set, serde_json::from_str::<IntervalSet>(&json).unwrap());
+    }
+
+    #[test]
+    fn echo_handler_never_panics(messages in collection::vec(message(echo()), 0..20)) {
+        check_handler::<EchoNode>(messages)?;
+    }
+
+    #[test]
+    fn unique_id_handler_never_panics(messages in collection::vec(message(generate()), 0..20)) {
+        check_handler::<UniqueIdNode>(messages)?;
+    }
+
+    #[test]
+    fn broadcast_handler_never_panics(messages in collection::vec(message(broadcast()), 0..20)) {
+        check_handler::<BroadcastNode>(messages)?;
+    }
+
+    #[test]
+    fn counter_handler_never_panics(
+        messages in collection::vec(message(counter().prop_map(CounterPayload::from)), 0..20)
+    ) {
+        check_handler::<CounterNode>(messages)?;
+    }
+}